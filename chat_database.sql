@@ -4,14 +4,34 @@ CREATE TABLE users (
     username TEXT UNIQUE NOT NULL,
     password TEXT NOT NULL,
     role TEXT,
-    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    totp_secret TEXT,                     -- base32 TOTP secret, NULL until two-factor enrollment starts
+    totp_enabled BOOLEAN DEFAULT 0,       -- set once the user confirms a code from their authenticator
+    totp_last_step INTEGER DEFAULT 0,     -- newest 30 second step accepted, so a code cannot be replayed
+    is_bot BOOLEAN DEFAULT 0,             -- bot accounts post with API tokens and cannot log in
+    bot_owner_id INTEGER,                 -- users.id of the person who created the bot
+    display_name TEXT,                    -- friendly name shown next to the username
+    status_text TEXT,                     -- short status message, e.g. "On holiday"
+    timezone TEXT,                        -- IANA time zone name, e.g. Europe/Berlin
+    avatar_sha256 TEXT,                   -- blob store key of the profile picture
+    avatar_content_type TEXT
 );
 
 -- Chats table
 CREATE TABLE chats (
     id INTEGER PRIMARY KEY,
     name TEXT,
-    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    topic TEXT,                           -- one line shown under the chat's name, also set with /topic
+    description TEXT,
+    avatar_attachment_id INTEGER,         -- image uploaded to the chat, shown as its avatar
+    created_by INTEGER,                   -- user who created the chat, NULL for older chats
+    visibility TEXT DEFAULT 'private',    -- public chats are listed in the directory and anyone may join
+    retention_days INTEGER,               -- messages older than this many days are purged, NULL keeps them
+    retention_keep_last INTEGER,          -- only this many of the newest messages are kept, NULL keeps all
+    archived_at TIMESTAMP,                -- archived chats are read-only and hidden from the chat list
+    deleted_at TIMESTAMP,                 -- deleted chats can be restored by their owner for 30 days
+    deleted_by INTEGER                    -- user who deleted the chat
 );
 
 -- Chat users table
@@ -21,6 +41,12 @@ CREATE TABLE chat_users (
     user_id INTEGER NOT NULL,
     is_active BOOLEAN DEFAULT 0,
     joined_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    last_read_message_id INTEGER DEFAULT 0, -- messages.id of the newest message this member has seen
+    role TEXT DEFAULT 'member',           -- owner, admin or member
+    notifications_muted_until TIMESTAMP,  -- the member's own mute, silences notifications until then
+    notification_level TEXT DEFAULT 'all', -- all, mentions or none
+    favorite BOOLEAN DEFAULT 0,
+    sort_order INTEGER,                   -- member's own position for the chat in list_chats, lowest first
     FOREIGN KEY(chat_id) REFERENCES chats(id) ON DELETE CASCADE,
     FOREIGN KEY(user_id) REFERENCES users(id)
 );
@@ -33,19 +59,29 @@ CREATE TABLE messages (
     content TEXT NOT NULL,
     created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
     status TEXT,
+    rejection_reason TEXT,                -- set when moderation rejects the message, shown to the author
+    display_name TEXT,                    -- name shown instead of the author's, set by incoming webhooks
+    command_output TEXT,                  -- private reply to a slash command, shown to the author
+    expires_at TIMESTAMP,                 -- self-destruct time set from the message's ttl_seconds
+    ttl_seconds INTEGER,                  -- kept so expires_at moves along when a scheduled message is rescheduled
     FOREIGN KEY(chat_id) REFERENCES chats(id) ON DELETE CASCADE,
     FOREIGN KEY(user_id) REFERENCES users(id)
 );
 
 -- Message queue table
 CREATE TABLE message_queue (
-    id INTEGER PRIMARY KEY,
-    message_id INTEGER NOT NULL,
+    id INTEGER PRIMARY KEY AUTOINCREMENT, -- never reused, ids are sent to webhooks as X-Chat-Delivery
+    message_id INTEGER NOT NULL,          -- no foreign key: message.deleted deliveries outlive their message
     direction TEXT,
     queued_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
     processed_at TIMESTAMP,
     status TEXT,
-    FOREIGN KEY(message_id) REFERENCES messages(id)
+    webhook_id INTEGER,                   -- outbound entries: the webhook to deliver to
+    event TEXT,                           -- outbound entries: e.g. message.sent
+    attempts INTEGER DEFAULT 0,           -- outbound entries: deliveries tried so far
+    next_attempt_at TIMESTAMP,            -- outbound entries: when the next try is due
+    deliver_at TIMESTAMP,                 -- inbound entries: scheduled delivery time, NULL to post right away
+    FOREIGN KEY(webhook_id) REFERENCES webhooks(id) ON DELETE CASCADE
 );
 
 -- Chat history cache table
@@ -55,4 +91,260 @@ CREATE TABLE chat_history_cache (
     message_history TEXT,                 -- SQLite supports JSON functions if stored as TEXT
     updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
     FOREIGN KEY(chat_id) REFERENCES chats(id) ON DELETE CASCADE
-);
\ No newline at end of file
+);
+
+-- Reactions table
+CREATE TABLE reactions (
+    id INTEGER PRIMARY KEY,
+    message_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    emoji TEXT NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE(message_id, user_id, emoji),
+    FOREIGN KEY(message_id) REFERENCES messages(id) ON DELETE CASCADE,
+    FOREIGN KEY(user_id) REFERENCES users(id)
+);
+
+
+-- Mentions table
+CREATE TABLE mentions (
+    id INTEGER PRIMARY KEY,
+    message_id INTEGER NOT NULL,
+    chat_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,             -- the user who was mentioned
+    kind TEXT NOT NULL,                   -- direct, here or all
+    is_read BOOLEAN DEFAULT 0,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE(message_id, user_id),
+    FOREIGN KEY(message_id) REFERENCES messages(id) ON DELETE CASCADE,
+    FOREIGN KEY(chat_id) REFERENCES chats(id) ON DELETE CASCADE,
+    FOREIGN KEY(user_id) REFERENCES users(id)
+);
+
+
+-- Attachments table; file contents live in the blob store keyed by sha256, so identical uploads share one blob
+CREATE TABLE attachments (
+    id INTEGER PRIMARY KEY,
+    sha256 TEXT NOT NULL,
+    filename TEXT NOT NULL,
+    content_type TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    chat_id INTEGER NOT NULL,
+    uploaded_by INTEGER NOT NULL,
+    message_id INTEGER,                   -- NULL until the upload is attached to a message
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY(chat_id) REFERENCES chats(id) ON DELETE CASCADE,
+    FOREIGN KEY(uploaded_by) REFERENCES users(id),
+    FOREIGN KEY(message_id) REFERENCES messages(id) ON DELETE CASCADE
+);
+
+
+-- Password reset codes; only an Argon2 hash of each code is stored, and a code works once
+CREATE TABLE password_resets (
+    id INTEGER PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    code_hash TEXT NOT NULL,
+    issued_by INTEGER,                    -- admin who issued the code
+    expires_at TIMESTAMP NOT NULL,
+    used_at TIMESTAMP,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE,
+    FOREIGN KEY(issued_by) REFERENCES users(id)
+);
+
+-- Usernames are unique regardless of case. Enforced with triggers rather than a NOCASE index so
+-- databases holding names that differ only in case from before this rule still open
+CREATE TRIGGER users_username_nocase_insert BEFORE INSERT ON users
+WHEN EXISTS (SELECT 1 FROM users WHERE username = NEW.username COLLATE NOCASE)
+BEGIN
+    SELECT RAISE(ABORT, 'Username is already taken');
+END;
+
+CREATE TRIGGER users_username_nocase_update BEFORE UPDATE OF username ON users
+WHEN EXISTS (SELECT 1 FROM users WHERE username = NEW.username COLLATE NOCASE AND id != NEW.id)
+BEGIN
+    SELECT RAISE(ABORT, 'Username is already taken');
+END;
+
+-- Invite codes for invite-only registration; each code registers one account
+CREATE TABLE invites (
+    id INTEGER PRIMARY KEY,
+    code TEXT UNIQUE NOT NULL,
+    created_by INTEGER NOT NULL,          -- admin who created the invite
+    used_by INTEGER,                      -- account registered with it, NULL while unused
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    used_at TIMESTAMP,
+    FOREIGN KEY(created_by) REFERENCES users(id),
+    FOREIGN KEY(used_by) REFERENCES users(id)
+);
+
+-- Single-use two-factor recovery codes; only an Argon2 hash of each code is stored
+CREATE TABLE recovery_codes (
+    id INTEGER PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    code_hash TEXT NOT NULL,
+    used_at TIMESTAMP,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+-- Long-lived API tokens for bot accounts; only a SHA-256 hash of each token is stored
+CREATE TABLE api_tokens (
+    id INTEGER PRIMARY KEY,
+    user_id INTEGER NOT NULL,             -- the bot the token acts as
+    label TEXT,
+    token_hash TEXT UNIQUE NOT NULL,
+    scope TEXT NOT NULL,                  -- read, post or read_post
+    created_by INTEGER NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    last_used_at TIMESTAMP,
+    revoked_at TIMESTAMP,
+    scoped INTEGER NOT NULL DEFAULT 0,    -- limited to the chats in api_token_chats, even once they are all purged
+    FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE,
+    FOREIGN KEY(created_by) REFERENCES users(id)
+);
+
+-- Chats a token is limited to; a token without rows here works in every chat the bot belongs to
+CREATE TABLE api_token_chats (
+    token_id INTEGER NOT NULL,
+    chat_id INTEGER NOT NULL,
+    PRIMARY KEY(token_id, chat_id),
+    FOREIGN KEY(token_id) REFERENCES api_tokens(id) ON DELETE CASCADE,
+    FOREIGN KEY(chat_id) REFERENCES chats(id) ON DELETE CASCADE
+);
+
+-- Outgoing webhooks: each message event in the chat is POSTed to the URL, signed with the secret
+CREATE TABLE webhooks (
+    id INTEGER PRIMARY KEY,
+    chat_id INTEGER NOT NULL,
+    url TEXT NOT NULL,
+    secret TEXT NOT NULL,                 -- HMAC-SHA256 key for the X-Chat-Signature header
+    created_by INTEGER NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY(chat_id) REFERENCES chats(id) ON DELETE CASCADE,
+    FOREIGN KEY(created_by) REFERENCES users(id)
+);
+
+-- One row per webhook delivery attempt
+CREATE TABLE webhook_deliveries (
+    id INTEGER PRIMARY KEY,
+    queue_id INTEGER NOT NULL,            -- message_queue entry being delivered
+    webhook_id INTEGER NOT NULL,
+    attempt INTEGER NOT NULL,
+    status_code INTEGER,                  -- HTTP status, NULL if no response arrived
+    error TEXT,
+    duration_ms INTEGER,
+    attempted_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY(webhook_id) REFERENCES webhooks(id) ON DELETE CASCADE
+);
+
+-- Incoming webhooks: posting to /hooks/<token> adds a message to the chat as the hook's bot account
+CREATE TABLE incoming_webhooks (
+    id INTEGER PRIMARY KEY,
+    chat_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,             -- bot account the messages are posted as
+    token_hash TEXT UNIQUE NOT NULL,      -- SHA-256 of the secret token in the URL
+    created_by INTEGER NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    last_used_at TIMESTAMP,
+    revoked_at TIMESTAMP,
+    FOREIGN KEY(chat_id) REFERENCES chats(id) ON DELETE CASCADE,
+    FOREIGN KEY(user_id) REFERENCES users(id),
+    FOREIGN KEY(created_by) REFERENCES users(id)
+);
+
+-- Pinned messages; any member may pin or unpin
+CREATE TABLE pins (
+    id INTEGER PRIMARY KEY,
+    chat_id INTEGER NOT NULL,
+    message_id INTEGER NOT NULL,
+    pinned_by INTEGER NOT NULL,
+    pinned_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE(chat_id, message_id),
+    FOREIGN KEY(chat_id) REFERENCES chats(id) ON DELETE CASCADE,
+    FOREIGN KEY(message_id) REFERENCES messages(id) ON DELETE CASCADE,
+    FOREIGN KEY(pinned_by) REFERENCES users(id)
+);
+
+-- Users someone has blocked; blocked users cannot start direct chats with them and are hidden from their history
+CREATE TABLE blocks (
+    id INTEGER PRIMARY KEY,
+    blocker_id INTEGER NOT NULL,
+    blocked_id INTEGER NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE(blocker_id, blocked_id),
+    FOREIGN KEY(blocker_id) REFERENCES users(id),
+    FOREIGN KEY(blocked_id) REFERENCES users(id)
+);
+
+-- Moderation actions with who took them, why and until when; mutes and bans are in force while unexpired and unrevoked
+CREATE TABLE moderation_actions (
+    id INTEGER PRIMARY KEY,
+    action TEXT NOT NULL,                 -- kick, mute, chat_ban or global_ban
+    chat_id INTEGER,                      -- NULL for global bans
+    target_id INTEGER NOT NULL,
+    actor_id INTEGER NOT NULL,
+    reason TEXT,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMP,                 -- NULL for kicks and permanent bans
+    revoked_at TIMESTAMP,                 -- set when a mute or ban is lifted early
+    revoked_by INTEGER,
+    FOREIGN KEY(chat_id) REFERENCES chats(id) ON DELETE CASCADE,
+    FOREIGN KEY(target_id) REFERENCES users(id),
+    FOREIGN KEY(actor_id) REFERENCES users(id),
+    FOREIGN KEY(revoked_by) REFERENCES users(id)
+);
+
+-- Messages reported by members, worked through by moderators; the message's text and author are copied so
+-- the report outlives the message
+CREATE TABLE reports (
+    id INTEGER PRIMARY KEY,
+    message_id INTEGER NOT NULL,
+    chat_id INTEGER NOT NULL,
+    author_id INTEGER NOT NULL,
+    content TEXT NOT NULL,
+    reporter_id INTEGER NOT NULL,
+    reason TEXT NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    status TEXT NOT NULL DEFAULT 'open',  -- open, claimed or resolved
+    claimed_by INTEGER,
+    claimed_at TIMESTAMP,
+    resolved_by INTEGER,
+    resolved_at TIMESTAMP,
+    outcome TEXT,                         -- dismissed, deleted, muted or banned
+    note TEXT,
+    action_id INTEGER,                    -- the mute or ban taken on the author
+    UNIQUE(message_id, reporter_id),
+    FOREIGN KEY(chat_id) REFERENCES chats(id) ON DELETE CASCADE,
+    FOREIGN KEY(author_id) REFERENCES users(id),
+    FOREIGN KEY(reporter_id) REFERENCES users(id),
+    FOREIGN KEY(claimed_by) REFERENCES users(id),
+    FOREIGN KEY(resolved_by) REFERENCES users(id),
+    FOREIGN KEY(action_id) REFERENCES moderation_actions(id)
+);
+
+-- Append-only record of security-relevant events; names are copied so entries outlive renamed or purged
+-- accounts and chats
+CREATE TABLE audit_log (
+    id INTEGER PRIMARY KEY,
+    event TEXT NOT NULL,                  -- e.g. login_failed, chat_created, member_removed, totp_enabled
+    actor_id INTEGER,                     -- who did it, NULL for the server itself or an unknown user
+    actor TEXT,
+    target_id INTEGER,                    -- the account acted on
+    target TEXT,
+    chat_id INTEGER,
+    chatname TEXT,
+    ip TEXT,                              -- NULL for actions that did not come from a request
+    detail TEXT,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TRIGGER audit_log_no_update BEFORE UPDATE ON audit_log
+BEGIN
+    SELECT RAISE(ABORT, 'audit_log is append-only');
+END;
+
+CREATE TRIGGER audit_log_no_delete BEFORE DELETE ON audit_log
+BEGIN
+    SELECT RAISE(ABORT, 'audit_log is append-only');
+END;
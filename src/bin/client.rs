@@ -1,17 +1,196 @@
+use dialoguer::console::{Key, Term};
 use dialoguer::{Input, Select};
 use serde::{Deserialize, Serialize};
+use reqwest::multipart::{Form, Part};
 use reqwest::Client;
 
 #[derive(Serialize)]
 struct Message {
     content: String,
+    attachments: Vec<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    deliver_at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ttl_seconds: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct AttachmentInfo {
+    id: i64,
+    filename: String,
+    size: i64,
 }
 
 #[derive(Deserialize)]
 struct ChatHistoryMessage {
+    #[serde(default)]
+    id: i64,
     username: String,
+    #[serde(default)]
+    display_name: Option<String>,
     content: String,
     created_at: String,
+    #[serde(default)]
+    reactions: Vec<ReactionSummary>,
+    #[serde(default)]
+    seen_by: Option<Vec<String>>,
+    #[serde(default)]
+    attachments: Vec<AttachmentInfo>,
+    #[serde(default)]
+    bot: bool,
+    #[serde(default)]
+    expires_at: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ScheduledMessage {
+    message_id: i64,
+    chatname: String,
+    content: String,
+    deliver_at: String,
+}
+
+#[derive(Deserialize)]
+struct BlockedUser {
+    username: String,
+    blocked_at: String,
+}
+
+#[derive(Deserialize)]
+struct Enrollment {
+    secret: String,
+    otpauth_uri: String,
+}
+
+#[derive(Deserialize)]
+struct ReactionSummary {
+    emoji: String,
+    count: i64,
+    reacted_by_me: bool,
+}
+
+#[derive(Deserialize)]
+struct Mention {
+    message_id: i64,
+    chatname: String,
+    author: String,
+    content: String,
+    created_at: String,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ChatEvent {
+    Message { message: ChatHistoryMessage },
+    Reaction { message_id: i64, username: String, emoji: String, added: bool },
+    Pin { message_id: i64, username: String, pinned: bool },
+    Read { username: String, message_id: i64 },
+    Typing { username: String, typing: bool },
+    Rejected { message_id: i64, reason: String },
+    CommandOutput { output: String },
+}
+
+/// Formats a history entry as "#id Display Name (username) [time]: content  reactions"
+fn format_message(m: &ChatHistoryMessage) -> String {
+    let badge = if m.bot { " [BOT]" } else { "" };
+    let author = match &m.display_name {
+        Some(name) => format!("{} ({})", name, m.username),
+        None => m.username.clone(),
+    };
+    let mut line = format!("#{} {}{} [{}]: {}", m.id, author, badge, m.created_at, m.content);
+    for r in &m.reactions {
+        let mine = if r.reacted_by_me { "*" } else { "" };
+        line.push_str(&format!("  {}{}{}", r.emoji, r.count, mine));
+    }
+    for a in &m.attachments {
+        line.push_str(&format!("  [attachment {}: {} ({} bytes)]", a.id, a.filename, a.size));
+    }
+    if let Some(seen_by) = m.seen_by.as_ref().filter(|s| !s.is_empty()) {
+        line.push_str(&format!("  (seen by {})", seen_by.join(", ")));
+    }
+    if let Some(expires_at) = &m.expires_at {
+        line.push_str(&format!("  (disappears at {} UTC)", expires_at));
+    }
+    line
+}
+
+/// Guesses a MIME type from the file extension, the server decides whether the type is allowed
+fn guess_content_type(path: &str) -> &'static str {
+    let extension = path.rsplit('.').next().unwrap_or("").to_lowercase();
+    match extension.as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "pdf" => "application/pdf",
+        "txt" => "text/plain",
+        "zip" => "application/zip",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Uploads a local file into a chat and returns the attachment id to send with a message
+async fn upload_file(client: &Client, base: &str, chat: &str, username: &str, path: &str) -> Result<Option<i64>, reqwest::Error> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            println!("Could not read {}: {}", path, e);
+            return Ok(None);
+        }
+    };
+    let filename = std::path::Path::new(path).file_name().unwrap().to_string_lossy().to_string();
+    let part = Part::bytes(bytes).file_name(filename).mime_str(guess_content_type(path))?;
+    let url = format!("{}/upload/chatname/{}/username/{}", base, chat, username);
+    let res = client.post(url).multipart(Form::new().part("file", part)).send().await?;
+    match res.json::<Result<Vec<AttachmentInfo>, String>>().await? {
+        Ok(uploaded) => Ok(uploaded.first().map(|a| a.id)),
+        Err(e) => {
+            println!("Upload failed: {}", e);
+            Ok(None)
+        }
+    }
+}
+
+/// Prints events from the chat's server-sent event stream until the connection closes
+async fn print_events(client: Client, url: String) -> Result<(), reqwest::Error> {
+    let mut res = client.get(url).send().await?;
+    let mut buffer = String::new();
+    while let Some(chunk) = res.chunk().await? {
+        buffer.push_str(&String::from_utf8_lossy(&chunk));
+        // Events are separated by a blank line; keep any partial event for the next chunk
+        while let Some(end) = buffer.find("\n\n") {
+            let raw: String = buffer.drain(..end + 2).collect();
+            for data in raw.lines().filter_map(|l| l.strip_prefix("data:")) {
+                match serde_json::from_str::<ChatEvent>(data.trim()) {
+                    Ok(ChatEvent::Message { message }) => println!("{}", format_message(&message)),
+                    Ok(ChatEvent::Reaction { message_id, username, emoji, added }) => {
+                        let verb = if added { "reacted" } else { "removed" };
+                        println!("* {} {} {} on #{}", username, verb, emoji, message_id);
+                    }
+                    Ok(ChatEvent::Pin { message_id, username, pinned }) => {
+                        let verb = if pinned { "pinned" } else { "unpinned" };
+                        println!("* {} {} #{}", username, verb, message_id);
+                    }
+                    Ok(ChatEvent::Read { username, message_id }) => {
+                        println!("* {} read up to #{}", username, message_id);
+                    }
+                    Ok(ChatEvent::Rejected { message_id, reason }) => {
+                        println!("* Your message #{} was rejected: {}", message_id, reason);
+                    }
+                    Ok(ChatEvent::CommandOutput { output }) => println!("{}", output),
+                    Ok(ChatEvent::Typing { username, typing }) => {
+                        if typing {
+                            println!("* {} is typing...", username);
+                        } else {
+                            println!("* {} stopped typing", username);
+                        }
+                    }
+                    Err(_) => {}
+                }
+            }
+        }
+    }
+    Ok(())
 }
 
 #[tokio::main]
@@ -26,6 +205,12 @@ async fn main() -> Result<(), reqwest::Error> {
             "Send Message",
             "Get Chat History",
             "Create Chat",
+            "React to Message",
+            "Live Chat",
+            "Unread Mentions",
+            "Two-Factor Setup",
+            "Scheduled Messages",
+            "Blocked Users",
             "Quit",
         ];
 
@@ -41,16 +226,35 @@ async fn main() -> Result<(), reqwest::Error> {
                 let password: String = Input::new().with_prompt("Password").interact().unwrap();
 
                 let url = format!("{}/Authenticate/username/{}/password/{}", base, username, password);
-                let res = client.get(url).send().await?;
+                let res = client.get(&url).send().await?;
 
-                println!("Response: {:?}", res.text().await?);
+                match res.json::<Result<String, String>>().await {
+                    // "2" means the password was right and the account has two-factor enabled
+                    Ok(Ok(status)) if status == "2" => {
+                        let code: String = Input::new()
+                            .with_prompt("Authentication code (or recovery code)")
+                            .interact()
+                            .unwrap();
+                        let res = client.get(format!("{}?code={}", url, code)).send().await?;
+                        println!("Response: {:?}", res.text().await?);
+                    }
+                    other => println!("Response: {:?}", other),
+                }
             }
 
             1 => {
                 let username: String = Input::new().with_prompt("New Username").interact().unwrap();
                 let password: String = Input::new().with_prompt("New Password").interact().unwrap();
+                let invite: String = Input::new()
+                    .with_prompt("Invite code (if required)")
+                    .allow_empty(true)
+                    .interact()
+                    .unwrap();
 
-                let url = format!("{}/createaccount/username/{}/password/{}", base, username, password);
+                let mut url = format!("{}/createaccount/username/{}/password/{}", base, username, password);
+                if !invite.is_empty() {
+                    url = format!("{}?invite={}", url, invite);
+                }
                 let res = client.get(url).send().await?;
 
                 println!("Response: {:?}", res.text().await?);
@@ -60,8 +264,40 @@ async fn main() -> Result<(), reqwest::Error> {
                 let chat: String = Input::new().with_prompt("Chat Name").interact().unwrap();
                 let username: String = Input::new().with_prompt("Your Username").interact().unwrap();
                 let content: String = Input::new().with_prompt("Message").interact().unwrap();
+                let path: String = Input::new()
+                    .with_prompt("Attachment path (optional)")
+                    .allow_empty(true)
+                    .interact()
+                    .unwrap();
 
-                let msg = Message { content };
+                let when: String = Input::new()
+                    .with_prompt("Deliver at (UTC, e.g. 2025-06-01 09:00; blank to send now)")
+                    .allow_empty(true)
+                    .interact()
+                    .unwrap();
+                let deliver_at = if when.is_empty() { None } else { Some(when) };
+                let ttl: String = Input::new()
+                    .with_prompt("Delete after how many seconds (blank to keep)")
+                    .allow_empty(true)
+                    .interact()
+                    .unwrap();
+                let ttl_seconds = match ttl.parse::<i64>() {
+                    Ok(secs) => Some(secs),
+                    Err(_) if ttl.is_empty() => None,
+                    Err(_) => {
+                        println!("Not a number of seconds");
+                        continue;
+                    }
+                };
+
+                let mut attachments = Vec::new();
+                if !path.is_empty() {
+                    match upload_file(&client, base, &chat, &username, &path).await? {
+                        Some(id) => attachments.push(id),
+                        None => continue,
+                    }
+                }
+                let msg = Message { content, attachments, deliver_at, ttl_seconds };
                 let url = format!("{}/newmessage/chatname/{}/username/{}", base, chat, username);
 
                 let res = client.post(url).json(&msg).send().await?;
@@ -70,16 +306,19 @@ async fn main() -> Result<(), reqwest::Error> {
 
             3 => {
                 let chat: String = Input::new().with_prompt("Chat Name").interact().unwrap();
+                let username: String = Input::new().with_prompt("Your Username").interact().unwrap();
 
-                let url = format!("{}/getchat/chatname/{}", base, chat);
+                let url = format!("{}/getchat/chatname/{}?username={}", base, chat, username);
                 let res = client.get(url).send().await?;
 
                 match res.json::<Vec<ChatHistoryMessage>>().await {
                     Ok(history) => {
                         println!("\nChat History:");
                         for m in history {
-                            println!("{} [{}]: {}", m.username, m.created_at, m.content);
+                            println!("{}", format_message(&m));
                         }
+                        let url = format!("{}/markread/chatname/{}/username/{}", base, chat, username);
+                        client.post(url).send().await?;
                     }
                     Err(_) => println!("No chat history or error occurred"),
                 }
@@ -103,6 +342,185 @@ async fn main() -> Result<(), reqwest::Error> {
             }
 
             5 => {
+                let chat: String = Input::new().with_prompt("Chat Name").interact().unwrap();
+                let username: String = Input::new().with_prompt("Your Username").interact().unwrap();
+                let message_id: i64 = Input::new().with_prompt("Message #").interact().unwrap();
+                let emoji: String = Input::new().with_prompt("Emoji").interact().unwrap();
+                let remove = Select::new()
+                    .with_prompt("Add or remove?")
+                    .items(&["Add", "Remove"])
+                    .interact()
+                    .unwrap() == 1;
+
+                let url = format!(
+                    "{}/reaction/chatname/{}/username/{}/messageid/{}/emoji/{}",
+                    base, chat, username, message_id, emoji
+                );
+                let res = if remove { client.delete(url) } else { client.post(url) }.send().await?;
+                println!("Response: {:?}", res.text().await?);
+            }
+
+            6 => {
+                let chat: String = Input::new().with_prompt("Chat Name").interact().unwrap();
+                let username: String = Input::new().with_prompt("Your Username").interact().unwrap();
+
+                let url = format!("{}/events/chatname/{}/username/{}", base, chat, username);
+                let listener = tokio::spawn(print_events(client.clone(), url));
+                println!("Live in {} (press Enter on an empty line to leave)", chat);
+
+                let typing_url = format!("{}/typing/chatname/{}/username/{}/state", base, chat, username);
+                loop {
+                    // Wait for the first keystroke so the typing signal goes out when typing actually starts
+                    let first = match Term::stdout().read_key().unwrap() {
+                        Key::Enter => break,
+                        Key::Char(c) => c.to_string(),
+                        _ => String::new(),
+                    };
+                    client.post(format!("{}/start", typing_url)).send().await?;
+                    let content: String = Input::new()
+                        .with_initial_text(first)
+                        .allow_empty(true)
+                        .interact_text()
+                        .unwrap();
+                    client.post(format!("{}/stop", typing_url)).send().await?;
+                    if content.is_empty() {
+                        break;
+                    }
+                    let url = format!("{}/newmessage/chatname/{}/username/{}", base, chat, username);
+                    client.post(url).json(&Message { content, attachments: Vec::new(), deliver_at: None, ttl_seconds: None }).send().await?;
+                }
+                listener.abort();
+            }
+
+            7 => {
+                let username: String = Input::new().with_prompt("Your Username").interact().unwrap();
+
+                let url = format!("{}/mentions/username/{}", base, username);
+                let res = client.get(url).send().await?;
+
+                match res.json::<Result<Vec<Mention>, String>>().await {
+                    Ok(Ok(mentions)) if !mentions.is_empty() => {
+                        println!("\nUnread Mentions:");
+                        for m in mentions {
+                            println!("[{}] #{} {} [{}]: {}", m.chatname, m.message_id, m.author, m.created_at, m.content);
+                        }
+                    }
+                    Ok(Ok(_)) => println!("No unread mentions"),
+                    _ => println!("Could not fetch mentions"),
+                }
+            }
+
+            8 => {
+                let username: String = Input::new().with_prompt("Username").interact().unwrap();
+                let password: String = Input::new().with_prompt("Password").interact().unwrap();
+
+                let url = format!("{}/totp/enroll/username/{}", base, username);
+                let res = client.post(url).json(&serde_json::json!({ "password": password })).send().await?;
+                let enrollment = match res.json::<Result<Enrollment, String>>().await {
+                    Ok(Ok(enrollment)) => enrollment,
+                    Ok(Err(e)) => {
+                        println!("Error: {}", e);
+                        continue;
+                    }
+                    Err(_) => {
+                        println!("Could not start two-factor setup");
+                        continue;
+                    }
+                };
+                println!("Add this key to your authenticator app: {}", enrollment.secret);
+                println!("Or open this link on the device: {}", enrollment.otpauth_uri);
+
+                let code: String = Input::new().with_prompt("Code shown by the app").interact().unwrap();
+                let url = format!("{}/totp/confirm/username/{}", base, username);
+                let res = client
+                    .post(url)
+                    .json(&serde_json::json!({ "password": password, "code": code }))
+                    .send()
+                    .await?;
+                match res.json::<Result<Vec<String>, String>>().await {
+                    Ok(Ok(codes)) => {
+                        println!("Two-factor authentication is on. Keep these recovery codes somewhere safe:");
+                        for code in codes {
+                            println!("  {}", code);
+                        }
+                    }
+                    Ok(Err(e)) => println!("Error: {}", e),
+                    Err(_) => println!("Could not confirm two-factor setup"),
+                }
+            }
+
+            9 => {
+                let username: String = Input::new().with_prompt("Your Username").interact().unwrap();
+
+                let url = format!("{}/scheduled/username/{}", base, username);
+                let res = client.get(url).send().await?;
+                let pending = match res.json::<Result<Vec<ScheduledMessage>, String>>().await {
+                    Ok(Ok(pending)) if !pending.is_empty() => pending,
+                    Ok(Ok(_)) => {
+                        println!("No scheduled messages");
+                        continue;
+                    }
+                    _ => {
+                        println!("Could not fetch scheduled messages");
+                        continue;
+                    }
+                };
+                println!("\nScheduled Messages:");
+                for m in &pending {
+                    println!("#{} [{}] at {} UTC: {}", m.message_id, m.chatname, m.deliver_at, m.content);
+                }
+
+                let action = Select::new()
+                    .with_prompt("Action")
+                    .items(&["Done", "Reschedule", "Cancel"])
+                    .interact()
+                    .unwrap();
+                if action == 0 {
+                    continue;
+                }
+                let message_id: i64 = Input::new().with_prompt("Message ID").interact().unwrap();
+                let res = if action == 1 {
+                    let when: String = Input::new().with_prompt("New delivery time (UTC)").interact().unwrap();
+                    let url = format!("{}/editscheduled/messageid/{}/username/{}", base, message_id, username);
+                    client.post(url).json(&serde_json::json!({ "deliver_at": when })).send().await?
+                } else {
+                    let url = format!("{}/cancelscheduled/messageid/{}/username/{}", base, message_id, username);
+                    client.post(url).send().await?
+                };
+                println!("Response: {:?}", res.text().await?);
+            }
+
+            10 => {
+                let username: String = Input::new().with_prompt("Your Username").interact().unwrap();
+
+                let url = format!("{}/blocks/username/{}", base, username);
+                let res = client.get(url).send().await?;
+                match res.json::<Result<Vec<BlockedUser>, String>>().await {
+                    Ok(Ok(blocked)) if !blocked.is_empty() => {
+                        println!("\nBlocked Users:");
+                        for b in &blocked {
+                            println!("{} (since {} UTC)", b.username, b.blocked_at);
+                        }
+                    }
+                    Ok(Ok(_)) => println!("You have not blocked anyone"),
+                    _ => println!("Could not fetch blocked users"),
+                }
+
+                let action = Select::new()
+                    .with_prompt("Action")
+                    .items(&["Done", "Block someone", "Unblock someone"])
+                    .interact()
+                    .unwrap();
+                if action == 0 {
+                    continue;
+                }
+                let target: String = Input::new().with_prompt("Username").interact().unwrap();
+                let url = format!("{}/block/username/{}/target/{}", base, username, target);
+                let res = if action == 1 { client.post(url) } else { client.delete(url) }.send().await?;
+                println!("Response: {:?}", res.text().await?);
+            }
+
+            11 => {
                 println!("Goodbye!");
                 break;
             }
@@ -10,8 +10,6 @@ use argon2::{
     },
     Argon2
 };use sqlx::{query, SqlitePool};
-use chrono;
-use tokio;
 
 // Things the central sever processor needs to handle:
 //    User prescence: Whether a user is currently online or not
@@ -43,7 +41,7 @@ async fn main() -> Result<(), sqlx::Error>{
     dotenv::dotenv().ok();
     let pool = SqlitePool::connect("sqlite:chat.db").await?;
     let mut thread_handlers = Vec::new();
-    for i in 0..NUM_THREADS{
+    for _i in 0..NUM_THREADS{
         let thread_pool = pool.clone();
         thread_handlers.push(tokio::spawn(async move {
             message_thread(thread_pool).await;
@@ -76,7 +74,7 @@ async fn root() -> Json<String>{
 /// TODO: Shared state concurency & synchronization when running multiple message_threads on sqlite database
 async fn message_thread(pool:SqlitePool){
     let limit:i64 = 5;
-    while true {
+    loop {
         let curr_message = match query!(
             "SELECT id, message_id FROM message_queue WHERE status = 'Queued' ORDER BY queued_at ASC LIMIT ?", limit)
             .fetch_optional(&pool)
@@ -100,7 +98,7 @@ async fn message_thread(pool:SqlitePool){
         if let Some(json_string) = json_message_history {
             let mut messages: Vec<ChatHistoryMessage> = serde_json::from_str(&json_string).unwrap();
             messages.push(ChatHistoryMessage{
-                username:username,
+                username,
                 content: message_content,
                 created_at: chrono::Utc::now().to_rfc3339(),
             });
@@ -141,9 +139,9 @@ async fn get_message_history(
         fetch_one(&pool).await.unwrap().message_history;
     if let Some(json_string) = json_message_history {
         let  messages: Vec<ChatHistoryMessage> = serde_json::from_str(&json_string).unwrap();
-        return Ok(Json(messages));
+        Ok(Json(messages))
     } else{
-        return Err(());
+        Err(())
     }
 }
 /// Queues incoming messages from users; Messages are added to priority queue (by time created) in sql database and processed by background threads
@@ -223,7 +221,7 @@ Query(params): Query<CreateChatParams>) -> Json<Result<(), ()>>{
             VALUES (?, ?, 1, datetime('now'))"#, chat_id, user_id
             ).execute(&pool).await.unwrap();
     }
-    return Json(Ok(()));
+    Json(Ok(()))
 }
 /// Checks for existing user:
 async fn check_user_exist(username: String, pool : SqlitePool)->Result<i64, sqlx::Error> {
@@ -234,7 +232,7 @@ async fn check_user_exist(username: String, pool : SqlitePool)->Result<i64, sqlx
     
     .fetch_one(&pool)
     .await?;
-    return Ok(exists); 
+    Ok(exists)
 }
 /// Routing function for checking for existing user
 async fn check_user_route(State(pool): State<SqlitePool>, Path(username):Path<String>)->Json<Result<String, String>>{
@@ -256,7 +254,7 @@ async fn new_user(State(pool): State<SqlitePool>, Path((username, password)):Pat
     r#"INSERT INTO users (username, password, role, created_at)
     VALUES (?, ?, ?, datetime('now'))"#, username, password_hash, role
     ).execute(&pool).await.unwrap();
-    return Json(Ok(String::from("1")));
+    Json(Ok(String::from("1")))
 }
 /// Authenticates user login
 /// # Query format:
@@ -274,19 +272,19 @@ async fn login(State(pool): State<SqlitePool>, Path((username, password)): Path<
                     .verify_password(password.as_bytes(), &parsed_hash)
                     .is_ok()
                 {
-                    return Json(Ok(String::from("1")));
+                    Json(Ok(String::from("1")))
                 } else {
-                    return Json(Ok(String::from("0")));
+                    Json(Ok(String::from("0")))
                 }
             }
             Err(_) => {
                 // The hash in the database is invalid
                 println!("Stored password hash is invalid!");
-                return Json(Ok(String::from("0")));
+                Json(Ok(String::from("0")))
             }
         }
     } else {
         println!("Username not found");
-        return Json(Ok(String::from("0")));
+        Json(Ok(String::from("0")))
     }
 }
@@ -0,0 +1,308 @@
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use axum::{extract::{ConnectInfo, Path, State}, response::Json};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::Deserialize;
+use sqlx::{query, SqlitePool};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+
+use crate::attachments::{self, Attachments};
+use crate::audit::{self, AuditEvent};
+use crate::ratelimit::RateLimits;
+use crate::registration::RegistrationPolicy;
+use crate::ChatHistoryMessage;
+
+/// Length of a password reset code
+const RESET_CODE_LENGTH: usize = 10;
+/// How long a reset code stays valid, as an SQLite datetime modifier
+const RESET_CODE_LIFETIME: &str = "+30 minutes";
+/// Name shown in chat history in place of a deleted account
+const DELETED_USER_NAME: &str = "Deleted user";
+
+#[derive(Deserialize)]
+pub(crate) struct ChangePasswordRequest {
+    old_password: String,
+    new_password: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct IssueResetRequest {
+    admin_password: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ResetPasswordRequest {
+    code: String,
+    new_password: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct DeleteAccountRequest {
+    password: String,
+}
+
+/// Hashes a password (or reset code) with Argon2 and a random salt
+pub(crate) fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default().hash_password(password.as_bytes(), &salt).unwrap().to_string()
+}
+
+/// Checks a password against a stored Argon2 hash; an unparsable hash never matches
+pub(crate) fn password_matches(password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(parsed_hash) => Argon2::default().verify_password(password.as_bytes(), &parsed_hash).is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Returns the user's id and global role if the password is correct
+pub(crate) async fn verify_credentials(username: &str, password: &str, pool: &SqlitePool) -> Option<(i64, String)> {
+    let row = query!(r#"SELECT id AS "id!", password, role FROM users WHERE username = ?"#, username)
+        .fetch_optional(pool)
+        .await
+        .unwrap()?;
+    if !password_matches(password, &row.password) {
+        return None;
+    }
+    Some((row.id, row.role.unwrap_or_default()))
+}
+
+/// Random single-use code handed to a user by an admin
+fn generate_reset_code() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(RESET_CODE_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+/// Changes a user's password after checking the current one
+/// # Query format:
+/// curl -X POST -H "Content-Type: application/json" -d '{"old_password": "Old", "new_password": "New"}' "http://98.93.98.244:80/changepassword/username/NameString"
+pub(crate) async fn change_password(
+    State(pool): State<SqlitePool>,
+    State(limits): State<RateLimits>,
+    State(policy): State<RegistrationPolicy>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(username): Path<String>,
+    Json(request): Json<ChangePasswordRequest>,
+) -> Json<Result<String, String>> {
+    let Some((user_id, _)) = verify_credentials(&username, &request.old_password, &pool).await else {
+        limits.lockout.record_failure(&username);
+        return Json(Err(String::from("Incorrect username or password")));
+    };
+    if let Err(e) = policy.check_password(&username, &request.new_password) {
+        return Json(Err(e));
+    }
+    let password_hash = hash_password(&request.new_password);
+    query!("UPDATE users SET password = ? WHERE id = ?", password_hash, user_id)
+        .execute(&pool)
+        .await
+        .unwrap();
+    audit::record(AuditEvent::PasswordChanged, Some(user_id), Some(user_id), None, Some(addr.ip()), None, &pool).await;
+    println!("Changed password for {}", username);
+    Json(Ok(String::from("1")))
+}
+
+/// Issues a password reset code for a user; only admins may do this. Any earlier unused code stops working
+/// # Query format:
+/// curl -X POST -H "Content-Type: application/json" -d '{"admin_password": "AdminPass"}' "http://98.93.98.244:80/issuereset/admin/AdminName/username/NameString"
+/// # Return format:
+/// The reset code, valid for 30 minutes, to pass on to the user
+pub(crate) async fn issue_reset(
+    State(pool): State<SqlitePool>,
+    State(limits): State<RateLimits>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path((admin, username)): Path<(String, String)>,
+    Json(request): Json<IssueResetRequest>,
+) -> Json<Result<String, String>> {
+    let admin_id = match verify_credentials(&admin, &request.admin_password, &pool).await {
+        Some((id, role)) if role == "admin" => id,
+        Some(_) => return Json(Err(String::from("Only admins can issue reset codes"))),
+        None => {
+            limits.lockout.record_failure(&admin);
+            return Json(Err(String::from("Incorrect username or password")));
+        }
+    };
+    let Some(user) = query!(r#"SELECT id AS "id!" FROM users WHERE username = ?"#, username)
+        .fetch_optional(&pool)
+        .await
+        .unwrap() else {
+        return Json(Err(String::from("User not found")));
+    };
+    query!(
+        "UPDATE password_resets SET used_at = datetime('now') WHERE user_id = ? AND used_at IS NULL",
+        user.id
+    ).execute(&pool).await.unwrap();
+    let code = generate_reset_code();
+    let code_hash = hash_password(&code);
+    query!(
+        r#"INSERT INTO password_resets (user_id, code_hash, issued_by, expires_at, used_at, created_at)
+        VALUES (?, ?, ?, datetime('now', ?), NULL, datetime('now'))"#,
+        user.id, code_hash, admin_id, RESET_CODE_LIFETIME
+    ).execute(&pool).await.unwrap();
+    audit::record(AuditEvent::PasswordResetIssued, Some(admin_id), Some(user.id), None, Some(addr.ip()), None, &pool).await;
+    println!("{} issued a password reset code for {}", admin, username);
+    Json(Ok(code))
+}
+
+/// Sets a new password using a reset code; the code is used up whether or not it arrives in time
+/// # Query format:
+/// curl -X POST -H "Content-Type: application/json" -d '{"code": "ResetCode", "new_password": "New"}' "http://98.93.98.244:80/resetpassword/username/NameString"
+pub(crate) async fn reset_password(
+    State(pool): State<SqlitePool>,
+    State(policy): State<RegistrationPolicy>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(username): Path<String>,
+    Json(request): Json<ResetPasswordRequest>,
+) -> Json<Result<String, String>> {
+    // Checked before the code is claimed so a weak password does not use it up
+    if let Err(e) = policy.check_password(&username, &request.new_password) {
+        return Json(Err(e));
+    }
+    let pending = query!(
+        r#"SELECT password_resets.id AS "id!", password_resets.user_id, password_resets.code_hash
+        FROM password_resets JOIN users ON users.id = password_resets.user_id
+        WHERE users.username = ? AND password_resets.used_at IS NULL
+        AND password_resets.expires_at > datetime('now')"#,
+        username
+    ).fetch_all(&pool).await.unwrap();
+    let Some(reset) = pending.iter().find(|r| password_matches(&request.code, &r.code_hash)) else {
+        return Json(Err(String::from("Invalid or expired reset code")));
+    };
+    // Claim the code before using it so two concurrent requests cannot both redeem it
+    let claimed = query!(
+        "UPDATE password_resets SET used_at = datetime('now') WHERE id = ? AND used_at IS NULL",
+        reset.id
+    ).execute(&pool).await.unwrap();
+    if claimed.rows_affected() == 0 {
+        return Json(Err(String::from("Invalid or expired reset code")));
+    }
+    let password_hash = hash_password(&request.new_password);
+    query!("UPDATE users SET password = ? WHERE id = ?", password_hash, reset.user_id)
+        .execute(&pool)
+        .await
+        .unwrap();
+    audit::record(AuditEvent::PasswordReset, Some(reset.user_id), Some(reset.user_id), None, Some(addr.ip()), None, &pool).await;
+    println!("Reset password for {}", username);
+    Json(Ok(String::from("1")))
+}
+
+/// Deletes an account after checking its password. The user leaves every chat, their reactions, mentions
+/// and profile are removed; their messages stay but are shown as from "Deleted user", and the username
+/// becomes free to register again
+/// # Query format:
+/// curl -X POST -H "Content-Type: application/json" -d '{"password": "PasswordString"}' "http://98.93.98.244:80/deleteaccount/username/NameString"
+pub(crate) async fn delete_account(
+    State(pool): State<SqlitePool>,
+    State(limits): State<RateLimits>,
+    State(attachments): State<Attachments>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(username): Path<String>,
+    Json(request): Json<DeleteAccountRequest>,
+) -> Json<Result<String, String>> {
+    let Some((user_id, _)) = verify_credentials(&username, &request.password, &pool).await else {
+        limits.lockout.record_failure(&username);
+        return Json(Err(String::from("Incorrect username or password")));
+    };
+    // Recorded first, while the events can still be put down to the user's real name
+    let ip = Some(addr.ip());
+    audit::record(AuditEvent::AccountDeleted, Some(user_id), Some(user_id), None, ip, None, &pool).await;
+    let memberships = query!("SELECT chat_id FROM chat_users WHERE user_id = ?", user_id)
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+    for membership in memberships {
+        let chat_id = Some(membership.chat_id);
+        audit::record(AuditEvent::MemberLeft, Some(user_id), Some(user_id), chat_id, ip, Some("Account deleted"), &pool).await;
+    }
+    let mut tx = pool.begin().await.unwrap();
+    let mut authored: HashMap<i64, HashSet<i64>> = HashMap::new();
+    let rows = query!(r#"SELECT id AS "id!", chat_id FROM messages WHERE user_id = ?"#, user_id)
+        .fetch_all(&mut *tx)
+        .await
+        .unwrap();
+    for row in rows {
+        authored.entry(row.chat_id).or_default().insert(row.id);
+    }
+    // The cached history stores names rather than ids, so rewrite the author in every chat they posted in,
+    // including ones they have since left
+    for (chat_id, message_ids) in authored {
+        let cache = query!("SELECT message_history FROM chat_history_cache WHERE chat_id = ?", chat_id)
+            .fetch_optional(&mut *tx)
+            .await
+            .unwrap();
+        let Some(json_string) = cache.and_then(|c| c.message_history) else {
+            continue;
+        };
+        let mut messages: Vec<ChatHistoryMessage> = serde_json::from_str(&json_string).unwrap();
+        // Entries cached before ids were recorded can only be matched by name
+        let by_user = |m: &ChatHistoryMessage| message_ids.contains(&m.id) || (m.id == 0 && m.username == username);
+        for message in messages.iter_mut().filter(|m| by_user(m)) {
+            message.username = String::from(DELETED_USER_NAME);
+            message.display_name = None;
+        }
+        let json_history = serde_json::to_string(&messages).unwrap();
+        query!(
+            "UPDATE chat_history_cache SET message_history = ?, updated_at = datetime('now') WHERE chat_id = ?",
+            json_history, chat_id
+        ).execute(&mut *tx).await.unwrap();
+    }
+    query!("DELETE FROM chat_users WHERE user_id = ?", user_id).execute(&mut *tx).await.unwrap();
+    query!("DELETE FROM reactions WHERE user_id = ?", user_id).execute(&mut *tx).await.unwrap();
+    query!("DELETE FROM mentions WHERE user_id = ?", user_id).execute(&mut *tx).await.unwrap();
+    query!("DELETE FROM password_resets WHERE user_id = ?", user_id).execute(&mut *tx).await.unwrap();
+    query!("DELETE FROM recovery_codes WHERE user_id = ?", user_id).execute(&mut *tx).await.unwrap();
+    query!("DELETE FROM blocks WHERE blocker_id = ?1 OR blocked_id = ?1", user_id).execute(&mut *tx).await.unwrap();
+    // Bots stay (their messages are in history) but nobody can use them any more
+    query!(
+        r#"UPDATE api_tokens SET revoked_at = datetime('now')
+        WHERE revoked_at IS NULL AND user_id IN (SELECT id FROM users WHERE bot_owner_id = ?)"#,
+        user_id
+    ).execute(&mut *tx).await.unwrap();
+    // Messages keep pointing at the row, so it stays as an anonymous placeholder that cannot log in
+    let placeholder = format!("deleted-user-{}", user_id);
+    let avatar = query!("SELECT avatar_sha256 FROM users WHERE id = ?", user_id)
+        .fetch_one(&mut *tx)
+        .await
+        .unwrap()
+        .avatar_sha256;
+    query!(
+        r#"UPDATE users SET username = ?, password = '', role = 'deleted', totp_secret = NULL, totp_enabled = 0,
+        display_name = NULL, status_text = NULL, timezone = NULL, avatar_sha256 = NULL, avatar_content_type = NULL
+        WHERE id = ?"#,
+        placeholder, user_id
+    ).execute(&mut *tx).await.unwrap();
+    tx.commit().await.unwrap();
+    attachments::remove_unreferenced(&attachments, avatar.into_iter().collect(), &pool).await;
+    println!("Deleted account {}", username);
+    Json(Ok(String::from("1")))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_password_hash_round_trip() {
+        let hash = hash_password("hunter2");
+        assert!(password_matches("hunter2", &hash));
+        assert!(!password_matches("hunter3", &hash));
+    }
+
+    #[test]
+    fn test_invalid_hash_never_matches() {
+        assert!(!password_matches("", ""));
+    }
+
+    #[test]
+    fn test_reset_code() {
+        let code = generate_reset_code();
+        assert_eq!(code.len(), RESET_CODE_LENGTH);
+        assert!(code.chars().all(|c| c.is_ascii_alphanumeric()));
+        assert_ne!(code, generate_reset_code());
+    }
+}
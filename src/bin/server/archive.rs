@@ -0,0 +1,263 @@
+use axum::{extract::{ConnectInfo, Path, State}, response::Json};
+use serde::{Deserialize, Serialize};
+use sqlx::{query, SqlitePool};
+use std::net::SocketAddr;
+
+use crate::accounts::verify_credentials;
+use crate::attachments::{self, Attachments};
+use crate::audit::{self, AuditEvent};
+use crate::ratelimit::RateLimits;
+use crate::{chat_role, lookup_member, manages_chat, retention};
+
+/// Days a deleted chat can still be restored before the janitor purges it
+const DELETE_GRACE_DAYS: i64 = 30;
+
+#[derive(Deserialize)]
+pub(crate) struct PurgeChatRequest {
+    admin_password: String,
+}
+
+/// A deleted chat its owner can still restore
+#[derive(Serialize)]
+pub(crate) struct DeletedChat {
+    name: String,
+    deleted_at: String,
+    purge_after: String, // Restoring is no longer possible after this time (UTC)
+}
+
+/// Fails if new messages may not be added to the chat because it is archived or deleted
+pub(crate) async fn check_writable(chat_id: i64, pool: &SqlitePool) -> Result<(), String> {
+    let row = query!("SELECT archived_at IS NOT NULL AS \"archived!: bool\", deleted_at IS NOT NULL AS \"deleted!: bool\" FROM chats WHERE id = ?", chat_id)
+        .fetch_optional(pool)
+        .await
+        .unwrap();
+    match row {
+        None => Err(String::from("Chat not found")),
+        Some(row) if row.deleted => Err(String::from("This chat has been deleted")),
+        Some(row) if row.archived => Err(String::from("This chat is archived and read-only")),
+        Some(_) => Ok(()),
+    }
+}
+
+/// Archives a chat: it becomes read-only and is hidden from list_chats unless archived chats are asked for.
+/// Only the chat's owner and admins may do this
+/// # Query format:
+/// curl -X POST "http://98.93.98.244:80/archivechat/chatname/ChatName/username/UsernameString"
+pub(crate) async fn archive_chat(
+    State(pool): State<SqlitePool>,
+    Path((chatname, username)): Path<(String, String)>,
+) -> Json<Result<String, String>> {
+    set_archived(&chatname, &username, true, &pool).await
+}
+
+/// Makes an archived chat writable and listed again
+/// # Query format:
+/// curl -X POST "http://98.93.98.244:80/unarchivechat/chatname/ChatName/username/UsernameString"
+pub(crate) async fn unarchive_chat(
+    State(pool): State<SqlitePool>,
+    Path((chatname, username)): Path<(String, String)>,
+) -> Json<Result<String, String>> {
+    set_archived(&chatname, &username, false, &pool).await
+}
+
+async fn set_archived(chatname: &str, username: &str, archived: bool, pool: &SqlitePool) -> Json<Result<String, String>> {
+    let (chat_id, user_id) = match lookup_member(chatname, username, pool).await {
+        Ok(ids) => ids,
+        Err(e) => return Json(Err(e)),
+    };
+    if !manages_chat(chat_id, user_id, pool).await {
+        return Json(Err(String::from("Only chat owners and admins can archive a chat")));
+    }
+    let result = if archived {
+        query!("UPDATE chats SET archived_at = datetime('now') WHERE id = ? AND archived_at IS NULL", chat_id)
+            .execute(pool)
+            .await
+            .unwrap()
+    } else {
+        query!("UPDATE chats SET archived_at = NULL WHERE id = ? AND archived_at IS NOT NULL", chat_id)
+            .execute(pool)
+            .await
+            .unwrap()
+    };
+    if result.rows_affected() == 0 {
+        return Json(Ok(String::from("0")));
+    }
+    println!("{} {} {}", username, if archived { "archived" } else { "unarchived" }, chatname);
+    Json(Ok(String::from("1")))
+}
+
+/// Deletes a chat; only its owner may do this. The chat disappears for every member right away but can be
+/// restored for 30 days, after which it is purged for good
+/// # Query format:
+/// curl "http://98.93.98.244:80/deletechat/username/UsernameString/chatname/ChatName"
+pub(crate) async fn delete_chat(
+    State(pool): State<SqlitePool>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path((username, chatname)): Path<(String, String)>,
+) -> Json<Result<String, String>> {
+    let (chat_id, user_id) = match lookup_member(&chatname, &username, &pool).await {
+        Ok(ids) => ids,
+        Err(e) => return Json(Err(e)),
+    };
+    if chat_role(chat_id, user_id, &pool).await.as_deref() != Some("owner") {
+        return Json(Err(String::from("Only the chat's owner can delete it")));
+    }
+    query!(
+        "UPDATE chats SET deleted_at = datetime('now'), deleted_by = ? WHERE id = ?",
+        user_id, chat_id
+    ).execute(&pool).await.unwrap();
+    audit::record(AuditEvent::ChatDeleted, Some(user_id), None, Some(chat_id), Some(addr.ip()), None, &pool).await;
+    println!("{} deleted {}", username, chatname);
+    Json(Ok(String::from("1")))
+}
+
+/// Restores a chat the user owns that was deleted within the grace period
+/// # Query format:
+/// curl -X POST "http://98.93.98.244:80/restorechat/chatname/ChatName/username/UsernameString"
+pub(crate) async fn restore_chat(
+    State(pool): State<SqlitePool>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path((chatname, username)): Path<(String, String)>,
+) -> Json<Result<String, String>> {
+    let grace = format!("-{} days", DELETE_GRACE_DAYS);
+    let Some(chat) = query!(
+        r#"SELECT chats.id AS "id!", users.id AS "user_id!" FROM chats
+        JOIN chat_users ON chat_users.chat_id = chats.id
+        JOIN users ON users.id = chat_users.user_id
+        WHERE chats.name = ? AND users.username = ? AND chat_users.role = 'owner'
+        AND chats.deleted_at IS NOT NULL AND chats.deleted_at > datetime('now', ?)
+        ORDER BY chats.deleted_at DESC LIMIT 1"#,
+        chatname, username, grace
+    ).fetch_optional(&pool).await.unwrap() else {
+        return Json(Err(String::from("No restorable chat with that name")));
+    };
+    let name_taken = sqlx::query_scalar!(
+        "SELECT EXISTS(SELECT 1 FROM chats WHERE name = ? AND deleted_at IS NULL) AS _exists",
+        chatname
+    ).fetch_one(&pool).await.unwrap();
+    if name_taken == 1 {
+        return Json(Err(String::from("Another chat now uses this name")));
+    }
+    query!("UPDATE chats SET deleted_at = NULL, deleted_by = NULL WHERE id = ?", chat.id)
+        .execute(&pool)
+        .await
+        .unwrap();
+    audit::record(AuditEvent::ChatRestored, Some(chat.user_id), None, Some(chat.id), Some(addr.ip()), None, &pool).await;
+    println!("{} restored {}", username, chatname);
+    Json(Ok(String::from("1")))
+}
+
+/// Lists the deleted chats the user owns that can still be restored
+/// # Query format:
+/// curl "http://98.93.98.244:80/deletedchats/username/UsernameString"
+/// # Return format:
+/// Array of "name", "deleted_at" and "purge_after"
+pub(crate) async fn list_deleted(
+    State(pool): State<SqlitePool>,
+    Path(username): Path<String>,
+) -> Json<Result<Vec<DeletedChat>, String>> {
+    let grace = format!("+{} days", DELETE_GRACE_DAYS);
+    let chats = query!(
+        r#"SELECT chats.name AS "name!", chats.deleted_at AS "deleted_at!: String",
+        datetime(chats.deleted_at, ?) AS "purge_after!: String"
+        FROM chats
+        JOIN chat_users ON chat_users.chat_id = chats.id
+        JOIN users ON users.id = chat_users.user_id
+        WHERE users.username = ? AND chat_users.role = 'owner' AND chats.deleted_at IS NOT NULL
+        ORDER BY chats.deleted_at DESC"#,
+        grace, username
+    ).fetch_all(&pool).await.unwrap()
+    .into_iter()
+    .map(|row| DeletedChat { name: row.name, deleted_at: row.deleted_at, purge_after: row.purge_after })
+    .collect();
+    Json(Ok(chats))
+}
+
+/// Purges deleted chats with the given name immediately instead of waiting out the grace period;
+/// only admins may do this
+/// # Query format:
+/// curl -X POST -H "Content-Type: application/json" -d '{"admin_password": "AdminPass"}' "http://98.93.98.244:80/purgechat/chatname/ChatName/admin/AdminName"
+/// # Return format:
+/// Number of chats purged
+pub(crate) async fn purge_chat_route(
+    State(pool): State<SqlitePool>,
+    State(attachments): State<Attachments>,
+    State(limits): State<RateLimits>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path((chatname, admin)): Path<(String, String)>,
+    Json(request): Json<PurgeChatRequest>,
+) -> Json<Result<String, String>> {
+    let admin_id = match verify_credentials(&admin, &request.admin_password, &pool).await {
+        Some((admin_id, role)) if role == "admin" => admin_id,
+        Some(_) => return Json(Err(String::from("Only admins can purge chats"))),
+        None => {
+            limits.lockout.record_failure(&admin);
+            return Json(Err(String::from("Incorrect username or password")));
+        }
+    };
+    let chats = query!(
+        r#"SELECT id AS "id!" FROM chats WHERE name = ? AND deleted_at IS NOT NULL"#,
+        chatname
+    ).fetch_all(&pool).await.unwrap();
+    if chats.is_empty() {
+        return Json(Err(String::from("No deleted chat with that name; the owner must delete it first")));
+    }
+    for chat in &chats {
+        audit::record(AuditEvent::ChatPurged, Some(admin_id), None, Some(chat.id), Some(addr.ip()), None, &pool).await;
+        purge_chat(chat.id, &pool, &attachments).await;
+    }
+    println!("{} purged {} chat(s) named {}", admin, chats.len(), chatname);
+    Json(Ok(chats.len().to_string()))
+}
+
+/// Purges chats whose grace period has run out, returning how many; run by the retention janitor
+pub(crate) async fn purge_expired_chats(pool: &SqlitePool, attachments: &Attachments) -> usize {
+    let grace = format!("-{} days", DELETE_GRACE_DAYS);
+    let chats = query!(
+        r#"SELECT id AS "id!" FROM chats WHERE deleted_at IS NOT NULL AND deleted_at <= datetime('now', ?)"#,
+        grace
+    ).fetch_all(pool).await.unwrap();
+    for chat in &chats {
+        audit::record(AuditEvent::ChatPurged, None, None, Some(chat.id), None, Some("Grace period ended"), pool).await;
+        purge_chat(chat.id, pool, attachments).await;
+    }
+    chats.len()
+}
+
+/// Deletes a chat and everything belonging to it
+async fn purge_chat(chat_id: i64, pool: &SqlitePool, attachments: &Attachments) {
+    let message_ids: Vec<i64> = query!("SELECT id FROM messages WHERE chat_id = ?", chat_id)
+        .fetch_all(pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|row| row.id)
+        .collect();
+    retention::purge(chat_id, &message_ids, pool, attachments).await;
+    // Uploads never attached to a message are left over
+    let blobs: Vec<String> = query!("SELECT sha256 FROM attachments WHERE chat_id = ?", chat_id)
+        .fetch_all(pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|row| row.sha256)
+        .collect();
+    let mut tx = pool.begin().await.unwrap();
+    query!("DELETE FROM attachments WHERE chat_id = ?", chat_id).execute(&mut *tx).await.unwrap();
+    query!("DELETE FROM mentions WHERE chat_id = ?", chat_id).execute(&mut *tx).await.unwrap();
+    query!("DELETE FROM pins WHERE chat_id = ?", chat_id).execute(&mut *tx).await.unwrap();
+    query!(
+        "DELETE FROM webhook_deliveries WHERE webhook_id IN (SELECT id FROM webhooks WHERE chat_id = ?)",
+        chat_id
+    ).execute(&mut *tx).await.unwrap();
+    query!("DELETE FROM webhooks WHERE chat_id = ?", chat_id).execute(&mut *tx).await.unwrap();
+    query!("DELETE FROM incoming_webhooks WHERE chat_id = ?", chat_id).execute(&mut *tx).await.unwrap();
+    query!("DELETE FROM api_token_chats WHERE chat_id = ?", chat_id).execute(&mut *tx).await.unwrap();
+    query!("DELETE FROM reports WHERE chat_id = ?", chat_id).execute(&mut *tx).await.unwrap();
+    query!("DELETE FROM moderation_actions WHERE chat_id = ?", chat_id).execute(&mut *tx).await.unwrap();
+    query!("DELETE FROM chat_users WHERE chat_id = ?", chat_id).execute(&mut *tx).await.unwrap();
+    query!("DELETE FROM chat_history_cache WHERE chat_id = ?", chat_id).execute(&mut *tx).await.unwrap();
+    query!("DELETE FROM chats WHERE id = ?", chat_id).execute(&mut *tx).await.unwrap();
+    tx.commit().await.unwrap();
+    attachments::remove_unreferenced(attachments, blobs, pool).await;
+}
@@ -0,0 +1,435 @@
+use axum::{
+    extract::{Multipart, Path, State}, http::{header, StatusCode}, response::{IntoResponse, Json, Response},
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{query, SqlitePool};
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::{is_chat_member, lookup_member};
+
+const DEFAULT_ATTACHMENT_DIR: &str = "attachments";
+const DEFAULT_MAX_BYTES: usize = 10 * 1024 * 1024;
+const DEFAULT_ALLOWED_TYPES: [&str; 7] = [
+    "image/png", "image/jpeg", "image/gif", "image/webp", "application/pdf", "text/plain", "application/zip",
+];
+/// Uploads not attached to a message (or used as a chat avatar) within this long are removed, as an
+/// SQLite datetime modifier
+const PENDING_UPLOAD_LIFETIME: &str = "-1 day";
+
+/// Storage backend for attachment contents, addressed by the SHA-256 of the bytes.
+/// Calls are blocking and are made from `spawn_blocking`
+pub(crate) trait BlobStore: Send + Sync {
+    /// Stores the bytes under the key; storing an existing key is a no-op
+    fn put(&self, key: &str, bytes: &[u8]) -> io::Result<()>;
+    /// Reads the bytes stored under the key, None if there are none
+    fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>>;
+    /// Removes the bytes stored under the key; removing a missing key is a no-op
+    fn delete(&self, key: &str) -> io::Result<()>;
+}
+
+/// Keeps blobs as files under a root directory, fanned out by the first two hex digits of the key
+pub(crate) struct LocalDiskStore {
+    root: PathBuf,
+}
+
+impl LocalDiskStore {
+    pub(crate) fn new(root: impl Into<PathBuf>) -> Self {
+        LocalDiskStore { root: root.into() }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(&key[..2]).join(key)
+    }
+}
+
+impl BlobStore for LocalDiskStore {
+    fn put(&self, key: &str, bytes: &[u8]) -> io::Result<()> {
+        let path = self.path(key);
+        if path.exists() {
+            return Ok(());
+        }
+        let dir = path.parent().unwrap();
+        std::fs::create_dir_all(dir)?;
+        // Write to a temporary file first so a crash never leaves a truncated blob under a valid key
+        let mut file = tempfile::NamedTempFile::new_in(dir)?;
+        io::Write::write_all(&mut file, bytes)?;
+        file.persist(&path).map_err(|e| e.error)?;
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        match std::fs::read(self.path(key)) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn delete(&self, key: &str) -> io::Result<()> {
+        match std::fs::remove_file(self.path(key)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Attachment storage and upload limits, read from the environment at startup:
+/// ATTACHMENT_DIR, ATTACHMENT_MAX_BYTES and ATTACHMENT_TYPES (comma-separated MIME types)
+#[derive(Clone)]
+pub(crate) struct Attachments {
+    store: Arc<dyn BlobStore>,
+    max_bytes: usize,
+    allowed_types: Vec<String>,
+}
+
+impl Attachments {
+    pub(crate) fn new(store: Arc<dyn BlobStore>, max_bytes: usize, allowed_types: Vec<String>) -> Self {
+        Attachments { store, max_bytes, allowed_types }
+    }
+
+    pub(crate) fn from_env() -> Self {
+        let dir = std::env::var("ATTACHMENT_DIR").unwrap_or_else(|_| String::from(DEFAULT_ATTACHMENT_DIR));
+        let max_bytes = std::env::var("ATTACHMENT_MAX_BYTES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_MAX_BYTES);
+        let allowed_types = match std::env::var("ATTACHMENT_TYPES") {
+            Ok(types) => types.split(',').map(|t| t.trim().to_string()).filter(|t| !t.is_empty()).collect(),
+            Err(_) => DEFAULT_ALLOWED_TYPES.iter().map(|t| t.to_string()).collect(),
+        };
+        Attachments::new(Arc::new(LocalDiskStore::new(dir)), max_bytes, allowed_types)
+    }
+
+    /// Largest single file accepted, in bytes
+    pub(crate) fn max_bytes(&self) -> usize {
+        self.max_bytes
+    }
+
+    /// Checks an upload against the size and type limits
+    fn check(&self, content_type: &str, size: usize) -> Result<(), String> {
+        if size == 0 {
+            return Err(String::from("Attachment is empty"));
+        }
+        if size > self.max_bytes {
+            return Err(format!("Attachment exceeds the {} byte limit", self.max_bytes));
+        }
+        if !self.allowed_types.iter().any(|t| t == content_type) {
+            return Err(format!("Attachment type {} is not allowed", content_type));
+        }
+        Ok(())
+    }
+}
+
+/// Checks that an image starts with the signature of its claimed type, so one kind of file cannot be passed
+/// off as another. Other types, including images this server does not know the signature of, are not checked
+fn check_signature(content_type: &str, bytes: &[u8]) -> Result<(), String> {
+    let matches = match content_type {
+        "image/png" => bytes.starts_with(b"\x89PNG\r\n\x1a\n"),
+        "image/jpeg" => bytes.starts_with(&[0xff, 0xd8, 0xff]),
+        "image/gif" => bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a"),
+        "image/webp" => bytes.starts_with(b"RIFF") && bytes.get(8..12) == Some(b"WEBP".as_slice()),
+        _ => true,
+    };
+    if !matches {
+        return Err(format!("Attachment is not a valid {} file", content_type));
+    }
+    Ok(())
+}
+
+/// Attachment metadata included in history responses
+#[derive(Deserialize, Serialize, Clone)]
+pub(crate) struct AttachmentInfo {
+    pub(crate) id: i64,
+    filename: String,
+    content_type: String,
+    size: i64,
+    sha256: String,
+}
+
+/// Hex-encoded SHA-256 of the contents, used as the blob store key
+fn content_address(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+/// Uploads one or more files into a chat; the returned ids are then listed in a message's "attachments"
+/// # Query format:
+/// curl -F "file=@picture.png" "http://98.93.98.244:80/upload/chatname/ChatName/username/UsernameString"
+/// # Return format:
+/// Array of attachment metadata containing "id", "filename", "content_type", "size" and "sha256"
+pub(crate) async fn upload(
+    State(pool): State<SqlitePool>,
+    State(attachments): State<Attachments>,
+    Path((chatname, username)): Path<(String, String)>,
+    mut multipart: Multipart,
+) -> Json<Result<Vec<AttachmentInfo>, String>> {
+    let (chat_id, user_id) = match lookup_member(&chatname, &username, &pool).await {
+        Ok(ids) => ids,
+        Err(e) => return Json(Err(e)),
+    };
+    let mut uploaded: Vec<AttachmentInfo> = Vec::new();
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => return Json(Err(e.body_text())),
+        };
+        let Some(filename) = field.file_name().map(|f| f.to_string()) else {
+            continue; // Only file parts are attachments
+        };
+        let content_type = field.content_type().unwrap_or("application/octet-stream").to_string();
+        let bytes = match field.bytes().await {
+            Ok(bytes) => bytes.to_vec(),
+            Err(e) => return Json(Err(e.body_text())),
+        };
+        match store_upload(&attachments, chat_id, user_id, filename, content_type, bytes, &pool).await {
+            Ok(info) => {
+                println!("{} uploaded {} ({} bytes) to {}", username, info.filename, info.size, chatname);
+                uploaded.push(info);
+            }
+            Err(e) => return Json(Err(e)),
+        }
+    }
+    Json(Ok(uploaded))
+}
+
+/// Checks and stores one uploaded file, recording it as pending until a message links it
+pub(crate) async fn store_upload(
+    attachments: &Attachments,
+    chat_id: i64,
+    user_id: i64,
+    filename: String,
+    content_type: String,
+    bytes: Vec<u8>,
+    pool: &SqlitePool,
+) -> Result<AttachmentInfo, String> {
+    attachments.check(&content_type, bytes.len())?;
+    check_signature(&content_type, &bytes)?;
+    let size = bytes.len() as i64;
+    let sha256 = put_blob(attachments, bytes).await?;
+    let id = query!(
+        r#"INSERT INTO attachments (sha256, filename, content_type, size, chat_id, uploaded_by, message_id, created_at)
+        VALUES (?, ?, ?, ?, ?, ?, NULL, datetime('now'))"#,
+        sha256, filename, content_type, size, chat_id, user_id
+    ).execute(pool).await.unwrap().last_insert_rowid();
+    Ok(AttachmentInfo { id, filename, content_type, size, sha256 })
+}
+
+/// Checks and stores an image that belongs to no chat, such as a profile picture, returning its blob key
+pub(crate) async fn store_image(attachments: &Attachments, content_type: &str, bytes: Vec<u8>) -> Result<String, String> {
+    if !content_type.starts_with("image/") {
+        return Err(String::from("Only images can be used here"));
+    }
+    attachments.check(content_type, bytes.len())?;
+    check_signature(content_type, &bytes)?;
+    put_blob(attachments, bytes).await
+}
+
+/// Writes bytes to the blob store, returning their key
+async fn put_blob(attachments: &Attachments, bytes: Vec<u8>) -> Result<String, String> {
+    let sha256 = content_address(&bytes);
+    let store = attachments.store.clone();
+    let key = sha256.clone();
+    if let Err(e) = tokio::task::spawn_blocking(move || store.put(&key, &bytes)).await.unwrap() {
+        println!("Failed to store attachment {}: {}", sha256, e);
+        return Err(String::from("Could not store attachment"));
+    }
+    Ok(sha256)
+}
+
+/// Responds with a stored blob, or 404 if it is missing
+pub(crate) async fn blob_response(attachments: &Attachments, key: String, content_type: String, filename: Option<&str>) -> Response {
+    let store = attachments.store.clone();
+    let blob = key.clone();
+    match tokio::task::spawn_blocking(move || store.get(&blob)).await.unwrap() {
+        Ok(Some(bytes)) => match filename {
+            Some(filename) => {
+                let disposition = format!("attachment; filename=\"{}\"", filename.replace('"', ""));
+                ([(header::CONTENT_TYPE, content_type), (header::CONTENT_DISPOSITION, disposition)], bytes)
+                    .into_response()
+            }
+            None => ([(header::CONTENT_TYPE, content_type)], bytes).into_response(),
+        },
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            println!("Failed to read attachment {}: {}", key, e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Deletes blobs that neither an attachment nor a profile picture refers to any more, e.g. after their
+/// messages were purged
+pub(crate) async fn remove_unreferenced(attachments: &Attachments, keys: Vec<String>, pool: &SqlitePool) {
+    for key in keys {
+        let referenced = sqlx::query_scalar!(
+            r#"SELECT EXISTS(SELECT 1 FROM attachments WHERE sha256 = ?1)
+            OR EXISTS(SELECT 1 FROM users WHERE avatar_sha256 = ?1) AS "referenced!: bool""#, key
+        ).fetch_one(pool).await.unwrap();
+        if referenced {
+            continue;
+        }
+        let store = attachments.store.clone();
+        let blob = key.clone();
+        if let Err(e) = tokio::task::spawn_blocking(move || store.delete(&blob)).await.unwrap() {
+            println!("Failed to delete attachment {}: {}", key, e);
+        }
+    }
+}
+
+/// Removes uploads that were never attached to a message, and their blobs, returning how many went.
+/// Images in use as chat avatars are kept
+pub(crate) async fn sweep_pending(attachments: &Attachments, pool: &SqlitePool) -> usize {
+    let keys: Vec<String> = query!(
+        r#"DELETE FROM attachments WHERE message_id IS NULL AND created_at <= datetime('now', ?)
+        AND id NOT IN (SELECT avatar_attachment_id FROM chats WHERE avatar_attachment_id IS NOT NULL)
+        RETURNING sha256"#,
+        PENDING_UPLOAD_LIFETIME
+    ).fetch_all(pool).await.unwrap()
+    .into_iter()
+    .map(|row| row.sha256)
+    .collect();
+    let removed = keys.len();
+    remove_unreferenced(attachments, keys, pool).await;
+    removed
+}
+
+/// Downloads an attachment; only members of the chat it was uploaded to may fetch it
+/// # Query format:
+/// curl -O "http://98.93.98.244:80/attachment/attachmentid/7/username/UsernameString"
+pub(crate) async fn download(
+    State(pool): State<SqlitePool>,
+    State(attachments): State<Attachments>,
+    Path((attachment_id, username)): Path<(i64, String)>,
+) -> Response {
+    let Some(attachment) = query!(
+        "SELECT sha256, filename, content_type, chat_id FROM attachments WHERE id = ?",
+        attachment_id
+    ).fetch_optional(&pool).await.unwrap() else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let Some(user) = query!(r#"SELECT id AS "id!" FROM users WHERE username = ?"#, username)
+        .fetch_optional(&pool)
+        .await
+        .unwrap() else {
+        return StatusCode::FORBIDDEN.into_response();
+    };
+    if !is_chat_member(attachment.chat_id, user.id, &pool).await {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    blob_response(&attachments, attachment.sha256, attachment.content_type, Some(&attachment.filename)).await
+}
+
+/// Checks that every id is an upload by this user to this chat that is not yet attached to a message
+pub(crate) async fn check_pending(ids: &[i64], chat_id: i64, user_id: i64, pool: &SqlitePool) -> Result<(), String> {
+    for id in ids {
+        let pending = sqlx::query_scalar!(
+            r#"SELECT EXISTS(SELECT 1 FROM attachments
+            WHERE id = ? AND chat_id = ? AND uploaded_by = ? AND message_id IS NULL) AS _exists"#,
+            id, chat_id, user_id
+        ).fetch_one(pool).await.unwrap();
+        if pending == 0 {
+            return Err(format!("Attachment {} is not available", id));
+        }
+    }
+    Ok(())
+}
+
+/// Attaches pending uploads to a newly queued message
+pub(crate) async fn link(ids: &[i64], message_id: i64, pool: &SqlitePool) {
+    for id in ids {
+        query!("UPDATE attachments SET message_id = ? WHERE id = ? AND message_id IS NULL", message_id, id)
+            .execute(pool)
+            .await
+            .unwrap();
+    }
+}
+
+/// Metadata of an image uploaded to the chat, used for chat avatars
+pub(crate) async fn chat_image(id: i64, chat_id: i64, pool: &SqlitePool) -> Option<AttachmentInfo> {
+    query!(
+        r#"SELECT id AS "id!", filename, content_type, size, sha256 FROM attachments
+        WHERE id = ? AND chat_id = ? AND content_type LIKE 'image/%'"#,
+        id, chat_id
+    ).fetch_optional(pool).await.unwrap()
+    .map(|row| AttachmentInfo {
+        id: row.id,
+        filename: row.filename,
+        content_type: row.content_type,
+        size: row.size,
+        sha256: row.sha256,
+    })
+}
+
+/// Metadata of the attachments on a message, in upload order
+pub(crate) async fn message_attachments(message_id: i64, pool: &SqlitePool) -> Vec<AttachmentInfo> {
+    query!(
+        r#"SELECT id AS "id!", filename, content_type, size, sha256 FROM attachments WHERE message_id = ? ORDER BY id"#,
+        message_id
+    ).fetch_all(pool).await.unwrap()
+    .into_iter()
+    .map(|row| AttachmentInfo {
+        id: row.id,
+        filename: row.filename,
+        content_type: row.content_type,
+        size: row.size,
+        sha256: row.sha256,
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn limits() -> Attachments {
+        let dir = tempfile::tempdir().unwrap();
+        Attachments::new(Arc::new(LocalDiskStore::new(dir.path())), 8, vec![String::from("text/plain")])
+    }
+
+    #[test]
+    fn test_content_address() {
+        assert_eq!(
+            content_address(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn test_check_limits() {
+        let attachments = limits();
+        assert!(attachments.check("text/plain", 8).is_ok());
+        assert!(attachments.check("text/plain", 0).is_err());
+        assert!(attachments.check("text/plain", 9).is_err());
+        assert!(attachments.check("image/png", 4).is_err());
+    }
+
+    #[test]
+    fn test_check_signature() {
+        assert!(check_signature("image/png", b"\x89PNG\r\n\x1a\n....").is_ok());
+        assert!(check_signature("image/jpeg", &[0xff, 0xd8, 0xff, 0xe0]).is_ok());
+        assert!(check_signature("image/gif", b"GIF89a..").is_ok());
+        assert!(check_signature("image/webp", b"RIFF\x10\0\0\0WEBPVP8 ").is_ok());
+        assert!(check_signature("image/png", b"<html><script>").is_err());
+        assert!(check_signature("image/jpeg", b"\x89PNG\r\n\x1a\n").is_err());
+        assert!(check_signature("image/webp", b"RIFF\x10\0\0\0WAVE").is_err());
+        assert!(check_signature("text/plain", b"anything").is_ok());
+    }
+
+    #[test]
+    fn test_local_disk_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LocalDiskStore::new(dir.path());
+        let key = content_address(b"hello");
+        assert!(store.get(&key).unwrap().is_none());
+        store.put(&key, b"hello").unwrap();
+        store.put(&key, b"hello").unwrap(); // Deduplicated, second put is a no-op
+        assert_eq!(store.get(&key).unwrap(), Some(b"hello".to_vec()));
+        assert!(dir.path().join(&key[..2]).join(&key).exists());
+        store.delete(&key).unwrap();
+        store.delete(&key).unwrap(); // Already gone, still fine
+        assert!(store.get(&key).unwrap().is_none());
+    }
+}
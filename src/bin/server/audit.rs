@@ -0,0 +1,292 @@
+use axum::{
+    extract::{Path, State}, http::header, response::{IntoResponse, Json, Response},
+};
+use chrono::{NaiveDate, NaiveDateTime};
+use serde::{Deserialize, Serialize};
+use sqlx::{query, SqlitePool};
+use std::net::IpAddr;
+
+use crate::accounts::verify_credentials;
+use crate::ratelimit::RateLimits;
+
+const DEFAULT_LIMIT: i64 = 1000;
+const MAX_LIMIT: i64 = 10_000;
+
+/// Security-relevant events kept in the audit log
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum AuditEvent {
+    LoginSucceeded,
+    LoginFailed,
+    AccountCreated, // Including bot accounts
+    AccountDeleted,
+    AccountBanned,  // From the whole server
+    AccountUnbanned,
+    PasswordChanged,
+    PasswordResetIssued,
+    PasswordReset,  // With a reset code
+    InviteCreated,
+    ChatCreated,
+    ChatDeleted,
+    ChatRestored,
+    ChatPurged,
+    MemberAdded,   // Put in a chat by someone else
+    MemberJoined,  // Joined a public channel themselves
+    MemberLeft,
+    MemberRemoved, // Kicked or banned
+    RoleChanged,
+    TotpEnabled,
+    TotpDisabled,
+    TokenCreated,
+    TokenRevoked,
+    KeyRegistered,  // Two-factor secret, before it is confirmed
+    WebhookCreated, // Outgoing or incoming
+    WebhookDeleted,
+}
+
+impl AuditEvent {
+    fn as_str(self) -> &'static str {
+        match self {
+            AuditEvent::LoginSucceeded => "login_succeeded",
+            AuditEvent::LoginFailed => "login_failed",
+            AuditEvent::AccountCreated => "account_created",
+            AuditEvent::AccountDeleted => "account_deleted",
+            AuditEvent::AccountBanned => "account_banned",
+            AuditEvent::AccountUnbanned => "account_unbanned",
+            AuditEvent::PasswordChanged => "password_changed",
+            AuditEvent::PasswordResetIssued => "password_reset_issued",
+            AuditEvent::PasswordReset => "password_reset",
+            AuditEvent::InviteCreated => "invite_created",
+            AuditEvent::ChatCreated => "chat_created",
+            AuditEvent::ChatDeleted => "chat_deleted",
+            AuditEvent::ChatRestored => "chat_restored",
+            AuditEvent::ChatPurged => "chat_purged",
+            AuditEvent::MemberAdded => "member_added",
+            AuditEvent::MemberJoined => "member_joined",
+            AuditEvent::MemberLeft => "member_left",
+            AuditEvent::MemberRemoved => "member_removed",
+            AuditEvent::RoleChanged => "role_changed",
+            AuditEvent::TotpEnabled => "totp_enabled",
+            AuditEvent::TotpDisabled => "totp_disabled",
+            AuditEvent::TokenCreated => "token_created",
+            AuditEvent::TokenRevoked => "token_revoked",
+            AuditEvent::KeyRegistered => "key_registered",
+            AuditEvent::WebhookCreated => "webhook_created",
+            AuditEvent::WebhookDeleted => "webhook_deleted",
+        }
+    }
+}
+
+/// How the audit log is exported
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ExportFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+/// Filters for an audit log query; missing filters match everything
+#[derive(Deserialize)]
+pub(crate) struct AuditQuery {
+    admin_password: String,
+    #[serde(default)]
+    event: Option<AuditEvent>,
+    #[serde(default)]
+    actor: Option<String>,
+    #[serde(default)]
+    target: Option<String>,
+    #[serde(default)]
+    chatname: Option<String>,
+    #[serde(default)]
+    ip: Option<String>,
+    #[serde(default)]
+    since: Option<String>, // UTC, inclusive: "2025-06-01" or "2025-06-01 09:00"
+    #[serde(default)]
+    until: Option<String>, // UTC, exclusive
+    #[serde(default)]
+    limit: Option<i64>,
+    #[serde(default)]
+    format: ExportFormat,
+}
+
+/// An audit log entry; names are as they were when the event happened
+#[derive(Serialize)]
+pub(crate) struct AuditEntry {
+    id: i64,
+    event: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    actor: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chatname: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ip: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+    created_at: String,
+}
+
+/// Appends an event to the audit log. The ids are resolved to the current user and chat names, which are
+/// kept even if the account or chat is later renamed or purged
+pub(crate) async fn record(
+    event: AuditEvent, actor_id: Option<i64>, target_id: Option<i64>, chat_id: Option<i64>,
+    ip: Option<IpAddr>, detail: Option<&str>, pool: &SqlitePool,
+) {
+    let event = event.as_str();
+    let ip = ip.map(|ip| ip.to_string());
+    query!(
+        r#"INSERT INTO audit_log (event, actor_id, actor, target_id, target, chat_id, chatname, ip, detail, created_at)
+        VALUES (?1, ?2, (SELECT username FROM users WHERE id = ?2), ?3, (SELECT username FROM users WHERE id = ?3),
+        ?4, (SELECT name FROM chats WHERE id = ?4), ?5, ?6, datetime('now'))"#,
+        event, actor_id, target_id, chat_id, ip, detail
+    ).execute(pool).await.unwrap();
+}
+
+/// Normalizes a UTC date, or date and time, to the format stored in the database
+fn parse_time(text: &str) -> Result<String, String> {
+    let text = text.trim();
+    ["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(text, format).ok())
+        .or_else(|| NaiveDate::parse_from_str(text, "%Y-%m-%d").ok().and_then(|date| date.and_hms_opt(0, 0, 0)))
+        .map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string())
+        .ok_or_else(|| format!("Invalid time {}, use e.g. 2025-06-01 or 2025-06-01 09:00 (UTC)", text))
+}
+
+/// Quotes a CSV field when needed. Fields that a spreadsheet would run as a formula get a leading
+/// apostrophe, since usernames, chat names and details come from users
+fn csv_field(text: &str) -> String {
+    let text = if text.starts_with(['=', '+', '-', '@']) { format!("'{}", text) } else { text.to_string() };
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text
+    }
+}
+
+fn to_csv(entries: &[AuditEntry]) -> String {
+    let mut csv = String::from("id,event,actor,target,chatname,ip,detail,created_at\n");
+    for entry in entries {
+        let fields = [
+            entry.id.to_string(),
+            entry.event.clone(),
+            entry.actor.clone().unwrap_or_default(),
+            entry.target.clone().unwrap_or_default(),
+            entry.chatname.clone().unwrap_or_default(),
+            entry.ip.clone().unwrap_or_default(),
+            entry.detail.clone().unwrap_or_default(),
+            entry.created_at.clone(),
+        ];
+        let line: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+        csv.push_str(&line.join(","));
+        csv.push('\n');
+    }
+    csv
+}
+
+/// Searches the audit log, newest first; only admins may do this. Filters match event, actor, target,
+/// chat name and IP exactly, and a UTC time range. Results come back as JSON or, with "format": "csv",
+/// as a CSV download
+/// # Query format:
+/// curl -X POST -H "Content-Type: application/json" -d '{"admin_password": "AdminPass", "event": "login_failed", "since": "2025-06-01", "format": "csv"}' "http://98.93.98.244:80/auditlog/admin/AdminName"
+/// # Return format:
+/// Array of "id", "event", "actor", "target", "chatname", "ip", "detail" and "created_at"; at most 1000
+/// entries unless "limit" (up to 10000) says otherwise
+pub(crate) async fn query_log(
+    State(pool): State<SqlitePool>,
+    State(limits): State<RateLimits>,
+    Path(admin): Path<String>,
+    Json(request): Json<AuditQuery>,
+) -> Response {
+    match verify_credentials(&admin, &request.admin_password, &pool).await {
+        Some((_, role)) if role == "admin" => {}
+        Some(_) => return Json(Err::<(), _>(String::from("Only admins can read the audit log"))).into_response(),
+        None => {
+            limits.lockout.record_failure(&admin);
+            return Json(Err::<(), _>(String::from("Incorrect username or password"))).into_response();
+        }
+    }
+    let (since, until) = match (
+        request.since.as_deref().map(parse_time).transpose(),
+        request.until.as_deref().map(parse_time).transpose(),
+    ) {
+        (Ok(since), Ok(until)) => (since, until),
+        (Err(e), _) | (_, Err(e)) => return Json(Err::<(), _>(e)).into_response(),
+    };
+    let limit = request.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let event = request.event.map(AuditEvent::as_str);
+    let entries: Vec<AuditEntry> = query!(
+        r#"SELECT id AS "id!", event, actor, target, chatname, ip, detail, created_at AS "created_at!: String"
+        FROM audit_log
+        WHERE (?1 IS NULL OR event = ?1) AND (?2 IS NULL OR actor = ?2) AND (?3 IS NULL OR target = ?3)
+        AND (?4 IS NULL OR chatname = ?4) AND (?5 IS NULL OR ip = ?5)
+        AND (?6 IS NULL OR created_at >= ?6) AND (?7 IS NULL OR created_at < ?7)
+        ORDER BY id DESC LIMIT ?8"#,
+        event, request.actor, request.target, request.chatname, request.ip, since, until, limit
+    ).fetch_all(&pool).await.unwrap()
+    .into_iter()
+    .map(|row| AuditEntry {
+        id: row.id,
+        event: row.event,
+        actor: row.actor,
+        target: row.target,
+        chatname: row.chatname,
+        ip: row.ip,
+        detail: row.detail,
+        created_at: row.created_at,
+    })
+    .collect();
+    println!("{} read {} audit log entries", admin, entries.len());
+    match request.format {
+        ExportFormat::Json => Json(Ok::<_, String>(entries)).into_response(),
+        ExportFormat::Csv => (
+            [
+                (header::CONTENT_TYPE, "text/csv; charset=utf-8"),
+                (header::CONTENT_DISPOSITION, "attachment; filename=\"audit_log.csv\""),
+            ],
+            to_csv(&entries),
+        ).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(parse_time("2025-06-01"), Ok(String::from("2025-06-01 00:00:00")));
+        assert_eq!(parse_time(" 2025-06-01 09:30 "), Ok(String::from("2025-06-01 09:30:00")));
+        assert_eq!(parse_time("2025-06-01 09:30:15"), Ok(String::from("2025-06-01 09:30:15")));
+        assert!(parse_time("yesterday").is_err());
+        assert!(parse_time("2025-13-01").is_err());
+    }
+
+    #[test]
+    fn test_csv_fields() {
+        assert_eq!(csv_field("alice"), "alice");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("=HYPERLINK(1)"), "'=HYPERLINK(1)");
+        let csv = to_csv(&[AuditEntry {
+            id: 1,
+            event: String::from("login_failed"),
+            actor: None,
+            target: Some(String::from("bob")),
+            chatname: None,
+            ip: Some(String::from("127.0.0.1")),
+            detail: Some(String::from("Wrong password")),
+            created_at: String::from("2025-06-01 09:00:00"),
+        }]);
+        assert_eq!(csv.lines().nth(1), Some("1,login_failed,,bob,,127.0.0.1,Wrong password,2025-06-01 09:00:00"));
+    }
+
+    #[test]
+    fn test_event_names() {
+        assert_eq!(serde_json::from_str::<AuditEvent>(r#""member_removed""#).unwrap(), AuditEvent::MemberRemoved);
+        assert_eq!(AuditEvent::TotpEnabled.as_str(), "totp_enabled");
+        assert_eq!(serde_json::to_string(&AuditEvent::PasswordResetIssued).unwrap(), r#""password_reset_issued""#);
+    }
+}
@@ -0,0 +1,125 @@
+use axum::{extract::{Path, State}, response::Json};
+use serde::Serialize;
+use sqlx::{query, SqlitePool};
+use std::collections::HashSet;
+
+/// A user on someone's block list
+#[derive(Serialize)]
+pub(crate) struct BlockedUser {
+    username: String,
+    blocked_at: String,
+}
+
+async fn user_id(username: &str, pool: &SqlitePool) -> Option<i64> {
+    query!(r#"SELECT id AS "id!" FROM users WHERE username = ?"#, username)
+        .fetch_optional(pool)
+        .await
+        .unwrap()
+        .map(|row| row.id)
+}
+
+/// Whether the first user has blocked the second
+pub(crate) async fn has_blocked(blocker_id: i64, blocked_id: i64, pool: &SqlitePool) -> bool {
+    let exists = sqlx::query_scalar!(
+        "SELECT EXISTS(SELECT 1 FROM blocks WHERE blocker_id = ? AND blocked_id = ?) AS _exists",
+        blocker_id, blocked_id
+    ).fetch_one(pool).await.unwrap();
+    exists == 1
+}
+
+/// Usernames the user has blocked
+pub(crate) async fn blocked_usernames(username: &str, pool: &SqlitePool) -> HashSet<String> {
+    query!(
+        r#"SELECT blocked.username FROM blocks
+        JOIN users AS blocker ON blocker.id = blocks.blocker_id
+        JOIN users AS blocked ON blocked.id = blocks.blocked_id
+        WHERE blocker.username = ?"#,
+        username
+    ).fetch_all(pool).await.unwrap()
+    .into_iter()
+    .map(|row| row.username)
+    .collect()
+}
+
+/// Fails if a direct chat between the two users may not be created because either blocked the other
+pub(crate) async fn check_direct_chat(creator_id: i64, other_id: i64, other: &str, pool: &SqlitePool) -> Result<(), String> {
+    if has_blocked(other_id, creator_id, pool).await {
+        return Err(format!("{} is not accepting messages from you", other));
+    }
+    if has_blocked(creator_id, other_id, pool).await {
+        return Err(format!("You have blocked {}, unblock them first", other));
+    }
+    Ok(())
+}
+
+/// Blocks another user: they cannot start direct chats with the user, their messages are hidden from
+/// the user's history and live events, and their mentions no longer reach the user.
+/// Blocking someone already blocked is a no-op
+/// # Query format:
+/// curl -X POST "http://98.93.98.244:80/block/username/UsernameString/target/OtherUser"
+pub(crate) async fn block_user(
+    State(pool): State<SqlitePool>,
+    Path((username, target)): Path<(String, String)>,
+) -> Json<Result<String, String>> {
+    let Some(blocker_id) = user_id(&username, &pool).await else {
+        return Json(Err(String::from("User not found")));
+    };
+    let Some(blocked_id) = user_id(&target, &pool).await else {
+        return Json(Err(String::from("User not found")));
+    };
+    if blocker_id == blocked_id {
+        return Json(Err(String::from("You cannot block yourself")));
+    }
+    let result = query!(
+        "INSERT OR IGNORE INTO blocks (blocker_id, blocked_id, created_at) VALUES (?, ?, datetime('now'))",
+        blocker_id, blocked_id
+    ).execute(&pool).await.unwrap();
+    if result.rows_affected() == 0 {
+        return Json(Ok(String::from("0")));
+    }
+    println!("{} blocked {}", username, target);
+    Json(Ok(String::from("1")))
+}
+
+/// Unblocks a user
+/// # Query format:
+/// curl -X DELETE "http://98.93.98.244:80/block/username/UsernameString/target/OtherUser"
+pub(crate) async fn unblock_user(
+    State(pool): State<SqlitePool>,
+    Path((username, target)): Path<(String, String)>,
+) -> Json<Result<String, String>> {
+    let result = query!(
+        r#"DELETE FROM blocks
+        WHERE blocker_id = (SELECT id FROM users WHERE username = ?)
+        AND blocked_id = (SELECT id FROM users WHERE username = ?)"#,
+        username, target
+    ).execute(&pool).await.unwrap();
+    if result.rows_affected() == 0 {
+        return Json(Ok(String::from("0")));
+    }
+    println!("{} unblocked {}", username, target);
+    Json(Ok(String::from("1")))
+}
+
+/// Lists the users the user has blocked, most recent first
+/// # Query format:
+/// curl "http://98.93.98.244:80/blocks/username/UsernameString"
+/// # Return format:
+/// Array of "username" and "blocked_at"
+pub(crate) async fn list_blocks(
+    State(pool): State<SqlitePool>,
+    Path(username): Path<String>,
+) -> Json<Result<Vec<BlockedUser>, String>> {
+    let blocks = query!(
+        r#"SELECT blocked.username, blocks.created_at AS "blocked_at!: String" FROM blocks
+        JOIN users AS blocker ON blocker.id = blocks.blocker_id
+        JOIN users AS blocked ON blocked.id = blocks.blocked_id
+        WHERE blocker.username = ?
+        ORDER BY blocks.id DESC"#,
+        username
+    ).fetch_all(&pool).await.unwrap()
+    .into_iter()
+    .map(|row| BlockedUser { username: row.username, blocked_at: row.blocked_at })
+    .collect();
+    Json(Ok(blocks))
+}
@@ -0,0 +1,429 @@
+use axum::{
+    extract::{ConnectInfo, Path, State}, http::{header, HeaderMap, StatusCode}, response::Json,
+};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{query, SqlitePool};
+use std::net::SocketAddr;
+
+use crate::accounts::verify_credentials;
+use crate::audit::{self, AuditEvent};
+use crate::ratelimit::RateLimits;
+use crate::registration::{is_username_conflict, RegistrationPolicy, USERNAME_TAKEN};
+use crate::{enqueue_message, is_chat_member, load_history, ChatHistoryMessage, Message};
+
+/// Every token starts with this so leaked tokens are easy to recognise
+const TOKEN_PREFIX: &str = "bot_";
+const TOKEN_LENGTH: usize = 40;
+
+/// What a token lets its bot do
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum TokenScope {
+    Read,
+    Post,
+    ReadPost,
+}
+
+impl TokenScope {
+    fn as_str(self) -> &'static str {
+        match self {
+            TokenScope::Read => "read",
+            TokenScope::Post => "post",
+            TokenScope::ReadPost => "read_post",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "read" => Some(TokenScope::Read),
+            "post" => Some(TokenScope::Post),
+            "read_post" => Some(TokenScope::ReadPost),
+            _ => None,
+        }
+    }
+
+    fn allows(self, needed: TokenScope) -> bool {
+        self == needed || self == TokenScope::ReadPost
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct CreateBotRequest {
+    password: String,
+    botname: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct CreateTokenRequest {
+    password: String,
+    botname: String,
+    #[serde(default)]
+    label: Option<String>,
+    scope: TokenScope,
+    #[serde(default)]
+    chats: Vec<String>, // Chat names the token is limited to, empty for every chat the bot is in
+}
+
+#[derive(Deserialize)]
+pub(crate) struct RevokeTokenRequest {
+    password: String,
+    token_id: i64,
+}
+
+#[derive(Serialize)]
+pub(crate) struct NewToken {
+    id: i64,
+    token: String,
+}
+
+/// Token details for its owner; the token itself is never shown again after creation
+#[derive(Serialize)]
+pub(crate) struct TokenInfo {
+    id: i64,
+    botname: String,
+    label: Option<String>,
+    scope: TokenScope,
+    scoped: bool, // Limited to "chats"; a scoped token whose chats were all purged works nowhere
+    chats: Vec<String>,
+    created_at: String,
+    last_used_at: Option<String>,
+    revoked: bool,
+}
+
+/// The bot and permissions behind a valid token
+pub(crate) struct TokenGrant {
+    token_id: i64,
+    user_id: i64,
+    botname: String,
+    scope: TokenScope,
+    chats: Option<Vec<i64>>, // None for every chat the bot is in
+}
+
+impl TokenGrant {
+    fn allows_chat(&self, chat_id: i64) -> bool {
+        self.chats.as_ref().is_none_or(|chats| chats.contains(&chat_id))
+    }
+
+    /// Checks the scope and chat limits; the bot must also still be a member of the chat
+    async fn authorize(&self, chat_id: i64, needed: TokenScope, pool: &SqlitePool) -> Result<(), (StatusCode, String)> {
+        if !self.scope.allows(needed) {
+            return Err((StatusCode::FORBIDDEN, format!("Token is not allowed to {}", needed.as_str())));
+        }
+        if !self.allows_chat(chat_id) {
+            return Err((StatusCode::FORBIDDEN, String::from("Token is not allowed in this chat")));
+        }
+        if !is_chat_member(chat_id, self.user_id, pool).await {
+            return Err((StatusCode::FORBIDDEN, String::from("Bot is not a member of this chat")));
+        }
+        Ok(())
+    }
+}
+
+fn generate_token() -> String {
+    let random: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(TOKEN_LENGTH)
+        .map(char::from)
+        .collect();
+    format!("{}{}", TOKEN_PREFIX, random)
+}
+
+/// Tokens are long and random, so a plain SHA-256 is enough and lets them be looked up directly
+pub(crate) fn token_hash(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// Reads the token from an "Authorization: Bearer ..." header
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
+
+/// Looks up the request's token and records that it was used
+pub(crate) async fn authenticate(headers: &HeaderMap, pool: &SqlitePool) -> Result<TokenGrant, (StatusCode, String)> {
+    let unauthorized = || (StatusCode::UNAUTHORIZED, String::from("Missing, invalid or revoked API token"));
+    let token = bearer_token(headers).ok_or_else(unauthorized)?;
+    let hash = token_hash(token);
+    let row = query!(
+        r#"SELECT api_tokens.id AS "id!", api_tokens.user_id, api_tokens.scope, api_tokens.scoped AS "scoped: bool",
+        users.username
+        FROM api_tokens JOIN users ON users.id = api_tokens.user_id
+        WHERE api_tokens.token_hash = ? AND api_tokens.revoked_at IS NULL AND users.is_bot = 1"#,
+        hash
+    ).fetch_optional(pool).await.unwrap().ok_or_else(unauthorized)?;
+    let scope = TokenScope::parse(&row.scope).ok_or_else(unauthorized)?;
+    // The chat rows go when a chat is purged, so an empty list on a scoped token allows nothing
+    let chats = match row.scoped {
+        true => Some(
+            query!("SELECT chat_id FROM api_token_chats WHERE token_id = ?", row.id)
+                .fetch_all(pool)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|r| r.chat_id)
+                .collect(),
+        ),
+        false => None,
+    };
+    query!("UPDATE api_tokens SET last_used_at = datetime('now') WHERE id = ?", row.id)
+        .execute(pool)
+        .await
+        .unwrap();
+    Ok(TokenGrant { token_id: row.id, user_id: row.user_id, botname: row.username, scope, chats })
+}
+
+/// Id of the bot if it exists and belongs to the owner
+async fn owned_bot(botname: &str, owner_id: i64, pool: &SqlitePool) -> Option<i64> {
+    query!(
+        r#"SELECT id AS "id!" FROM users WHERE username = ? AND is_bot = 1 AND bot_owner_id = ?"#,
+        botname, owner_id
+    ).fetch_optional(pool).await.unwrap().map(|row| row.id)
+}
+
+async fn chat_id_by_name(chatname: &str, pool: &SqlitePool) -> Option<i64> {
+    query!("SELECT id FROM chats WHERE name = ? AND deleted_at IS NULL", chatname)
+        .fetch_optional(pool)
+        .await
+        .unwrap()
+        .map(|row| row.id)
+}
+
+/// Creates a bot account owned by the user. Bots cannot log in; add them to chats like any other
+/// user and give them API tokens
+/// # Query format:
+/// curl -X POST -H "Content-Type: application/json" -d '{"password": "PasswordString", "botname": "ci-bot"}' "http://98.93.98.244:80/createbot/username/NameString"
+pub(crate) async fn create_bot(
+    State(pool): State<SqlitePool>,
+    State(limits): State<RateLimits>,
+    State(policy): State<RegistrationPolicy>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(username): Path<String>,
+    Json(request): Json<CreateBotRequest>,
+) -> Json<Result<String, String>> {
+    let Some((owner_id, _)) = verify_credentials(&username, &request.password, &pool).await else {
+        limits.lockout.record_failure(&username);
+        return Json(Err(String::from("Incorrect username or password")));
+    };
+    if let Err(e) = policy.check_username(&request.botname) {
+        return Json(Err(e));
+    }
+    // An empty password hash never verifies, so the account can only act through tokens
+    let bot_id = match query!(
+        r#"INSERT INTO users (username, password, role, created_at, is_bot, bot_owner_id)
+        VALUES (?, '', 'bot', datetime('now'), 1, ?)"#,
+        request.botname, owner_id
+    ).execute(&pool).await {
+        Ok(result) => result.last_insert_rowid(),
+        Err(e) if is_username_conflict(&e) => return Json(Err(String::from(USERNAME_TAKEN))),
+        Err(e) => panic!("{}", e),
+    };
+    audit::record(AuditEvent::AccountCreated, Some(owner_id), Some(bot_id), None, Some(addr.ip()), Some("Bot"), &pool).await;
+    println!("{} created bot {}", username, request.botname);
+    Json(Ok(String::from("1")))
+}
+
+/// Creates an API token for one of the user's bots, optionally limited to some chats
+/// # Query format:
+/// curl -X POST -H "Content-Type: application/json" -d '{"password": "PasswordString", "botname": "ci-bot", "label": "CI", "scope": "post", "chats": ["builds"]}' "http://98.93.98.244:80/createtoken/username/NameString"
+/// # Return format:
+/// "id" and "token"; the token is only shown this once. Scope is "read", "post" or "read_post"
+pub(crate) async fn create_token(
+    State(pool): State<SqlitePool>,
+    State(limits): State<RateLimits>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(username): Path<String>,
+    Json(request): Json<CreateTokenRequest>,
+) -> Json<Result<NewToken, String>> {
+    let Some((owner_id, _)) = verify_credentials(&username, &request.password, &pool).await else {
+        limits.lockout.record_failure(&username);
+        return Json(Err(String::from("Incorrect username or password")));
+    };
+    let Some(bot_id) = owned_bot(&request.botname, owner_id, &pool).await else {
+        return Json(Err(String::from("Bot not found")));
+    };
+    let mut chat_ids = Vec::new();
+    for chatname in &request.chats {
+        match chat_id_by_name(chatname, &pool).await {
+            Some(id) => chat_ids.push(id),
+            None => return Json(Err(format!("Chat {} not found", chatname))),
+        }
+    }
+    let token = generate_token();
+    let hash = token_hash(&token);
+    let scope = request.scope.as_str();
+    let scoped = !chat_ids.is_empty();
+    let mut tx = pool.begin().await.unwrap();
+    let id = query!(
+        r#"INSERT INTO api_tokens (user_id, label, token_hash, scope, scoped, created_by, created_at)
+        VALUES (?, ?, ?, ?, ?, ?, datetime('now'))"#,
+        bot_id, request.label, hash, scope, scoped, owner_id
+    ).execute(&mut *tx).await.unwrap().last_insert_rowid();
+    for chat_id in chat_ids {
+        query!("INSERT OR IGNORE INTO api_token_chats (token_id, chat_id) VALUES (?, ?)", id, chat_id)
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+    }
+    tx.commit().await.unwrap();
+    let detail = format!("Token {} ({})", id, scope);
+    audit::record(AuditEvent::TokenCreated, Some(owner_id), Some(bot_id), None, Some(addr.ip()), Some(&detail), &pool).await;
+    println!("{} created a {} token for {}", username, scope, request.botname);
+    Json(Ok(NewToken { id, token }))
+}
+
+/// Revokes one of the tokens of the user's bots; it stops working immediately
+/// # Query format:
+/// curl -X POST -H "Content-Type: application/json" -d '{"password": "PasswordString", "token_id": 3}' "http://98.93.98.244:80/revoketoken/username/NameString"
+pub(crate) async fn revoke_token(
+    State(pool): State<SqlitePool>,
+    State(limits): State<RateLimits>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(username): Path<String>,
+    Json(request): Json<RevokeTokenRequest>,
+) -> Json<Result<String, String>> {
+    let Some((owner_id, _)) = verify_credentials(&username, &request.password, &pool).await else {
+        limits.lockout.record_failure(&username);
+        return Json(Err(String::from("Incorrect username or password")));
+    };
+    let revoked = query!(
+        r#"UPDATE api_tokens SET revoked_at = datetime('now')
+        WHERE id = ? AND revoked_at IS NULL
+        AND user_id IN (SELECT id FROM users WHERE bot_owner_id = ?)
+        RETURNING user_id"#,
+        request.token_id, owner_id
+    ).fetch_optional(&pool).await.unwrap();
+    let Some(revoked) = revoked else {
+        return Json(Err(String::from("Token not found")));
+    };
+    let detail = format!("Token {}", request.token_id);
+    audit::record(AuditEvent::TokenRevoked, Some(owner_id), Some(revoked.user_id), None, Some(addr.ip()), Some(&detail), &pool).await;
+    println!("{} revoked token {}", username, request.token_id);
+    Json(Ok(String::from("1")))
+}
+
+/// Lists the tokens of every bot the user owns, newest first
+/// # Query format:
+/// curl "http://98.93.98.244:80/listtokens/username/NameString"
+/// # Return format:
+/// Array of "id", "botname", "label", "scope", "scoped", "chats", "created_at", "last_used_at" and "revoked"
+pub(crate) async fn list_tokens(
+    State(pool): State<SqlitePool>,
+    Path(username): Path<String>,
+) -> Json<Result<Vec<TokenInfo>, String>> {
+    let rows = query!(
+        r#"SELECT api_tokens.id AS "id!", bots.username AS botname, api_tokens.label, api_tokens.scope,
+        api_tokens.created_at AS "created_at!: String", api_tokens.last_used_at AS "last_used_at: String",
+        api_tokens.revoked_at IS NOT NULL AS "revoked!: bool", api_tokens.scoped AS "scoped: bool"
+        FROM api_tokens
+        JOIN users AS bots ON bots.id = api_tokens.user_id
+        JOIN users AS owners ON owners.id = bots.bot_owner_id
+        WHERE owners.username = ?
+        ORDER BY api_tokens.id DESC"#,
+        username
+    ).fetch_all(&pool).await.unwrap();
+    let mut tokens = Vec::new();
+    for row in rows {
+        let chats = query!(
+            "SELECT chats.name FROM api_token_chats JOIN chats ON chats.id = api_token_chats.chat_id WHERE token_id = ?",
+            row.id
+        ).fetch_all(&pool).await.unwrap()
+        .into_iter()
+        .filter_map(|r| r.name)
+        .collect();
+        tokens.push(TokenInfo {
+            id: row.id,
+            botname: row.botname,
+            label: row.label,
+            scope: TokenScope::parse(&row.scope).unwrap_or(TokenScope::Read),
+            scoped: row.scoped,
+            chats,
+            created_at: row.created_at,
+            last_used_at: row.last_used_at,
+            revoked: row.revoked,
+        });
+    }
+    Json(Ok(tokens))
+}
+
+/// Posts a message as the token's bot, through the same queue as /newmessage
+/// # Query format:
+/// curl -X POST -H "Authorization: Bearer bot_Token" -H "Content-Type: application/json" -d '{"content": "Build passed"}' "http://98.93.98.244:80/bot/newmessage/chatname/ChatName"
+/// # Return format:
+/// The new message's id
+pub(crate) async fn bot_message(
+    State(pool): State<SqlitePool>,
+    headers: HeaderMap,
+    Path(chatname): Path<String>,
+    Json(msg): Json<Message>,
+) -> Result<Json<Result<i64, String>>, (StatusCode, String)> {
+    let grant = authenticate(&headers, &pool).await?;
+    let chat_id = chat_id_by_name(&chatname, &pool)
+        .await
+        .ok_or((StatusCode::NOT_FOUND, String::from("Chat not found")))?;
+    grant.authorize(chat_id, TokenScope::Post, &pool).await?;
+    println!("Bot {} posting in {} with token {}", grant.botname, chatname, grant.token_id);
+    Ok(Json(enqueue_message(chat_id, grant.user_id, &msg, None, &pool).await))
+}
+
+/// Reads a chat's history as the token's bot
+/// # Query format:
+/// curl -H "Authorization: Bearer bot_Token" "http://98.93.98.244:80/bot/getchat/chatname/ChatName"
+/// # Return format:
+/// Same as /getchat
+pub(crate) async fn bot_history(
+    State(pool): State<SqlitePool>,
+    headers: HeaderMap,
+    Path(chatname): Path<String>,
+) -> Result<Json<Vec<ChatHistoryMessage>>, (StatusCode, String)> {
+    let grant = authenticate(&headers, &pool).await?;
+    let chat_id = chat_id_by_name(&chatname, &pool)
+        .await
+        .ok_or((StatusCode::NOT_FOUND, String::from("Chat not found")))?;
+    grant.authorize(chat_id, TokenScope::Read, &pool).await?;
+    Ok(Json(load_history(chat_id, Some(&grant.botname), &pool).await.unwrap_or_default()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_scope() {
+        assert!(TokenScope::ReadPost.allows(TokenScope::Post));
+        assert!(TokenScope::Post.allows(TokenScope::Post));
+        assert!(!TokenScope::Post.allows(TokenScope::Read));
+        assert!(!TokenScope::Read.allows(TokenScope::Post));
+        for scope in [TokenScope::Read, TokenScope::Post, TokenScope::ReadPost] {
+            assert_eq!(TokenScope::parse(scope.as_str()), Some(scope));
+        }
+    }
+
+    #[test]
+    fn test_chat_limits() {
+        let grant = |chats| TokenGrant { token_id: 1, user_id: 2, botname: String::from("bot"), scope: TokenScope::Read, chats };
+        assert!(grant(None).allows_chat(5));
+        assert!(grant(Some(vec![5])).allows_chat(5));
+        assert!(!grant(Some(vec![5])).allows_chat(6));
+        // Every chat the token was limited to has been purged
+        assert!(!grant(Some(Vec::new())).allows_chat(5));
+    }
+
+    #[test]
+    fn test_bearer_token() {
+        let token = generate_token();
+        assert!(token.starts_with(TOKEN_PREFIX));
+        assert_eq!(token.len(), TOKEN_PREFIX.len() + TOKEN_LENGTH);
+        let mut headers = HeaderMap::new();
+        assert_eq!(bearer_token(&headers), None);
+        headers.insert(header::AUTHORIZATION, format!("Bearer {}", token).parse().unwrap());
+        assert_eq!(bearer_token(&headers), Some(token.as_str()));
+        headers.insert(header::AUTHORIZATION, "Basic abc".parse().unwrap());
+        assert_eq!(bearer_token(&headers), None);
+    }
+}
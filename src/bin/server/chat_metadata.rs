@@ -0,0 +1,249 @@
+use axum::{extract::{Path, State}, response::Json};
+use serde::{Deserialize, Deserializer, Serialize};
+use sqlx::{query, SqlitePool};
+use std::sync::Arc;
+
+use crate::attachments::{self, AttachmentInfo};
+use crate::directory::Visibility;
+use crate::filters::FilterChain;
+use crate::pins::{self, PinnedMessage};
+use crate::retention::{self, RetentionPolicy};
+use crate::{lookup_member, manages_chat};
+
+const MAX_TOPIC_CHARS: usize = 250;
+const MAX_DESCRIPTION_CHARS: usize = 2000;
+
+/// Descriptive fields of a chat, reported by list_chats and chat details
+#[derive(Deserialize, Serialize, Default)]
+pub(crate) struct ChatMetadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    topic: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    avatar_attachment_id: Option<i64>, // Image uploaded to the chat, fetched through /attachment
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    created_by: Option<String>, // None for chats created before this was recorded
+    #[serde(default)]
+    created_at: String,
+    #[serde(default)]
+    visibility: Visibility,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    archived_at: Option<String>, // Set while the chat is archived and read-only
+}
+
+#[derive(Serialize)]
+pub(crate) struct MemberInfo {
+    username: String,
+    role: String,
+}
+
+/// Everything shown on a chat's details page
+#[derive(Serialize)]
+pub(crate) struct ChatDetails {
+    name: String,
+    #[serde(flatten)]
+    metadata: ChatMetadata,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    avatar: Option<AttachmentInfo>,
+    members: Vec<MemberInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retention: Option<RetentionPolicy>,
+    pins: Vec<PinnedMessage>,
+}
+
+/// Fields to change; missing fields are left alone, while an empty text or a null avatar clears them
+#[derive(Deserialize)]
+pub(crate) struct UpdateChatRequest {
+    #[serde(default)]
+    topic: Option<String>,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default, deserialize_with = "present")]
+    avatar_attachment_id: Option<Option<i64>>,
+    #[serde(default)]
+    visibility: Option<Visibility>,
+}
+
+/// Tells a null value apart from a missing field
+pub(crate) fn present<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    Option::deserialize(deserializer).map(Some)
+}
+
+/// Checks a topic's length; it must fit on one line
+pub(crate) fn check_topic(topic: &str) -> Result<(), String> {
+    if topic.chars().count() > MAX_TOPIC_CHARS {
+        return Err(format!("Topic can be at most {} characters", MAX_TOPIC_CHARS));
+    }
+    if topic.contains(['\n', '\r']) {
+        return Err(String::from("Topic must be a single line"));
+    }
+    Ok(())
+}
+
+fn check_description(description: &str) -> Result<(), String> {
+    if description.chars().count() > MAX_DESCRIPTION_CHARS {
+        return Err(format!("Description can be at most {} characters", MAX_DESCRIPTION_CHARS));
+    }
+    Ok(())
+}
+
+/// Trims a text field and runs it through moderation; empty text becomes None so the field is cleared
+pub(crate) fn moderated(text: &str, filters: &FilterChain) -> Result<Option<String>, String> {
+    match text.trim() {
+        "" => Ok(None),
+        trimmed => filters.apply(trimmed.to_string()).map(Some),
+    }
+}
+
+/// The chat's metadata; chats that do not exist get empty metadata
+pub(crate) async fn chat_metadata(chat_id: i64, pool: &SqlitePool) -> ChatMetadata {
+    query!(
+        r#"SELECT chats.topic, chats.description, chats.avatar_attachment_id,
+        users.username AS "created_by?", chats.created_at AS "created_at: String",
+        COALESCE(chats.visibility, 'private') = 'public' AS "public!: bool",
+        chats.archived_at AS "archived_at: String"
+        FROM chats LEFT JOIN users ON users.id = chats.created_by WHERE chats.id = ?"#,
+        chat_id
+    ).fetch_optional(pool).await.unwrap()
+    .map(|row| ChatMetadata {
+        topic: row.topic,
+        description: row.description,
+        avatar_attachment_id: row.avatar_attachment_id,
+        created_by: row.created_by,
+        created_at: row.created_at.unwrap_or_default(),
+        visibility: if row.public { Visibility::Public } else { Visibility::Private },
+        archived_at: row.archived_at,
+    })
+    .unwrap_or_default()
+}
+
+/// Updates a chat's topic, description, avatar and visibility; only the chat's owner and admins may do this.
+/// The avatar must be an image uploaded to the chat through /upload; public chats appear in /directory
+/// # Query format:
+/// curl -X POST -H "Content-Type: application/json" -d '{"topic": "Release planning", "description": "Weekly sync", "avatar_attachment_id": 7, "visibility": "public"}' "http://98.93.98.244:80/updatechat/chatname/ChatName/username/UsernameString"
+pub(crate) async fn update_chat(
+    State(pool): State<SqlitePool>,
+    State(filters): State<Arc<FilterChain>>,
+    Path((chatname, username)): Path<(String, String)>,
+    Json(request): Json<UpdateChatRequest>,
+) -> Json<Result<String, String>> {
+    let (chat_id, user_id) = match lookup_member(&chatname, &username, &pool).await {
+        Ok(ids) => ids,
+        Err(e) => return Json(Err(e)),
+    };
+    if !manages_chat(chat_id, user_id, &pool).await {
+        return Json(Err(String::from("Only chat owners and admins can edit chat details")));
+    }
+    if let Some(topic) = &request.topic {
+        let topic = match check_topic(topic).and_then(|_| moderated(topic, &filters)) {
+            Ok(topic) => topic,
+            Err(e) => return Json(Err(e)),
+        };
+        query!("UPDATE chats SET topic = ? WHERE id = ?", topic, chat_id).execute(&pool).await.unwrap();
+    }
+    if let Some(description) = &request.description {
+        let description = match check_description(description).and_then(|_| moderated(description, &filters)) {
+            Ok(description) => description,
+            Err(e) => return Json(Err(e)),
+        };
+        query!("UPDATE chats SET description = ? WHERE id = ?", description, chat_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+    if let Some(avatar) = request.avatar_attachment_id {
+        if let Some(id) = avatar
+            && attachments::chat_image(id, chat_id, &pool).await.is_none()
+        {
+            return Json(Err(String::from("Avatar must be an image uploaded to this chat")));
+        }
+        query!("UPDATE chats SET avatar_attachment_id = ? WHERE id = ?", avatar, chat_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+    if let Some(visibility) = request.visibility {
+        let visibility = visibility.as_str();
+        query!("UPDATE chats SET visibility = ? WHERE id = ?", visibility, chat_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+    println!("{} updated details of {}", username, chatname);
+    Json(Ok(String::from("1")))
+}
+
+/// Shows a chat's metadata, members, retention policy and pinned messages to its members
+/// # Query format:
+/// curl "http://98.93.98.244:80/chatdetails/chatname/ChatName/username/UsernameString"
+/// # Return format:
+/// "name", "topic", "description", "avatar_attachment_id" and "avatar" (attachment metadata), "created_by",
+/// "created_at", "visibility", "archived_at", "members" ("username" and "role"), "retention" and "pins"
+pub(crate) async fn chat_details(
+    State(pool): State<SqlitePool>,
+    Path((chatname, username)): Path<(String, String)>,
+) -> Json<Result<ChatDetails, String>> {
+    let (chat_id, _) = match lookup_member(&chatname, &username, &pool).await {
+        Ok(ids) => ids,
+        Err(e) => return Json(Err(e)),
+    };
+    let metadata = chat_metadata(chat_id, &pool).await;
+    let avatar = match metadata.avatar_attachment_id {
+        Some(id) => attachments::chat_image(id, chat_id, &pool).await,
+        None => None,
+    };
+    let members = query!(
+        r#"SELECT users.username, COALESCE(chat_users.role, 'member') AS "role!: String"
+        FROM chat_users JOIN users ON users.id = chat_users.user_id
+        WHERE chat_users.chat_id = ? ORDER BY chat_users.id"#,
+        chat_id
+    ).fetch_all(&pool).await.unwrap()
+    .into_iter()
+    .map(|row| MemberInfo { username: row.username, role: row.role })
+    .collect();
+    Json(Ok(ChatDetails {
+        name: chatname,
+        metadata,
+        avatar,
+        members,
+        retention: retention::chat_policy(chat_id, &pool).await,
+        pins: pins::pinned_messages(chat_id, &pool).await,
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_topic_rules() {
+        assert!(check_topic("Release planning").is_ok());
+        assert!(check_topic(&"t".repeat(MAX_TOPIC_CHARS + 1)).is_err());
+        assert!(check_topic("two\nlines").is_err());
+        assert!(check_description(&"d".repeat(MAX_DESCRIPTION_CHARS)).is_ok());
+    }
+
+    #[test]
+    fn test_update_fields() {
+        let request: UpdateChatRequest = serde_json::from_str(r#"{"topic": "New"}"#).unwrap();
+        assert_eq!(request.topic.as_deref(), Some("New"));
+        assert!(request.description.is_none());
+        assert_eq!(request.avatar_attachment_id, None);
+        let request: UpdateChatRequest = serde_json::from_str(r#"{"avatar_attachment_id": null}"#).unwrap();
+        assert_eq!(request.avatar_attachment_id, Some(None));
+        let request: UpdateChatRequest = serde_json::from_str(r#"{"avatar_attachment_id": 7}"#).unwrap();
+        assert_eq!(request.avatar_attachment_id, Some(Some(7)));
+    }
+
+    #[test]
+    fn test_moderated_clears_blank_text() {
+        let filters = FilterChain::new(Vec::new());
+        assert_eq!(moderated("   ", &filters), Ok(None));
+        assert_eq!(moderated(" Hi ", &filters), Ok(Some(String::from("Hi"))));
+    }
+}
@@ -0,0 +1,365 @@
+use sqlx::{query, SqlitePool};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use crate::chat_metadata::check_topic;
+use crate::filters::FilterChain;
+use crate::audit::{self, AuditEvent};
+use crate::moderation;
+
+/// Longest mute a chat admin can hand out
+const MAX_MUTE_SECS: i64 = 30 * 24 * 60 * 60;
+
+/// What a message turned out to be once the leading '/' is looked at
+#[derive(Debug, PartialEq)]
+pub(crate) enum Input {
+    Text(String),
+    Command { name: String, args: String },
+}
+
+/// Splits "/name rest of line" into a command; "//text" posts "/text" verbatim and anything
+/// not shaped like a command (e.g. "/usr/bin") is ordinary text
+pub(crate) fn parse(content: &str) -> Input {
+    if let Some(escaped) = content.strip_prefix("//") {
+        return Input::Text(format!("/{}", escaped));
+    }
+    let Some(body) = content.strip_prefix('/') else {
+        return Input::Text(content.to_string());
+    };
+    let (name, args) = body.split_once(char::is_whitespace).unwrap_or((body, ""));
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphabetic()) {
+        return Input::Text(content.to_string());
+    }
+    Input::Command { name: name.to_ascii_lowercase(), args: args.trim().to_string() }
+}
+
+/// Parses durations such as "30s", "10m", "2h" or "1d" into seconds
+pub(crate) fn parse_duration(text: &str) -> Option<i64> {
+    let unit = text.chars().last()?;
+    let amount: i64 = text[..text.len() - unit.len_utf8()].parse().ok().filter(|&n| n > 0)?;
+    let multiplier = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        _ => return None,
+    };
+    amount.checked_mul(multiplier).filter(|&secs| secs <= MAX_MUTE_SECS)
+}
+
+/// Who may run a command
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Permission {
+    Member,
+    ChatAdmin, // Chat owners and admins
+}
+
+/// Result of a command that ran successfully
+#[derive(Debug, PartialEq)]
+pub(crate) enum Outcome {
+    Post(String),  // Posted to the chat in place of the command
+    Reply(String), // Shown only to the author
+}
+
+/// The message a command arrived in and who sent it
+#[derive(Clone)]
+pub(crate) struct CommandContext {
+    pub(crate) pool: SqlitePool,
+    pub(crate) filters: Arc<FilterChain>,
+    pub(crate) chat_id: i64,
+    pub(crate) user_id: i64,
+    pub(crate) username: String,
+    pub(crate) role: String, // The author's role in the chat
+}
+
+type CommandFuture = Pin<Box<dyn Future<Output = Result<Outcome, String>> + Send>>;
+type Handler = fn(CommandContext, String) -> CommandFuture;
+
+/// A slash command: its name, help text, who may run it and the handler given the rest of the line
+pub(crate) struct Command {
+    pub(crate) name: &'static str,
+    pub(crate) usage: &'static str,
+    pub(crate) description: &'static str,
+    pub(crate) permission: Permission,
+    pub(crate) min_args: usize, // Words required after the name, checked before the handler runs
+    pub(crate) handler: Handler,
+}
+
+/// Commands understood by the message pipeline; /help is built in and lists everything registered
+pub(crate) struct CommandRegistry {
+    commands: Vec<Command>,
+}
+
+impl CommandRegistry {
+    pub(crate) fn new() -> Self {
+        CommandRegistry { commands: Vec::new() }
+    }
+
+    pub(crate) fn register(&mut self, command: Command) {
+        self.commands.push(command);
+    }
+
+    /// Registry with the commands shipped with the server
+    pub(crate) fn builtin() -> Self {
+        let mut registry = CommandRegistry::new();
+        registry.register(Command {
+            name: "me", usage: "/me <action>", description: "Posts an action, e.g. \"* alice waves\"",
+            permission: Permission::Member, min_args: 1, handler: |ctx, args| Box::pin(me(ctx, args)),
+        });
+        registry.register(Command {
+            name: "topic", usage: "/topic <text>", description: "Sets the chat's topic",
+            permission: Permission::ChatAdmin, min_args: 1, handler: |ctx, args| Box::pin(topic(ctx, args)),
+        });
+        registry.register(Command {
+            name: "invite", usage: "/invite <user>", description: "Adds a user to the chat",
+            permission: Permission::Member, min_args: 1, handler: |ctx, args| Box::pin(invite(ctx, args)),
+        });
+        registry.register(Command {
+            name: "kick", usage: "/kick <user> [reason]", description: "Removes a member from the chat",
+            permission: Permission::ChatAdmin, min_args: 1, handler: |ctx, args| Box::pin(kick(ctx, args)),
+        });
+        registry.register(Command {
+            name: "mute", usage: "/mute <user> <duration> [reason]", description: "Stops a member posting for a while (30s, 10m, 2h, 1d)",
+            permission: Permission::ChatAdmin, min_args: 2, handler: |ctx, args| Box::pin(mute(ctx, args)),
+        });
+        registry.register(Command {
+            name: "unmute", usage: "/unmute <user>", description: "Lets a muted member post again",
+            permission: Permission::ChatAdmin, min_args: 1, handler: |ctx, args| Box::pin(unmute(ctx, args)),
+        });
+        registry.register(Command {
+            name: "ban", usage: "/ban <user> [duration] [reason]", description: "Removes a user and keeps them out, for good unless a duration is given",
+            permission: Permission::ChatAdmin, min_args: 1, handler: |ctx, args| Box::pin(ban(ctx, args)),
+        });
+        registry.register(Command {
+            name: "unban", usage: "/unban <user>", description: "Lets a banned user back into the chat",
+            permission: Permission::ChatAdmin, min_args: 1, handler: |ctx, args| Box::pin(unban(ctx, args)),
+        });
+        registry
+    }
+
+    fn find(&self, name: &str) -> Option<&Command> {
+        self.commands.iter().find(|c| c.name == name)
+    }
+
+    /// One line per command, generated from the registry
+    pub(crate) fn help_text(&self) -> String {
+        let mut lines = vec![String::from("/help [command] - Lists commands or shows one command's usage")];
+        for command in &self.commands {
+            let restriction = match command.permission {
+                Permission::Member => "",
+                Permission::ChatAdmin => " (chat admins)",
+            };
+            lines.push(format!("{} - {}{}", command.usage, command.description, restriction));
+        }
+        lines.join("\n")
+    }
+
+    /// Checks the author may run the command and has given enough arguments, then runs it
+    pub(crate) async fn run(&self, ctx: CommandContext, name: &str, args: String) -> Result<Outcome, String> {
+        if name == "help" {
+            return match args.as_str() {
+                "" => Ok(Outcome::Reply(self.help_text())),
+                other => match self.find(other.trim_start_matches('/')) {
+                    Some(command) => Ok(Outcome::Reply(format!("{} - {}", command.usage, command.description))),
+                    None => Err(format!("Unknown command /{}", other.trim_start_matches('/'))),
+                },
+            };
+        }
+        let Some(command) = self.find(name) else {
+            return Err(format!("Unknown command /{}, see /help", name));
+        };
+        if !permitted(command.permission, &ctx.role) {
+            return Err(format!("Only chat owners and admins can use /{}", command.name));
+        }
+        if args.split_whitespace().count() < command.min_args {
+            return Err(format!("Usage: {}", command.usage));
+        }
+        (command.handler)(ctx, args).await
+    }
+}
+
+fn permitted(permission: Permission, role: &str) -> bool {
+    match permission {
+        Permission::Member => true,
+        Permission::ChatAdmin => matches!(role, "owner" | "admin"),
+    }
+}
+
+/// Looks up a member of the context's chat by username, returning their id and role
+async fn find_member(ctx: &CommandContext, username: &str) -> Result<(i64, String), String> {
+    query!(
+        r#"SELECT users.id AS "id!", chat_users.role FROM chat_users JOIN users ON users.id = chat_users.user_id
+        WHERE chat_users.chat_id = ? AND users.username = ?"#,
+        ctx.chat_id, username
+    ).fetch_optional(&ctx.pool).await.unwrap()
+    .map(|row| (row.id, row.role.unwrap_or_else(|| String::from("member"))))
+    .ok_or_else(|| format!("{} is not a member of this chat", username))
+}
+
+/// Finds a member the author is allowed to moderate
+async fn moderated_member(ctx: &CommandContext, username: &str) -> Result<i64, String> {
+    let (user_id, _) = find_member(ctx, username).await?;
+    moderation::check_authority(ctx.chat_id, ctx.user_id, user_id, &ctx.pool).await?;
+    Ok(user_id)
+}
+
+/// Finds a user the author is allowed to moderate, who need not be a member (e.g. to unban them)
+async fn moderated_user(ctx: &CommandContext, username: &str) -> Result<i64, String> {
+    let Some(user) = query!(r#"SELECT id AS "id!" FROM users WHERE username = ?"#, username)
+        .fetch_optional(&ctx.pool)
+        .await
+        .unwrap() else {
+        return Err(format!("User {} not found", username));
+    };
+    moderation::check_authority(ctx.chat_id, ctx.user_id, user.id, &ctx.pool).await?;
+    Ok(user.id)
+}
+
+/// Text after the first `skip` words, None when there is none
+fn rest(args: &str, skip: usize) -> Option<&str> {
+    let mut rest = args.trim_start();
+    for _ in 0..skip {
+        rest = rest.split_once(char::is_whitespace).map_or("", |(_, tail)| tail).trim_start();
+    }
+    Some(rest.trim_end()).filter(|r| !r.is_empty())
+}
+
+async fn me(ctx: CommandContext, args: String) -> Result<Outcome, String> {
+    Ok(Outcome::Post(format!("* {} {}", ctx.username, args)))
+}
+
+async fn topic(ctx: CommandContext, args: String) -> Result<Outcome, String> {
+    check_topic(&args)?;
+    let topic = ctx.filters.apply(args)?;
+    query!("UPDATE chats SET topic = ? WHERE id = ?", topic, ctx.chat_id)
+        .execute(&ctx.pool)
+        .await
+        .unwrap();
+    Ok(Outcome::Post(format!("* {} set the topic to: {}", ctx.username, topic)))
+}
+
+async fn invite(ctx: CommandContext, args: String) -> Result<Outcome, String> {
+    let target = args.split_whitespace().next().unwrap_or_default();
+    let Some(user) = query!(r#"SELECT id AS "id!" FROM users WHERE username = ?"#, target)
+        .fetch_optional(&ctx.pool)
+        .await
+        .unwrap() else {
+        return Err(format!("User {} not found", target));
+    };
+    if find_member(&ctx, target).await.is_ok() {
+        return Err(format!("{} is already in this chat", target));
+    }
+    moderation::check_not_banned(ctx.chat_id, user.id, &ctx.pool)
+        .await
+        .map_err(|_| format!("{} is banned from this chat", target))?;
+    query!(
+        r#"INSERT INTO chat_users (chat_id, user_id, is_active, joined_at, role)
+        VALUES (?, ?, 1, datetime('now'), 'member')"#,
+        ctx.chat_id, user.id
+    ).execute(&ctx.pool).await.unwrap();
+    audit::record(AuditEvent::MemberAdded, Some(ctx.user_id), Some(user.id), Some(ctx.chat_id), None, None, &ctx.pool).await;
+    Ok(Outcome::Post(format!("* {} added {} to the chat", ctx.username, target)))
+}
+
+async fn kick(ctx: CommandContext, args: String) -> Result<Outcome, String> {
+    let target = args.split_whitespace().next().unwrap_or_default();
+    let user_id = moderated_member(&ctx, target).await?;
+    moderation::kick(ctx.chat_id, user_id, ctx.user_id, rest(&args, 1), None, &ctx.pool).await;
+    Ok(Outcome::Post(format!("* {} removed {} from the chat", ctx.username, target)))
+}
+
+async fn mute(ctx: CommandContext, args: String) -> Result<Outcome, String> {
+    let mut words = args.split_whitespace();
+    let (target, duration) = (words.next().unwrap_or_default(), words.next().unwrap_or_default());
+    let Some(secs) = parse_duration(duration) else {
+        return Err(format!("Invalid duration {}, use e.g. 30s, 10m, 2h or 1d (at most 30d)", duration));
+    };
+    let user_id = moderated_member(&ctx, target).await?;
+    moderation::mute(ctx.chat_id, user_id, ctx.user_id, secs, rest(&args, 2), &ctx.pool).await;
+    Ok(Outcome::Post(format!("* {} muted {} for {}", ctx.username, target, duration)))
+}
+
+async fn unmute(ctx: CommandContext, args: String) -> Result<Outcome, String> {
+    let target = args.split_whitespace().next().unwrap_or_default();
+    let user_id = moderated_member(&ctx, target).await?;
+    if !moderation::unmute(ctx.chat_id, user_id, ctx.user_id, &ctx.pool).await {
+        return Err(format!("{} is not muted", target));
+    }
+    Ok(Outcome::Post(format!("* {} unmuted {}", ctx.username, target)))
+}
+
+async fn ban(ctx: CommandContext, args: String) -> Result<Outcome, String> {
+    let mut words = args.split_whitespace();
+    let target = words.next().unwrap_or_default();
+    // The second word is a duration only if it parses as one, otherwise it starts the reason
+    let secs = words.next().and_then(parse_duration);
+    let reason = rest(&args, if secs.is_some() { 2 } else { 1 });
+    let user_id = moderated_user(&ctx, target).await?;
+    let expiry = secs.map(|secs| format!("+{} seconds", secs));
+    moderation::ban(ctx.chat_id, user_id, ctx.user_id, expiry.as_deref(), reason, None, &ctx.pool).await;
+    Ok(Outcome::Post(format!("* {} banned {} from the chat", ctx.username, target)))
+}
+
+async fn unban(ctx: CommandContext, args: String) -> Result<Outcome, String> {
+    let target = args.split_whitespace().next().unwrap_or_default();
+    let user_id = moderated_user(&ctx, target).await?;
+    if !moderation::unban(ctx.chat_id, user_id, ctx.user_id, &ctx.pool).await {
+        return Err(format!("{} is not banned", target));
+    }
+    Ok(Outcome::Post(format!("* {} unbanned {}", ctx.username, target)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("/kick bob"), Input::Command { name: String::from("kick"), args: String::from("bob") });
+        assert_eq!(parse("/HELP"), Input::Command { name: String::from("help"), args: String::new() });
+        assert_eq!(parse("/me  waves  "), Input::Command { name: String::from("me"), args: String::from("waves") });
+        assert_eq!(parse("//shrug"), Input::Text(String::from("/shrug")));
+        assert_eq!(parse("/usr/bin is full"), Input::Text(String::from("/usr/bin is full")));
+        assert_eq!(parse("hello /me"), Input::Text(String::from("hello /me")));
+        assert_eq!(parse("/"), Input::Text(String::from("/")));
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("30s"), Some(30));
+        assert_eq!(parse_duration("10m"), Some(600));
+        assert_eq!(parse_duration("2h"), Some(7200));
+        assert_eq!(parse_duration("1d"), Some(86400));
+        assert_eq!(parse_duration("31d"), None);
+        assert_eq!(parse_duration("0m"), None);
+        assert_eq!(parse_duration("10"), None);
+        assert_eq!(parse_duration("m"), None);
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("5é"), None);
+    }
+
+    #[test]
+    fn test_permissions() {
+        assert!(permitted(Permission::Member, "member"));
+        assert!(!permitted(Permission::ChatAdmin, "member"));
+        assert!(permitted(Permission::ChatAdmin, "admin"));
+    }
+
+    #[test]
+    fn test_rest() {
+        assert_eq!(rest("bob 10m being rude", 2), Some("being rude"));
+        assert_eq!(rest("bob  spamming links ", 1), Some("spamming links"));
+        assert_eq!(rest("bob", 1), None);
+        assert_eq!(rest("bob 10m", 2), None);
+    }
+
+    #[test]
+    fn test_help_lists_registered_commands() {
+        let help = CommandRegistry::builtin().help_text();
+        for name in ["/help", "/me", "/topic", "/invite", "/kick", "/mute", "/unmute", "/ban", "/unban"] {
+            assert!(help.contains(name), "{} missing from help", name);
+        }
+        assert!(help.contains("/kick <user> [reason] - Removes a member from the chat (chat admins)"));
+    }
+}
@@ -0,0 +1,208 @@
+use axum::{extract::{ConnectInfo, Path, State}, response::Json};
+use axum_extra::extract::Query;
+use serde::{Deserialize, Serialize};
+use sqlx::{query, SqlitePool};
+use std::net::SocketAddr;
+
+use crate::audit::{self, AuditEvent};
+use crate::{chat_role, lookup_member, moderation};
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 100;
+
+/// Whether a chat is listed in the directory and open to anyone, or invitation only
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Visibility {
+    Public,
+    #[default]
+    Private,
+}
+
+impl Visibility {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Visibility::Public => "public",
+            Visibility::Private => "private",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct DirectoryParams {
+    search: Option<String>,   // Matched against name, topic and description
+    username: Option<String>, // Requesting user, used for "joined" flags
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+/// A public channel as listed in the directory
+#[derive(Serialize)]
+pub(crate) struct DirectoryEntry {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    member_count: i64,
+    last_activity: Option<String>, // Time of the newest posted message, None for a quiet channel
+    joined: bool,
+}
+
+/// LIKE pattern matching the search text anywhere, with LIKE's wildcards taken literally
+fn like_pattern(search: &str) -> String {
+    let mut pattern = String::from("%");
+    for c in search.trim().chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('%');
+    pattern
+}
+
+/// Lists public channels, most recently active first, optionally filtered by a search term.
+/// Archived channels are not listed
+/// # Query format:
+/// curl "http://98.93.98.244:80/directory?search=rust&username=UsernameString&limit=20&offset=0"
+/// # Return format:
+/// Array of "name", "topic", "description", "member_count", "last_activity" and "joined"
+pub(crate) async fn list_directory(
+    State(pool): State<SqlitePool>,
+    Query(params): Query<DirectoryParams>,
+) -> Json<Result<Vec<DirectoryEntry>, String>> {
+    let pattern = params.search.as_deref().filter(|s| !s.trim().is_empty()).map(like_pattern);
+    let limit = params.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let offset = params.offset.unwrap_or(0).max(0);
+    let entries = query!(
+        r#"SELECT chats.name AS "name!", chats.topic, chats.description,
+        (SELECT COUNT(*) FROM chat_users WHERE chat_users.chat_id = chats.id) AS "member_count!: i64",
+        (SELECT MAX(messages.created_at) FROM messages
+            WHERE messages.chat_id = chats.id AND messages.status = 'Sent!') AS "last_activity: String",
+        EXISTS(SELECT 1 FROM chat_users JOIN users ON users.id = chat_users.user_id
+            WHERE chat_users.chat_id = chats.id AND users.username = ?2) AS "joined!: bool"
+        FROM chats
+        WHERE chats.visibility = 'public' AND chats.archived_at IS NULL AND chats.deleted_at IS NULL
+        AND (?1 IS NULL OR chats.name LIKE ?1 ESCAPE '\' OR chats.topic LIKE ?1 ESCAPE '\'
+            OR chats.description LIKE ?1 ESCAPE '\')
+        ORDER BY "last_activity: String" IS NULL, "last_activity: String" DESC, chats.name
+        LIMIT ?3 OFFSET ?4"#,
+        pattern, params.username, limit, offset
+    ).fetch_all(&pool).await.unwrap()
+    .into_iter()
+    .map(|row| DirectoryEntry {
+        name: row.name,
+        topic: row.topic,
+        description: row.description,
+        member_count: row.member_count,
+        last_activity: row.last_activity,
+        joined: row.joined,
+    })
+    .collect();
+    Json(Ok(entries))
+}
+
+/// Joins a public channel; joining a channel the user is already in is a no-op, and users banned from it cannot join
+/// # Query format:
+/// curl -X POST "http://98.93.98.244:80/join/chatname/ChatName/username/UsernameString"
+pub(crate) async fn join_chat(
+    State(pool): State<SqlitePool>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path((chatname, username)): Path<(String, String)>,
+) -> Json<Result<String, String>> {
+    let Some(chat) = query!(
+        r#"SELECT id AS "id!", COALESCE(visibility, 'private') AS "visibility!: String" FROM chats
+        WHERE name = ? AND archived_at IS NULL AND deleted_at IS NULL"#,
+        chatname
+    ).fetch_optional(&pool).await.unwrap() else {
+        return Json(Err(String::from("Chat not found")));
+    };
+    // Private chats are reported the same way as missing ones so their names are not revealed
+    if chat.visibility != Visibility::Public.as_str() {
+        return Json(Err(String::from("Chat not found")));
+    }
+    let Some(user) = query!(r#"SELECT id AS "id!" FROM users WHERE username = ?"#, username)
+        .fetch_optional(&pool)
+        .await
+        .unwrap() else {
+        return Json(Err(String::from("User not found")));
+    };
+    if chat_role(chat.id, user.id, &pool).await.is_some() {
+        return Json(Ok(String::from("0")));
+    }
+    if let Err(e) = moderation::check_not_banned(chat.id, user.id, &pool).await {
+        return Json(Err(e));
+    }
+    query!(
+        r#"INSERT INTO chat_users (chat_id, user_id, is_active, joined_at, role)
+        VALUES (?, ?, 1, datetime('now'), 'member')"#,
+        chat.id, user.id
+    ).execute(&pool).await.unwrap();
+    audit::record(AuditEvent::MemberJoined, Some(user.id), Some(user.id), Some(chat.id), Some(addr.ip()), None, &pool).await;
+    println!("{} joined {}", username, chatname);
+    Json(Ok(String::from("1")))
+}
+
+/// Leaves a chat. When the owner leaves, the longest-standing admin, or failing that member,
+/// becomes the owner
+/// # Query format:
+/// curl -X POST "http://98.93.98.244:80/leave/chatname/ChatName/username/UsernameString"
+pub(crate) async fn leave_chat(
+    State(pool): State<SqlitePool>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path((chatname, username)): Path<(String, String)>,
+) -> Json<Result<String, String>> {
+    let (chat_id, user_id) = match lookup_member(&chatname, &username, &pool).await {
+        Ok(ids) => ids,
+        Err(e) => return Json(Err(e)),
+    };
+    let was_owner = chat_role(chat_id, user_id, &pool).await.as_deref() == Some("owner");
+    let mut tx = pool.begin().await.unwrap();
+    query!("DELETE FROM chat_users WHERE chat_id = ? AND user_id = ?", chat_id, user_id)
+        .execute(&mut *tx)
+        .await
+        .unwrap();
+    let mut new_owner = None;
+    if was_owner {
+        let successor = query!(
+            r#"SELECT id AS "id!", user_id FROM chat_users WHERE chat_id = ?
+            ORDER BY COALESCE(role, 'member') = 'admin' DESC, joined_at, id LIMIT 1"#,
+            chat_id
+        ).fetch_optional(&mut *tx).await.unwrap();
+        if let Some(successor) = successor {
+            query!("UPDATE chat_users SET role = 'owner' WHERE id = ?", successor.id)
+                .execute(&mut *tx)
+                .await
+                .unwrap();
+            new_owner = Some(successor.user_id);
+        }
+    }
+    tx.commit().await.unwrap();
+    let ip = Some(addr.ip());
+    audit::record(AuditEvent::MemberLeft, Some(user_id), Some(user_id), Some(chat_id), ip, None, &pool).await;
+    if let Some(new_owner) = new_owner {
+        audit::record(AuditEvent::RoleChanged, Some(user_id), Some(new_owner), Some(chat_id), ip, Some("Became owner"), &pool).await;
+    }
+    println!("{} left {}", username, chatname);
+    Json(Ok(String::from("1")))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_like_pattern() {
+        assert_eq!(like_pattern("rust"), "%rust%");
+        assert_eq!(like_pattern("  100%_done "), "%100\\%\\_done%");
+        assert_eq!(like_pattern("a\\b"), "%a\\\\b%");
+    }
+
+    #[test]
+    fn test_visibility_names() {
+        assert_eq!(serde_json::to_string(&Visibility::Public).unwrap(), r#""public""#);
+        assert_eq!(serde_json::from_str::<Visibility>(r#""private""#).unwrap(), Visibility::Private);
+        assert_eq!(Visibility::default(), Visibility::Private);
+    }
+}
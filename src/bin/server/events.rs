@@ -0,0 +1,185 @@
+use axum::{
+    extract::{Path, State}, http::StatusCode, response::sse::{Event, KeepAlive, Sse},
+};
+use serde::Serialize;
+use sqlx::SqlitePool;
+use tokio::sync::broadcast;
+use tokio_stream::{wrappers::BroadcastStream, Stream, StreamExt};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+
+use crate::blocks;
+use crate::{lookup_member, ChatHistoryMessage};
+
+/// Number of events a slow subscriber can fall behind before it starts missing them
+pub(crate) const EVENT_BUFFER: usize = 256;
+
+pub(crate) type EventSender = broadcast::Sender<ChatEvent>;
+
+/// Real-time events fanned out to every client connected to a chat's event stream
+#[derive(Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum ChatEvent {
+    Message {
+        #[serde(skip)]
+        chat_id: i64,
+        message: ChatHistoryMessage,
+    },
+    Reaction {
+        #[serde(skip)]
+        chat_id: i64,
+        message_id: i64,
+        username: String,
+        emoji: String,
+        added: bool,
+    },
+    Pin {
+        #[serde(skip)]
+        chat_id: i64,
+        message_id: i64,
+        username: String,
+        pinned: bool,
+    },
+    Read {
+        #[serde(skip)]
+        chat_id: i64,
+        username: String,
+        message_id: i64,
+    },
+    Typing {
+        #[serde(skip)]
+        chat_id: i64,
+        username: String,
+        typing: bool,
+    },
+    Rejected {
+        #[serde(skip)]
+        chat_id: i64,
+        username: String,
+        message_id: i64,
+        reason: String,
+    },
+    CommandOutput {
+        #[serde(skip)]
+        chat_id: i64,
+        username: String,
+        message_id: i64,
+        output: String,
+    },
+    Deleted {
+        #[serde(skip)]
+        chat_id: i64,
+        message_id: i64,
+    },
+}
+
+impl ChatEvent {
+    fn chat_id(&self) -> i64 {
+        match self {
+            ChatEvent::Message { chat_id, .. } => *chat_id,
+            ChatEvent::Reaction { chat_id, .. } => *chat_id,
+            ChatEvent::Pin { chat_id, .. } => *chat_id,
+            ChatEvent::Read { chat_id, .. } => *chat_id,
+            ChatEvent::Typing { chat_id, .. } => *chat_id,
+            ChatEvent::Rejected { chat_id, .. } => *chat_id,
+            ChatEvent::CommandOutput { chat_id, .. } => *chat_id,
+            ChatEvent::Deleted { chat_id, .. } => *chat_id,
+        }
+    }
+
+    /// Whether a subscriber should receive the event; users are not told about their own typing,
+    /// and only the author hears that their message was rejected or what their command replied
+    fn visible_to(&self, chat_id: i64, subscriber: &str) -> bool {
+        match self {
+            ChatEvent::Typing { username, .. } if username == subscriber => false,
+            ChatEvent::Rejected { username, .. } if username != subscriber => false,
+            ChatEvent::CommandOutput { username, .. } if username != subscriber => false,
+            _ => self.chat_id() == chat_id,
+        }
+    }
+}
+
+/// Members currently connected to each chat's event stream, keyed by (chat id, user id)
+/// with a count of open connections so several tabs or devices are handled
+#[derive(Clone, Default)]
+pub(crate) struct Presence {
+    online: Arc<Mutex<HashMap<(i64, i64), usize>>>,
+}
+
+impl Presence {
+    /// Marks the user online in the chat until the returned guard is dropped
+    fn connect(&self, chat_id: i64, user_id: i64) -> PresenceGuard {
+        *self.online.lock().unwrap().entry((chat_id, user_id)).or_insert(0) += 1;
+        PresenceGuard { presence: self.clone(), chat_id, user_id }
+    }
+
+    /// Ids of the users with at least one open event stream in the chat
+    pub(crate) fn online_in(&self, chat_id: i64) -> Vec<i64> {
+        self.online
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|(chat, _)| *chat == chat_id)
+            .map(|(_, user)| *user)
+            .collect()
+    }
+}
+
+/// Held by an open event stream; dropping it when the client disconnects marks the user offline
+struct PresenceGuard {
+    presence: Presence,
+    chat_id: i64,
+    user_id: i64,
+}
+
+impl Drop for PresenceGuard {
+    fn drop(&mut self) {
+        let mut online = self.presence.online.lock().unwrap();
+        let key = (self.chat_id, self.user_id);
+        if let Some(count) = online.get_mut(&key) {
+            *count -= 1;
+            if *count == 0 {
+                online.remove(&key);
+            }
+        }
+    }
+}
+
+/// Sends an event to every connected subscriber; having nobody listening is not an error
+pub(crate) fn publish(events: &EventSender, event: ChatEvent) {
+    let _ = events.send(event);
+}
+
+/// Streams live events for a chat as server-sent events, only members of the chat may subscribe
+/// # Query format:
+/// curl -N "http://98.93.98.244:80/events/chatname/ChatName/username/UsernameString"
+/// # Return format:
+/// One JSON object per event, tagged by "type" ("message", "reaction", "read", "typing", "rejected", "deleted")
+pub(crate) async fn subscribe(
+    State(pool): State<SqlitePool>,
+    State(events): State<EventSender>,
+    State(presence): State<Presence>,
+    Path((chatname, username)): Path<(String, String)>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, StatusCode> {
+    let (chat_id, user_id) = lookup_member(&chatname, &username, &pool)
+        .await
+        .map_err(|_| StatusCode::FORBIDDEN)?;
+    println!("{} subscribed to events in {}", username, chatname);
+    // Blocks made while the stream is open take effect when the client reconnects
+    let blocked = blocks::blocked_usernames(&username, &pool).await;
+    // The stream owns the guard, so the user stays online exactly as long as the connection
+    let guard = presence.connect(chat_id, user_id);
+    let stream = BroadcastStream::new(events.subscribe()).filter_map(move |event| {
+        let _online = &guard;
+        match event {
+            // Lagged receivers skip the events they missed and carry on
+            Ok(ChatEvent::Message { message, .. }) if blocked.contains(&message.username) => None,
+            Ok(event) if event.visible_to(chat_id, &username) => {
+                Some(Ok(Event::default().json_data(&event).unwrap()))
+            }
+            _ => None,
+        }
+    });
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
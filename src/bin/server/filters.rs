@@ -0,0 +1,266 @@
+use regex::Regex;
+use serde::Deserialize;
+
+const DEFAULT_CONFIG_PATH: &str = "moderation.json";
+const DEFAULT_MAX_LENGTH: usize = 4000;
+const DEFAULT_MAX_REPEAT: usize = 30;
+
+/// A moderation stage in the message pipeline. Returns the content to post, possibly rewritten,
+/// or the reason the message is rejected
+pub(crate) trait MessageFilter: Send + Sync {
+    fn apply(&self, content: String) -> Result<String, String>;
+}
+
+/// What the word list does when a listed word appears
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum WordAction {
+    #[default]
+    Mask,
+    Reject,
+}
+
+/// Masks or rejects listed words, matched case-insensitively as whole words
+pub(crate) struct WordListFilter {
+    pattern: Regex,
+    action: WordAction,
+}
+
+impl WordListFilter {
+    pub(crate) fn new(words: &[String], action: WordAction) -> Self {
+        let alternatives: Vec<String> = words.iter().map(|w| regex::escape(w)).collect();
+        let pattern = Regex::new(&format!(r"(?i)\b(?:{})\b", alternatives.join("|"))).unwrap();
+        WordListFilter { pattern, action }
+    }
+}
+
+impl MessageFilter for WordListFilter {
+    fn apply(&self, content: String) -> Result<String, String> {
+        if !self.pattern.is_match(&content) {
+            return Ok(content);
+        }
+        match self.action {
+            WordAction::Reject => Err(String::from("Message contains a blocked word")),
+            WordAction::Mask => Ok(self
+                .pattern
+                .replace_all(&content, |caps: &regex::Captures| "*".repeat(caps[0].chars().count()))
+                .into_owned()),
+        }
+    }
+}
+
+/// Rejects messages matching any configured regular expression, with that rule's reason
+pub(crate) struct RegexFilter {
+    rules: Vec<(Regex, String)>,
+}
+
+impl MessageFilter for RegexFilter {
+    fn apply(&self, content: String) -> Result<String, String> {
+        match self.rules.iter().find(|(pattern, _)| pattern.is_match(&content)) {
+            Some((_, reason)) => Err(reason.clone()),
+            None => Ok(content),
+        }
+    }
+}
+
+/// Rejects links unless they point at an allowed domain or one of its subdomains
+pub(crate) struct LinkFilter {
+    pattern: Regex,
+    allowed_domains: Vec<String>,
+}
+
+impl LinkFilter {
+    pub(crate) fn new(allowed_domains: Vec<String>) -> Self {
+        let pattern = Regex::new(r"(?i)\b(?:https?://|www\.)([a-z0-9.-]+)").unwrap();
+        let allowed_domains = allowed_domains.into_iter().map(|d| d.to_lowercase()).collect();
+        LinkFilter { pattern, allowed_domains }
+    }
+
+    fn allowed(&self, host: &str) -> bool {
+        let host = host.to_lowercase();
+        let host = host.strip_prefix("www.").unwrap_or(&host);
+        self.allowed_domains
+            .iter()
+            .any(|d| host == d || host.ends_with(&format!(".{}", d)))
+    }
+}
+
+impl MessageFilter for LinkFilter {
+    fn apply(&self, content: String) -> Result<String, String> {
+        for caps in self.pattern.captures_iter(&content) {
+            if !self.allowed(&caps[1]) {
+                return Err(String::from("Links are not allowed in this chat"));
+            }
+        }
+        Ok(content)
+    }
+}
+
+/// Rejects messages longer than a number of characters
+pub(crate) struct MaxLengthFilter {
+    max_chars: usize,
+}
+
+impl MessageFilter for MaxLengthFilter {
+    fn apply(&self, content: String) -> Result<String, String> {
+        if content.chars().count() > self.max_chars {
+            return Err(format!("Message is longer than {} characters", self.max_chars));
+        }
+        Ok(content)
+    }
+}
+
+/// Rejects spam such as "aaaaaaaaaaaa" or "!!!!!!!!!!!!" with a run of one character above the limit
+pub(crate) struct RepeatedCharFilter {
+    max_run: usize,
+}
+
+impl MessageFilter for RepeatedCharFilter {
+    fn apply(&self, content: String) -> Result<String, String> {
+        let mut run = 0;
+        let mut previous: Option<char> = None;
+        for c in content.chars() {
+            run = if previous == Some(c) && !c.is_whitespace() { run + 1 } else { 1 };
+            if run > self.max_run {
+                return Err(String::from("Message looks like spam (too many repeated characters)"));
+            }
+            previous = Some(c);
+        }
+        Ok(content)
+    }
+}
+
+/// One regex rule in the moderation config
+#[derive(Deserialize)]
+struct RegexRule {
+    pattern: String,
+    reason: String,
+}
+
+/// Contents of the moderation config file, every field is optional
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct FilterConfig {
+    blocked_words: Vec<String>,
+    word_action: WordAction,
+    regex_rules: Vec<RegexRule>,
+    block_links: bool,
+    allowed_domains: Vec<String>,
+    max_length: Option<usize>,
+    max_repeated_chars: Option<usize>,
+}
+
+/// The configured filters, applied in order until one rejects the message
+pub(crate) struct FilterChain {
+    filters: Vec<Box<dyn MessageFilter>>,
+}
+
+impl FilterChain {
+    pub(crate) fn new(filters: Vec<Box<dyn MessageFilter>>) -> Self {
+        FilterChain { filters }
+    }
+
+    /// Builds the chain from the JSON file at MODERATION_CONFIG (default moderation.json).
+    /// Without a config file only the length and repeated-character limits apply
+    pub(crate) fn from_env() -> Self {
+        let path = std::env::var("MODERATION_CONFIG").unwrap_or_else(|_| String::from(DEFAULT_CONFIG_PATH));
+        let config = match std::fs::read_to_string(&path) {
+            Ok(json) => serde_json::from_str(&json).expect("Invalid moderation config"),
+            Err(_) => FilterConfig::default(),
+        };
+        FilterChain::from_config(config)
+    }
+
+    fn from_config(config: FilterConfig) -> Self {
+        let mut filters: Vec<Box<dyn MessageFilter>> = vec![
+            Box::new(MaxLengthFilter { max_chars: config.max_length.unwrap_or(DEFAULT_MAX_LENGTH) }),
+            Box::new(RepeatedCharFilter { max_run: config.max_repeated_chars.unwrap_or(DEFAULT_MAX_REPEAT) }),
+        ];
+        if !config.blocked_words.is_empty() {
+            filters.push(Box::new(WordListFilter::new(&config.blocked_words, config.word_action)));
+        }
+        if !config.regex_rules.is_empty() {
+            let rules = config
+                .regex_rules
+                .into_iter()
+                .map(|r| (Regex::new(&r.pattern).expect("Invalid moderation regex"), r.reason))
+                .collect();
+            filters.push(Box::new(RegexFilter { rules }));
+        }
+        if config.block_links {
+            filters.push(Box::new(LinkFilter::new(config.allowed_domains)));
+        }
+        FilterChain::new(filters)
+    }
+
+    /// Runs the message through every filter, stopping at the first rejection
+    pub(crate) fn apply(&self, content: String) -> Result<String, String> {
+        self.filters.iter().try_fold(content, |content, filter| filter.apply(content))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn words(list: &[&str]) -> Vec<String> {
+        list.iter().map(|w| w.to_string()).collect()
+    }
+
+    #[test]
+    fn test_word_list_mask() {
+        let filter = WordListFilter::new(&words(&["darn"]), WordAction::Mask);
+        assert_eq!(filter.apply(String::from("Darn it, darnation")).unwrap(), "**** it, darnation");
+    }
+
+    #[test]
+    fn test_word_list_reject() {
+        let filter = WordListFilter::new(&words(&["darn"]), WordAction::Reject);
+        assert!(filter.apply(String::from("oh DARN")).is_err());
+        assert!(filter.apply(String::from("all fine")).is_ok());
+    }
+
+    #[test]
+    fn test_regex_filter() {
+        let rules = vec![(Regex::new(r"\d{16}").unwrap(), String::from("Looks like a card number"))];
+        let filter = RegexFilter { rules };
+        assert_eq!(filter.apply(String::from("1234567812345678")).unwrap_err(), "Looks like a card number");
+        assert!(filter.apply(String::from("order 1234")).is_ok());
+    }
+
+    #[test]
+    fn test_link_filter() {
+        let filter = LinkFilter::new(words(&["example.com"]));
+        assert!(filter.apply(String::from("see https://docs.example.com/page")).is_ok());
+        assert!(filter.apply(String::from("see www.example.com")).is_ok());
+        assert!(filter.apply(String::from("see http://evil.com")).is_err());
+        assert!(filter.apply(String::from("see https://example.com.evil.com")).is_err());
+    }
+
+    #[test]
+    fn test_max_length() {
+        let filter = MaxLengthFilter { max_chars: 3 };
+        assert!(filter.apply(String::from("héé")).is_ok());
+        assert!(filter.apply(String::from("four")).is_err());
+    }
+
+    #[test]
+    fn test_repeated_chars() {
+        let filter = RepeatedCharFilter { max_run: 3 };
+        assert!(filter.apply(String::from("cool!!!")).is_ok());
+        assert!(filter.apply(String::from("cool!!!!")).is_err());
+        assert!(filter.apply(String::from("a     b")).is_ok());
+    }
+
+    #[test]
+    fn test_chain_stops_at_first_rejection() {
+        let config = FilterConfig {
+            blocked_words: words(&["darn"]),
+            max_length: Some(10),
+            ..Default::default()
+        };
+        let chain = FilterChain::from_config(config);
+        assert_eq!(chain.apply(String::from("darn")).unwrap(), "****");
+        assert!(chain.apply(String::from("darn darn darn")).is_err());
+    }
+}
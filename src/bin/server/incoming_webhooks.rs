@@ -0,0 +1,300 @@
+use axum::{extract::{ConnectInfo, Path, State}, http::StatusCode, response::Json};
+use data_encoding::BASE64;
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use sqlx::{query, SqlitePool};
+use std::net::SocketAddr;
+
+use crate::accounts::verify_credentials;
+use crate::attachments::{self, Attachments};
+use crate::audit::{self, AuditEvent};
+use crate::bots::token_hash;
+use crate::ratelimit::RateLimits;
+use crate::registration::{is_reserved, is_username_conflict, RegistrationPolicy, USERNAME_TAKEN};
+use crate::{enqueue_message, lookup_member, manages_chat, Message};
+
+const TOKEN_PREFIX: &str = "hook_";
+const TOKEN_LENGTH: usize = 40;
+const MAX_DISPLAY_NAME_LENGTH: usize = 32;
+
+#[derive(Deserialize)]
+pub(crate) struct CreateIncomingWebhookRequest {
+    password: String,
+    botname: String, // Name of the bot account created for the webhook
+}
+
+#[derive(Deserialize)]
+pub(crate) struct DeleteIncomingWebhookRequest {
+    password: String,
+}
+
+#[derive(Serialize)]
+pub(crate) struct NewIncomingWebhook {
+    id: i64,
+    path: String, // "/hooks/<token>", only shown this once
+}
+
+#[derive(Serialize)]
+pub(crate) struct IncomingWebhookInfo {
+    id: i64,
+    botname: String,
+    created_at: String,
+    last_used_at: Option<String>,
+}
+
+/// A file sent inline in a webhook payload
+#[derive(Deserialize)]
+pub(crate) struct InlineAttachment {
+    filename: String,
+    content_type: String,
+    data: String, // Base64 contents
+}
+
+/// Body accepted by /hooks/<token>
+#[derive(Deserialize)]
+pub(crate) struct IncomingPayload {
+    text: String,
+    #[serde(default)]
+    username: Option<String>, // Shown as the message's display name; the author stays the bot
+    #[serde(default)]
+    attachments: Vec<InlineAttachment>,
+}
+
+fn generate_token() -> String {
+    let random: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(TOKEN_LENGTH)
+        .map(char::from)
+        .collect();
+    format!("{}{}", TOKEN_PREFIX, random)
+}
+
+/// Checks the shape of a username override; whether it belongs to a real user is checked separately
+fn check_display_name(name: &str) -> Result<(), String> {
+    let trimmed = name.trim();
+    if trimmed.is_empty() || trimmed.chars().count() > MAX_DISPLAY_NAME_LENGTH {
+        return Err(format!("Username override must be 1 to {} characters long", MAX_DISPLAY_NAME_LENGTH));
+    }
+    if trimmed.chars().any(char::is_control) {
+        return Err(String::from("Username override may not contain control characters"));
+    }
+    if is_reserved(trimmed) {
+        return Err(String::from("Username override is reserved"));
+    }
+    Ok(())
+}
+
+/// Posts a message into the webhook's chat; the token in the URL is the only credential needed
+/// # Query format:
+/// curl -X POST -H "Content-Type: application/json" -d '{"text": "Build #12 passed", "username": "CI"}' "http://98.93.98.244:80/hooks/hook_Token"
+/// Files can be sent inline: '{"text": "Report", "attachments": [{"filename": "log.txt", "content_type": "text/plain", "data": "<base64>"}]}'
+/// # Return format:
+/// The new message's id
+pub(crate) async fn receive(
+    State(pool): State<SqlitePool>,
+    State(attachments): State<Attachments>,
+    Path(token): Path<String>,
+    Json(payload): Json<IncomingPayload>,
+) -> Result<Json<Result<i64, String>>, (StatusCode, String)> {
+    let hash = token_hash(&token);
+    let hook = query!(
+        r#"SELECT incoming_webhooks.id AS "id!", incoming_webhooks.chat_id, incoming_webhooks.user_id, users.username
+        FROM incoming_webhooks JOIN users ON users.id = incoming_webhooks.user_id
+        WHERE incoming_webhooks.token_hash = ? AND incoming_webhooks.revoked_at IS NULL"#,
+        hash
+    ).fetch_optional(&pool).await.unwrap()
+    .ok_or((StatusCode::NOT_FOUND, String::from("Unknown or revoked webhook")))?;
+    let display_name = match payload.username.as_deref().map(str::trim) {
+        Some(name) => {
+            if let Err(e) = check_display_name(name) {
+                return Ok(Json(Err(e)));
+            }
+            // Never let a hook pass itself off as a real account
+            let taken = sqlx::query_scalar!(
+                "SELECT EXISTS(SELECT 1 FROM users WHERE username = ? COLLATE NOCASE AND id != ?) AS _exists",
+                name, hook.user_id
+            ).fetch_one(&pool).await.unwrap();
+            if taken == 1 {
+                return Ok(Json(Err(String::from("Username override matches an existing user"))));
+            }
+            Some(name.to_string())
+        }
+        None => None,
+    };
+    let mut attachment_ids = Vec::new();
+    for inline in payload.attachments {
+        let Ok(bytes) = BASE64.decode(inline.data.as_bytes()) else {
+            return Ok(Json(Err(format!("Attachment {} is not valid base64", inline.filename))));
+        };
+        match attachments::store_upload(
+            &attachments, hook.chat_id, hook.user_id, inline.filename, inline.content_type, bytes, &pool
+        ).await {
+            Ok(info) => attachment_ids.push(info.id),
+            Err(e) => return Ok(Json(Err(e))),
+        }
+    }
+    query!("UPDATE incoming_webhooks SET last_used_at = datetime('now') WHERE id = ?", hook.id)
+        .execute(&pool)
+        .await
+        .unwrap();
+    println!("Incoming webhook {} posting as {}", hook.id, display_name.as_deref().unwrap_or(&hook.username));
+    let msg = Message { content: payload.text, attachments: attachment_ids, deliver_at: None, ttl_seconds: None };
+    Ok(Json(enqueue_message(hook.chat_id, hook.user_id, &msg, display_name.as_deref(), &pool).await))
+}
+
+/// Creates an incoming webhook for a chat, along with the bot account its messages are posted as.
+/// Only the chat's owner and admins may do this
+/// # Query format:
+/// curl -X POST -H "Content-Type: application/json" -d '{"password": "PasswordString", "botname": "ci-hook"}' "http://98.93.98.244:80/createincomingwebhook/chatname/ChatName/username/NameString"
+/// # Return format:
+/// "id" and "path" to POST messages to; the path contains the secret token and is only shown this once
+pub(crate) async fn create(
+    State(pool): State<SqlitePool>,
+    State(limits): State<RateLimits>,
+    State(policy): State<RegistrationPolicy>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path((chatname, username)): Path<(String, String)>,
+    Json(request): Json<CreateIncomingWebhookRequest>,
+) -> Json<Result<NewIncomingWebhook, String>> {
+    if verify_credentials(&username, &request.password, &pool).await.is_none() {
+        limits.lockout.record_failure(&username);
+        return Json(Err(String::from("Incorrect username or password")));
+    }
+    let (chat_id, user_id) = match lookup_member(&chatname, &username, &pool).await {
+        Ok(ids) => ids,
+        Err(e) => return Json(Err(e)),
+    };
+    if !manages_chat(chat_id, user_id, &pool).await {
+        return Json(Err(String::from("Only chat owners and admins can add webhooks")));
+    }
+    if let Err(e) = policy.check_username(&request.botname) {
+        return Json(Err(e));
+    }
+    let token = generate_token();
+    let hash = token_hash(&token);
+    let mut tx = pool.begin().await.unwrap();
+    let bot_id = match query!(
+        r#"INSERT INTO users (username, password, role, created_at, is_bot, bot_owner_id)
+        VALUES (?, '', 'bot', datetime('now'), 1, ?)"#,
+        request.botname, user_id
+    ).execute(&mut *tx).await {
+        Ok(result) => result.last_insert_rowid(),
+        Err(e) if is_username_conflict(&e) => return Json(Err(String::from(USERNAME_TAKEN))),
+        Err(e) => panic!("{}", e),
+    };
+    query!(
+        r#"INSERT INTO chat_users (chat_id, user_id, is_active, joined_at, role)
+        VALUES (?, ?, 1, datetime('now'), 'member')"#,
+        chat_id, bot_id
+    ).execute(&mut *tx).await.unwrap();
+    let id = query!(
+        r#"INSERT INTO incoming_webhooks (chat_id, user_id, token_hash, created_by, created_at)
+        VALUES (?, ?, ?, ?, datetime('now'))"#,
+        chat_id, bot_id, hash, user_id
+    ).execute(&mut *tx).await.unwrap().last_insert_rowid();
+    tx.commit().await.unwrap();
+    let ip = Some(addr.ip());
+    let detail = format!("Incoming webhook {}", id);
+    audit::record(AuditEvent::AccountCreated, Some(user_id), Some(bot_id), None, ip, Some("Incoming webhook bot"), &pool).await;
+    audit::record(AuditEvent::MemberAdded, Some(user_id), Some(bot_id), Some(chat_id), ip, None, &pool).await;
+    audit::record(AuditEvent::WebhookCreated, Some(user_id), Some(bot_id), Some(chat_id), ip, Some(&detail), &pool).await;
+    println!("{} added incoming webhook {} to {}", username, id, chatname);
+    Json(Ok(NewIncomingWebhook { id, path: format!("/hooks/{}", token) }))
+}
+
+/// Revokes an incoming webhook; its URL stops working and its bot leaves the chat
+/// # Query format:
+/// curl -X POST -H "Content-Type: application/json" -d '{"password": "PasswordString"}' "http://98.93.98.244:80/deleteincomingwebhook/webhookid/3/username/NameString"
+pub(crate) async fn delete(
+    State(pool): State<SqlitePool>,
+    State(limits): State<RateLimits>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path((webhook_id, username)): Path<(i64, String)>,
+    Json(request): Json<DeleteIncomingWebhookRequest>,
+) -> Json<Result<String, String>> {
+    let Some((user_id, _)) = verify_credentials(&username, &request.password, &pool).await else {
+        limits.lockout.record_failure(&username);
+        return Json(Err(String::from("Incorrect username or password")));
+    };
+    let Some(hook) = query!(
+        "SELECT chat_id, user_id FROM incoming_webhooks WHERE id = ? AND revoked_at IS NULL",
+        webhook_id
+    ).fetch_optional(&pool).await.unwrap() else {
+        return Json(Err(String::from("Webhook not found")));
+    };
+    if !manages_chat(hook.chat_id, user_id, &pool).await {
+        return Json(Err(String::from("Only chat owners and admins can remove webhooks")));
+    }
+    let mut tx = pool.begin().await.unwrap();
+    query!("UPDATE incoming_webhooks SET revoked_at = datetime('now') WHERE id = ?", webhook_id)
+        .execute(&mut *tx)
+        .await
+        .unwrap();
+    query!("DELETE FROM chat_users WHERE chat_id = ? AND user_id = ?", hook.chat_id, hook.user_id)
+        .execute(&mut *tx)
+        .await
+        .unwrap();
+    tx.commit().await.unwrap();
+    let ip = Some(addr.ip());
+    let detail = format!("Incoming webhook {}", webhook_id);
+    audit::record(AuditEvent::WebhookDeleted, Some(user_id), Some(hook.user_id), Some(hook.chat_id), ip, Some(&detail), &pool).await;
+    audit::record(AuditEvent::MemberRemoved, Some(user_id), Some(hook.user_id), Some(hook.chat_id), ip, Some("Webhook deleted"), &pool).await;
+    println!("{} removed incoming webhook {}", username, webhook_id);
+    Json(Ok(String::from("1")))
+}
+
+/// Lists a chat's active incoming webhooks for its owner and admins
+/// # Query format:
+/// curl "http://98.93.98.244:80/listincomingwebhooks/chatname/ChatName/username/NameString"
+pub(crate) async fn list(
+    State(pool): State<SqlitePool>,
+    Path((chatname, username)): Path<(String, String)>,
+) -> Json<Result<Vec<IncomingWebhookInfo>, String>> {
+    let (chat_id, user_id) = match lookup_member(&chatname, &username, &pool).await {
+        Ok(ids) => ids,
+        Err(e) => return Json(Err(e)),
+    };
+    if !manages_chat(chat_id, user_id, &pool).await {
+        return Json(Err(String::from("Only chat owners and admins can see webhooks")));
+    }
+    let hooks = query!(
+        r#"SELECT incoming_webhooks.id AS "id!", users.username AS botname,
+        incoming_webhooks.created_at AS "created_at!: String", incoming_webhooks.last_used_at AS "last_used_at: String"
+        FROM incoming_webhooks JOIN users ON users.id = incoming_webhooks.user_id
+        WHERE incoming_webhooks.chat_id = ? AND incoming_webhooks.revoked_at IS NULL
+        ORDER BY incoming_webhooks.id"#,
+        chat_id
+    ).fetch_all(&pool).await.unwrap()
+    .into_iter()
+    .map(|row| IncomingWebhookInfo {
+        id: row.id,
+        botname: row.botname,
+        created_at: row.created_at,
+        last_used_at: row.last_used_at,
+    })
+    .collect();
+    Json(Ok(hooks))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_display_name() {
+        assert!(check_display_name("Jenkins CI").is_ok());
+        assert!(check_display_name("   ").is_err());
+        assert!(check_display_name(&"x".repeat(MAX_DISPLAY_NAME_LENGTH + 1)).is_err());
+        assert!(check_display_name("line\nbreak").is_err());
+        assert!(check_display_name("Admin").is_err());
+        assert!(check_display_name("deleted-user-7").is_err());
+    }
+
+    #[test]
+    fn test_payload_defaults() {
+        let payload: IncomingPayload = serde_json::from_str(r#"{"text": "Build passed"}"#).unwrap();
+        assert_eq!(payload.text, "Build passed");
+        assert!(payload.username.is_none());
+        assert!(payload.attachments.is_empty());
+    }
+}
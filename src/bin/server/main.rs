@@ -0,0 +1,841 @@
+use axum::{
+    extract::Path, extract::FromRef, extract::DefaultBodyLimit, extract::ConnectInfo, middleware, response::Json, routing::get, routing::post, Router, extract::State,
+};
+use axum_extra::extract::Query;
+use serde::{Deserialize, Serialize};
+use argon2::{
+    password_hash::{
+        PasswordHash, PasswordVerifier,
+    },
+    Argon2
+};use sqlx::{query, SqlitePool};
+use tokio::sync::broadcast;
+
+mod accounts;
+mod archive;
+mod attachments;
+mod audit;
+mod blocks;
+mod bots;
+mod chat_metadata;
+mod commands;
+mod directory;
+mod events;
+mod filters;
+mod incoming_webhooks;
+mod mentions;
+mod moderation;
+mod pins;
+mod preferences;
+mod profiles;
+mod ratelimit;
+mod reactions;
+mod receipts;
+mod registration;
+mod reports;
+mod retention;
+mod scheduled;
+mod totp;
+mod typing;
+mod webhooks;
+
+use attachments::{AttachmentInfo, Attachments};
+use audit::AuditEvent;
+use chat_metadata::ChatMetadata;
+use commands::{CommandContext, CommandRegistry, Outcome};
+use events::{publish, ChatEvent, EventSender, Presence};
+use filters::FilterChain;
+use ratelimit::RateLimits;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use reactions::ReactionSummary;
+use registration::RegistrationPolicy;
+use preferences::ChatPreferences;
+use retention::RetentionPolicy;
+use totp::SecondFactor;
+use typing::TypingTracker;
+use webhooks::WebhookEvent;
+
+// Things the central sever processor needs to handle:
+//    User prescence: Whether a user is currently online or not
+//    User authentication & account login
+//    "Ground truth" chat history: keeps a central state of the chat history that users pull from when they login
+//    Message queue: Incoming message requests are added to a queue processed and updated to everyone's chats one at a time to prevent conflict
+//    Chatroom management: Json 
+// TODO: Return 0 and 1 instea of string
+#[derive(Deserialize)]
+struct Message{
+    content: String,
+    #[serde(default)]
+    attachments: Vec<i64>, // Ids returned by /upload
+    #[serde(default)]
+    deliver_at: Option<String>, // Posts the message later instead, see scheduled::parse_deliver_at
+    #[serde(default)]
+    ttl_seconds: Option<i64>, // Deletes the message this long after it is posted
+}
+#[derive(Deserialize, Serialize)]
+struct ChatInfo{
+    id: String,
+    users: Vec<String>,
+    unread: i64, // Messages from others newer than the user's read position
+    #[serde(flatten)]
+    metadata: ChatMetadata,
+    #[serde(default)]
+    pins: Vec<i64>, // Pinned message ids, most recently pinned first
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    retention: Option<RetentionPolicy>, // None when the chat keeps messages forever
+    #[serde(default)]
+    preferences: ChatPreferences, // The requesting user's own settings for the chat
+}
+#[derive(Deserialize, Serialize, Clone)]
+struct ChatHistoryMessage{
+    #[serde(default)]
+    id: i64, // messages.id, 0 for entries cached before ids were recorded
+    username: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    display_name: Option<String>, // Author's current display name filled in per request, or the name an incoming webhook posted under
+    content: String,
+    created_at: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    reactions: Vec<ReactionSummary>, // Filled in per request, never stored in the cache
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    seen_by: Option<Vec<String>>, // Filled in per request for small chats only
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    attachments: Vec<AttachmentInfo>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    bot: bool, // Posted by a bot account
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<String>, // When a self-destructing message will be deleted (UTC)
+}
+#[derive(Deserialize)]
+struct CreateChatParams {
+    name: String,
+    user: Vec<String>, // ?user=alice&user=bob → vec!["alice", "bob"]
+    #[serde(default)]
+    visibility: directory::Visibility, // Chats are private unless "public" is given
+}
+#[derive(Deserialize, Serialize)]
+struct MessageStatus{
+    status: String,
+    rejection_reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    command_output: Option<String>, // Private reply to a slash command
+}
+#[derive(Deserialize)]
+struct HistoryParams {
+    username: Option<String>, // Requesting user, used for "reacted_by_me" flags
+}
+#[derive(Deserialize)]
+struct ListChatsParams {
+    #[serde(default)]
+    archived: bool, // Also list archived chats
+}
+#[derive(Deserialize)]
+struct LoginParams {
+    code: Option<String>, // Two-factor code, only needed once two-factor authentication is enabled
+}
+/// Shared state handed to every route; handlers extract only the parts they need
+#[derive(Clone, FromRef)]
+struct AppState {
+    pool: SqlitePool,
+    events: EventSender,
+    typing: TypingTracker,
+    presence: Presence,
+    attachments: Attachments,
+    limits: RateLimits,
+    registration: RegistrationPolicy,
+    filters: Arc<FilterChain>,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), sqlx::Error>{
+    const NUM_THREADS:i32 = 1;
+    dotenv::dotenv().ok();
+    let pool = SqlitePool::connect("sqlite:chat.db").await?;
+    sqlx::query("PRAGMA foreign_keys = ON;")
+    .execute(&pool)
+    .await?;
+    let (events, _) = broadcast::channel(events::EVENT_BUFFER);
+    let presence = Presence::default();
+    let filters = Arc::new(FilterChain::from_env());
+    let commands = Arc::new(CommandRegistry::builtin());
+    let attachments = Attachments::from_env();
+    // Multipart framing adds a little on top of the file itself
+    let upload_limit = attachments.max_bytes() + 64 * 1024;
+    // Webhook payloads carry files as base64, a third larger than the files themselves
+    let inline_upload_limit = attachments.max_bytes() * 4 / 3 + 64 * 1024;
+    let mut thread_handlers = Vec::new();
+    for _i in 0..NUM_THREADS{
+        let thread_pool = pool.clone();
+        let thread_events = events.clone();
+        let thread_presence = presence.clone();
+        let thread_filters = filters.clone();
+        let thread_commands = commands.clone();
+        thread_handlers.push(tokio::spawn(async move {
+            message_thread(thread_pool, thread_events, thread_presence, thread_filters, thread_commands).await;
+        }));
+    }
+    tokio::spawn(webhooks::delivery_thread(pool.clone()));
+    tokio::spawn(retention::janitor_thread(pool.clone(), attachments.clone(), events.clone()));
+    
+    let state = AppState {
+        pool: pool.clone(), events, typing: TypingTracker::default(), presence, attachments,
+        limits: RateLimits::from_env(), registration: RegistrationPolicy::from_env(), filters,
+    };
+    let app = Router::new()
+        .route("/", get(root))
+        .route("/Authenticate/username/{name}/password/{pass}",
+            get(login).layer(middleware::from_fn_with_state(state.clone(), ratelimit::limit_login)))
+        .route("/createaccount/username/{name}/password/{pass}",
+            get(registration::new_user).layer(middleware::from_fn_with_state(state.clone(), ratelimit::limit_create_account)))
+        .route("/totp/enroll/username/{name}",
+            post(totp::enroll).layer(middleware::from_fn_with_state(state.clone(), ratelimit::limit_login)))
+        .route("/totp/confirm/username/{name}",
+            post(totp::confirm).layer(middleware::from_fn_with_state(state.clone(), ratelimit::limit_login)))
+        .route("/totp/disable/username/{name}",
+            post(totp::disable).layer(middleware::from_fn_with_state(state.clone(), ratelimit::limit_login)))
+        .route("/createbot/username/{name}",
+            post(bots::create_bot).layer(middleware::from_fn_with_state(state.clone(), ratelimit::limit_login)))
+        .route("/createtoken/username/{name}",
+            post(bots::create_token).layer(middleware::from_fn_with_state(state.clone(), ratelimit::limit_login)))
+        .route("/revoketoken/username/{name}",
+            post(bots::revoke_token).layer(middleware::from_fn_with_state(state.clone(), ratelimit::limit_login)))
+        .route("/listtokens/username/{name}", get(bots::list_tokens))
+        .route("/bot/newmessage/chatname/{chat}",
+            post(bots::bot_message).layer(middleware::from_fn_with_state(state.clone(), ratelimit::limit_message)))
+        .route("/bot/getchat/chatname/{chat}", get(bots::bot_history))
+        .route("/createwebhook/chatname/{chat}/username/{name}",
+            post(webhooks::create_webhook).layer(middleware::from_fn_with_state(state.clone(), ratelimit::limit_login)))
+        .route("/deletewebhook/webhookid/{id}/username/{name}",
+            post(webhooks::delete_webhook).layer(middleware::from_fn_with_state(state.clone(), ratelimit::limit_login)))
+        .route("/listwebhooks/chatname/{chat}/username/{user}", get(webhooks::list_webhooks))
+        .route("/webhookdeliveries/webhookid/{id}/username/{user}", get(webhooks::delivery_log))
+        .route("/createincomingwebhook/chatname/{chat}/username/{name}",
+            post(incoming_webhooks::create).layer(middleware::from_fn_with_state(state.clone(), ratelimit::limit_login)))
+        .route("/deleteincomingwebhook/webhookid/{id}/username/{name}",
+            post(incoming_webhooks::delete).layer(middleware::from_fn_with_state(state.clone(), ratelimit::limit_login)))
+        .route("/listincomingwebhooks/chatname/{chat}/username/{user}", get(incoming_webhooks::list))
+        .route("/hooks/{token}",
+            post(incoming_webhooks::receive)
+                .layer(DefaultBodyLimit::max(inline_upload_limit))
+                .layer(middleware::from_fn_with_state(state.clone(), ratelimit::limit_message)))
+        .route("/createinvite/admin/{name}",
+            post(registration::create_invite).layer(middleware::from_fn_with_state(state.clone(), ratelimit::limit_login)))
+        .route("/changepassword/username/{name}",
+            post(accounts::change_password).layer(middleware::from_fn_with_state(state.clone(), ratelimit::limit_login)))
+        .route("/issuereset/admin/{name}/username/{user}",
+            post(accounts::issue_reset).layer(middleware::from_fn_with_state(state.clone(), ratelimit::limit_login)))
+        .route("/resetpassword/username/{name}",
+            post(accounts::reset_password).layer(middleware::from_fn_with_state(state.clone(), ratelimit::limit_login)))
+        .route("/deleteaccount/username/{name}",
+            post(accounts::delete_account).layer(middleware::from_fn_with_state(state.clone(), ratelimit::limit_login)))
+        .route("/createchat", get(new_chat))
+        .route("/newmessage/chatname/{chat}/username/{user}",
+            post(incoming_message).layer(middleware::from_fn_with_state(state.clone(), ratelimit::limit_message)))
+        .route("/messagestatus/messageid/{id}/username/{user}", get(message_status))
+        .route("/scheduled/username/{user}", get(scheduled::list_scheduled))
+        .route("/updatechat/chatname/{chat}/username/{user}", post(chat_metadata::update_chat))
+        .route("/chatdetails/chatname/{chat}/username/{user}", get(chat_metadata::chat_details))
+        .route("/pin/chatname/{chat}/username/{user}/messageid/{id}",
+            post(pins::pin_message).delete(pins::unpin_message))
+        .route("/pins/chatname/{chat}/username/{user}", get(pins::list_pins))
+        .route("/chatpreferences/chatname/{chat}/username/{user}",
+            get(preferences::get_preferences).post(preferences::update_preferences))
+        .route("/directory", get(directory::list_directory))
+        .route("/join/chatname/{chat}/username/{user}", post(directory::join_chat))
+        .route("/leave/chatname/{chat}/username/{user}", post(directory::leave_chat))
+        .route("/retention/chatname/{chat}/username/{name}",
+            post(retention::set_retention).layer(middleware::from_fn_with_state(state.clone(), ratelimit::limit_login)))
+        .route("/editscheduled/messageid/{id}/username/{user}", post(scheduled::edit_scheduled))
+        .route("/cancelscheduled/messageid/{id}/username/{user}", post(scheduled::cancel_scheduled))
+        .route("/getchat/chatname/{chat}", get(get_message_history))
+        .route("/checkuser/username/{name}", get(check_user_route))
+        .route("/block/username/{user}/target/{target}", post(blocks::block_user).delete(blocks::unblock_user))
+        .route("/blocks/username/{user}", get(blocks::list_blocks))
+        .route("/profile/username/{name}", get(profiles::get_profile).post(profiles::update_profile))
+        .route("/profile/avatar/username/{name}",
+            post(profiles::upload_avatar).delete(profiles::remove_avatar).layer(DefaultBodyLimit::max(upload_limit)))
+        .route("/avatar/username/{name}", get(profiles::download_avatar))
+        .route("/listchats/username/{name}", get(list_chats))
+        .route("/deletechat/username/{username}/chatname/{chatname}", get(archive::delete_chat))
+        .route("/restorechat/chatname/{chat}/username/{user}", post(archive::restore_chat))
+        .route("/deletedchats/username/{user}", get(archive::list_deleted))
+        .route("/archivechat/chatname/{chat}/username/{user}", post(archive::archive_chat))
+        .route("/unarchivechat/chatname/{chat}/username/{user}", post(archive::unarchive_chat))
+        .route("/purgechat/chatname/{chat}/admin/{name}",
+            post(archive::purge_chat_route).layer(middleware::from_fn_with_state(state.clone(), ratelimit::limit_login)))
+        .route("/moderate/chatname/{chat}/username/{name}",
+            post(moderation::moderate).layer(middleware::from_fn_with_state(state.clone(), ratelimit::limit_login)))
+        .route("/globalban/username/{name}",
+            post(moderation::global_ban).layer(middleware::from_fn_with_state(state.clone(), ratelimit::limit_login)))
+        .route("/moderationlog/chatname/{chat}/username/{name}",
+            post(moderation::chat_log).layer(middleware::from_fn_with_state(state.clone(), ratelimit::limit_login)))
+        .route("/moderationlog/username/{name}",
+            post(moderation::global_log).layer(middleware::from_fn_with_state(state.clone(), ratelimit::limit_login)))
+        .route("/report/messageid/{id}/username/{user}", post(reports::report_message))
+        .route("/reports/username/{name}",
+            post(reports::list_reports).layer(middleware::from_fn_with_state(state.clone(), ratelimit::limit_login)))
+        .route("/claimreport/reportid/{id}/username/{name}",
+            post(reports::claim_report).layer(middleware::from_fn_with_state(state.clone(), ratelimit::limit_login)))
+        .route("/resolvereport/reportid/{id}/username/{name}",
+            post(reports::resolve_report).layer(middleware::from_fn_with_state(state.clone(), ratelimit::limit_login)))
+        .route("/auditlog/admin/{name}",
+            post(audit::query_log).layer(middleware::from_fn_with_state(state.clone(), ratelimit::limit_login)))
+        .route("/reaction/chatname/{chat}/username/{user}/messageid/{id}/emoji/{emoji}",
+            post(reactions::add_reaction).delete(reactions::remove_reaction))
+        .route("/events/chatname/{chat}/username/{user}", get(events::subscribe))
+        .route("/markread/chatname/{chat}/username/{user}", post(receipts::mark_read))
+        .route("/typing/chatname/{chat}/username/{user}/state/{state}", post(typing::typing))
+        .route("/mentions/username/{name}", get(mentions::list_mentions))
+        .route("/upload/chatname/{chat}/username/{user}",
+            post(attachments::upload).layer(DefaultBodyLimit::max(upload_limit)))
+        .route("/attachment/attachmentid/{id}/username/{user}", get(attachments::download))
+        .with_state(state);
+    // run our app with hyper, listening globally on port 3000
+    let listener = tokio::net::TcpListener::bind("0.0.0.0:80").await.unwrap();
+    // Client addresses are needed by the rate limiter
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap();
+
+    Ok(())
+    // 
+}
+
+async fn root() -> Json<String>{
+    println!("200");
+    Json(String::from("Root!"))
+}
+
+/// Background thread for message processing tasks, retrieves oldest unprocessed message in the message_queue, processes it, and adds to the chat_history_cache json
+/// TODO: Shared state concurency & synchronization when running multiple message_threads on sqlite database
+async fn message_thread(pool:SqlitePool, events: EventSender, presence: Presence, filters: Arc<FilterChain>, commands: Arc<CommandRegistry>){
+    let limit:i64 = 5;
+    loop {
+        let curr_message = match query!(
+            r#"SELECT id, message_id, deliver_at IS NOT NULL AS "scheduled!: bool" FROM message_queue
+            WHERE status = 'Queued' AND direction = 'inbound' AND (deliver_at IS NULL OR deliver_at <= datetime('now'))
+            ORDER BY COALESCE(deliver_at, queued_at) ASC LIMIT ?"#, limit)
+            .fetch_optional(&pool)
+            .await
+            .unwrap() {
+            Some(msg) => msg,
+            None => {tokio::time::sleep(std::time::Duration::from_secs(1)).await; continue;},
+        };
+        let message_stuff = query!(
+            r#"SELECT content, chat_id, user_id, display_name, expires_at AS "expires_at: String" FROM messages WHERE id = ?"#, curr_message.message_id).
+            fetch_one(&pool).await.unwrap();
+        let author = query!(
+            r#"SELECT username, display_name, COALESCE(is_bot, 0) AS "is_bot!: bool" FROM users WHERE id = ?"#,
+            message_stuff.user_id
+        ).fetch_one(&pool).await.unwrap();
+        let username = author.username;
+        let chat_id = message_stuff.chat_id;
+        // The chat may have been archived or deleted while the message waited in the queue
+        if let Err(reason) = archive::check_writable(chat_id, &pool).await {
+            reject_message(curr_message.id, curr_message.message_id, chat_id, username, reason, &events, &pool).await;
+            continue;
+        }
+        if curr_message.scheduled {
+            // The author may have left, been muted or been banned since scheduling the message
+            let blocked = if !is_chat_member(chat_id, message_stuff.user_id, &pool).await {
+                Some(String::from("You are no longer a member of this chat"))
+            } else {
+                moderation::check_can_post(chat_id, message_stuff.user_id, &pool).await.err()
+            };
+            if let Some(reason) = blocked {
+                reject_message(curr_message.id, curr_message.message_id, chat_id, username, reason, &events, &pool).await;
+                continue;
+            }
+            query!("UPDATE messages SET created_at = datetime('now') WHERE id = ?", curr_message.message_id)
+                .execute(&pool).await.unwrap();
+        }
+        // Slash commands run instead of being posted; some post an announcement in their place
+        let text = match commands::parse(&message_stuff.content) {
+            commands::Input::Text(text) => text,
+            commands::Input::Command { name, args } => {
+                let ctx = CommandContext {
+                    pool: pool.clone(),
+                    filters: filters.clone(),
+                    chat_id,
+                    user_id: message_stuff.user_id,
+                    username: username.clone(),
+                    role: chat_role(chat_id, message_stuff.user_id, &pool).await.unwrap_or_default(),
+                };
+                println!("Running /{} for {}", name, username);
+                match commands.run(ctx, &name, args).await {
+                    Ok(Outcome::Post(text)) => text,
+                    Ok(Outcome::Reply(output)) => {
+                        query!(
+                            "UPDATE messages SET status = ?, command_output = ? WHERE id = ?",
+                            "Command",
+                            output,
+                            curr_message.message_id
+                        ).execute(&pool).await.unwrap();
+                        query!(
+                            "UPDATE message_queue SET status = ? WHERE id = ?",
+                            "Finished",
+                            curr_message.id,
+                        ).execute(&pool)
+                        .await.unwrap();
+                        publish(&events, ChatEvent::CommandOutput { chat_id, username, message_id: curr_message.message_id, output });
+                        continue;
+                    }
+                    Err(reason) => {
+                        reject_message(curr_message.id, curr_message.message_id, chat_id, username, reason, &events, &pool).await;
+                        continue;
+                    }
+                }
+            }
+        };
+        // Moderation: each configured filter may rewrite the message (e.g. masking words) or reject it
+        let message_content = match filters.apply(text) {
+            Ok(content) => content,
+            Err(reason) => {
+                reject_message(curr_message.id, curr_message.message_id, chat_id, username, reason, &events, &pool).await;
+                continue;
+            }
+        };
+        if message_content != message_stuff.content {
+            query!("UPDATE messages SET content = ? WHERE id = ?", message_content, curr_message.message_id)
+                .execute(&pool).await.unwrap();
+        }
+        let json_message_history = query!(
+            "SELECT message_history FROM chat_history_cache WHERE chat_id = ?", chat_id).
+            fetch_one(&pool).await.unwrap().message_history;
+        if let Some(json_string) = json_message_history {
+            let mut messages: Vec<ChatHistoryMessage> = serde_json::from_str(&json_string).unwrap();
+            // Messages posted under another name (incoming webhooks) show it in place of the account's display name
+            let display_name = message_stuff.display_name.or(author.display_name);
+            let message = ChatHistoryMessage{
+                id: curr_message.message_id,
+                username,
+                display_name,
+                content: message_content,
+                created_at: chrono::Utc::now().to_rfc3339(),
+                reactions: Vec::new(),
+                seen_by: None,
+                attachments: attachments::message_attachments(curr_message.message_id, &pool).await,
+                bot: author.is_bot,
+                expires_at: message_stuff.expires_at,
+            };
+            messages.push(message.clone());
+            let json_history = serde_json::to_string(&messages).unwrap();
+            query!(
+                "UPDATE chat_history_cache SET message_history = ? WHERE chat_id = ?",
+                json_history,
+                chat_id
+            ).execute(&pool)
+            .await.unwrap();
+            let mentioned = mentions::record_mentions(
+                curr_message.message_id, chat_id, message_stuff.user_id, &message.content, &presence, &pool
+            ).await;
+            if mentioned > 0 {
+                println!("Recorded {} mentions", mentioned);
+            }
+            publish(&events, ChatEvent::Message { chat_id, message });
+        }
+        println!("Updated cache history");
+        query!(
+            "UPDATE message_queue SET status = ? WHERE id = ?",
+            "Finished",
+            curr_message.id,
+        ).execute(&pool)
+        .await.unwrap();
+        query!(
+            "UPDATE messages SET status = ? WHERE id = ?",
+            "Sent!",
+            curr_message.message_id
+        ).execute(&pool).await.unwrap();
+        webhooks::queue_event(chat_id, curr_message.message_id, WebhookEvent::MessageSent, &pool).await;
+    }
+}
+/// Marks a message rejected, by moderation or a failed command, and tells only its author why
+async fn reject_message(
+    queue_id: i64, message_id: i64, chat_id: i64, username: String, reason: String,
+    events: &EventSender, pool: &SqlitePool,
+) {
+    println!("Rejected message {}: {}", message_id, reason);
+    query!(
+        "UPDATE messages SET status = ?, rejection_reason = ? WHERE id = ?",
+        "Rejected",
+        reason,
+        message_id
+    ).execute(pool).await.unwrap();
+    query!(
+        "UPDATE message_queue SET status = ? WHERE id = ?",
+        "Rejected",
+        queue_id,
+    ).execute(pool)
+    .await.unwrap();
+    publish(events, ChatEvent::Rejected { chat_id, username, message_id, reason });
+}
+/// Retrieves chat history given chatname
+/// # Query format:
+/// curl "http://98.93.98.244:80/getchat/chatname/ChatName?username=UsernameString" 
+/// # Return format:
+/// Array of ChatHistoryMessage datatypes, each containing "id", "username", "display_name" (if the author set one), "content", and "created_at" headers,
+/// plus "reactions" (emoji, count, reacted_by_me) for messages that have any and, in chats of up to 10 members,
+/// "seen_by" listing the members who have read each message
+async fn get_message_history(
+    Path(chatname):Path<String>, State(pool): State<SqlitePool>,
+    Query(params): Query<HistoryParams>)->Result<Json<Vec<ChatHistoryMessage>>, ()>{
+    let Some(chat) = query!(
+        "SELECT id FROM chats WHERE name = ? AND deleted_at IS NULL", chatname).
+        fetch_optional(&pool).await.unwrap() else {
+        return Err(());
+    };
+    match load_history(chat.id, params.username.as_deref(), &pool).await {
+        Some(messages) => Ok(Json(messages)),
+        None => Err(()),
+    }
+}
+/// Cached history of a chat with reactions (flagged for the viewer), "seen by" lists and the authors'
+/// current display names filled in. Messages from users the viewer blocked are left out
+async fn load_history(chat_id: i64, viewer: Option<&str>, pool: &SqlitePool) -> Option<Vec<ChatHistoryMessage>> {
+    let json_string = query!(
+        "SELECT message_history FROM chat_history_cache WHERE chat_id = ?", chat_id).
+        fetch_one(pool).await.unwrap().message_history?;
+    let mut messages: Vec<ChatHistoryMessage> = serde_json::from_str(&json_string).unwrap();
+    if let Some(viewer) = viewer {
+        let blocked = blocks::blocked_usernames(viewer, pool).await;
+        messages.retain(|m| !blocked.contains(&m.username));
+    }
+    let mut reactions = reactions::reaction_summaries(chat_id, viewer, pool).await;
+    let read_positions = receipts::read_positions(chat_id, pool).await;
+    let mut authors: Vec<&str> = messages.iter().map(|m| m.username.as_str()).collect();
+    authors.sort_unstable();
+    authors.dedup();
+    let display_names = profiles::display_names(&authors, pool).await;
+    for message in messages.iter_mut() {
+        // Bots have no profile, so a bot message's cached name is the one its webhook posted under
+        let posted_as = message.display_name.take().filter(|_| message.bot);
+        message.display_name = display_names.get(&message.username).cloned().or(posted_as);
+        if let Some(summary) = reactions.remove(&message.id) {
+            message.reactions = summary;
+        }
+        if let Some(positions) = &read_positions {
+            message.seen_by = Some(receipts::seen_by(message.id, &message.username, positions));
+        }
+    }
+    Some(messages)
+}
+/// Queues incoming messages from users; Messages are added to priority queue (by time created) in sql database and processed by background threads
+/// # Query format:
+/// curl -X POST \ -H "Content-Type: application/json" \ -d '{"content": "Message here :)"}' \ 'http://98.93.98.244:80/newmessage/chatname/ChatName/username/UsernameString'
+/// Files uploaded through /upload are attached by listing their ids: '{"content": "Look!", "attachments": [7]}'
+/// A message can be scheduled with a UTC delivery time: '{"content": "Standup!", "deliver_at": "2025-06-01 09:00"}'
+/// and made to self-destruct some seconds after it is posted: '{"content": "Secret", "ttl_seconds": 60}'
+/// # Return format:
+/// The new message's id, which the author can pass to /messagestatus to see whether it was posted or rejected
+async fn incoming_message(
+    Path((chatname, username)):Path<(String,String)>,
+    State(pool): State<SqlitePool>,
+    Json(msg): Json<Message>,
+) -> Json<Result<i64, String>> {
+    println!("New message from {} in chat {}: {}", username, chatname, msg.content);
+    let Some(chat) = query!("SELECT id FROM chats WHERE name = ? AND deleted_at IS NULL", chatname)
+        .fetch_optional(&pool)
+        .await
+        .unwrap() else {
+        return Json(Err(String::from("Chat not found")));
+    };
+    let user_id = query!(r#"SELECT id AS "id!" FROM users WHERE username = ?"#, username)
+            .fetch_one(&pool)
+            .await.unwrap().id;     
+    Json(enqueue_message(chat.id, user_id, &msg, None, &pool).await)
+}
+/// Stores a new message and queues it for the message threads, returning its id.
+/// A display name replaces the author's username in history (used by incoming webhooks).
+/// Archived and deleted chats take no messages, and banned or muted users are refused until the
+/// ban or mute runs out (see moderation). Messages with a delivery time
+/// stay "Scheduled" in the queue until it passes; a time-to-live counts from the delivery time
+async fn enqueue_message(chat_id: i64, user_id: i64, msg: &Message, display_name: Option<&str>, pool: &SqlitePool) -> Result<i64, String> {
+    archive::check_writable(chat_id, pool).await?;
+    moderation::check_can_post(chat_id, user_id, pool).await?;
+    let deliver_at = match &msg.deliver_at {
+        Some(time) => Some(scheduled::parse_deliver_at(time, chrono::Utc::now())?),
+        None => None,
+    };
+    if let Some(ttl) = msg.ttl_seconds {
+        retention::check_ttl(ttl)?;
+    }
+    attachments::check_pending(&msg.attachments, chat_id, user_id, pool).await?;
+    let status = String::from(if deliver_at.is_some() { "Scheduled" } else { "Processing" });
+    let ttl_modifier = msg.ttl_seconds.map(|ttl| format!("+{} seconds", ttl));
+    let result = query!(
+        r#"INSERT INTO messages (chat_id, user_id, content, created_at, status, display_name, expires_at, ttl_seconds)
+        VALUES (?, ?, ?, datetime('now'), ?, ?, datetime(COALESCE(?, datetime('now')), ?), ?)"#,
+        chat_id,
+        user_id,
+        msg.content, 
+        status,
+        display_name,
+        deliver_at,
+        ttl_modifier,
+        msg.ttl_seconds
+    ).execute(pool).await.unwrap();
+    println!("Processing");
+    let message_id = result.last_insert_rowid();
+    attachments::link(&msg.attachments, message_id, pool).await;
+    let status = String::from("Queued");
+    let direction: String = String::from("inbound"); // Messages going to server for processing
+    query!(
+        "INSERT INTO message_queue (message_id, direction, queued_at, processed_at, status, deliver_at) VALUES (?, ?, datetime('now'), NULL, ?, ?)",
+        message_id,
+        direction, 
+        status,
+        deliver_at
+    ).execute(pool).await.unwrap();
+    println!("Queued!");
+    Ok(message_id)
+}
+/// Shows the author the delivery status of one of their messages, including why moderation rejected it
+/// # Query format:
+/// curl "http://98.93.98.244:80/messagestatus/messageid/42/username/UsernameString"
+/// # Return format:
+/// "status" (Scheduled, Processing, Sent!, Rejected, Command, Cancelled), "rejection_reason" and, for slash commands that
+/// reply privately (e.g. /help), "command_output"
+async fn message_status(State(pool): State<SqlitePool>, Path((message_id, username)): Path<(i64, String)>) -> Json<Result<MessageStatus, String>>{
+    let row = query!(
+        r#"SELECT messages.status, messages.rejection_reason, messages.command_output FROM messages
+        JOIN users ON users.id = messages.user_id
+        WHERE messages.id = ? AND users.username = ?"#,
+        message_id, username
+    ).fetch_optional(&pool).await.unwrap();
+    match row {
+        Some(row) => Json(Ok(MessageStatus{
+            status: row.status.unwrap_or_default(),
+            rejection_reason: row.rejection_reason,
+            command_output: row.command_output,
+        })),
+        None => Json(Err(String::from("Message not found"))),
+    }
+}
+/// Creates new chat; Chats are connected to users through bipartite graph, one side being the chats the other being the users
+/// The first listed user becomes the chat's owner, everyone else joins as a member
+/// # Query format:
+/// curl "http://98.93.98.244:80/createchat?name=ChatName&user=username1&user=username2&user=username3..."
+/// Add "&visibility=public" to list the chat in the directory for anyone to join.
+/// A chat of two users cannot be created if either has blocked the other
+async fn new_chat(State(pool): State<SqlitePool>, ConnectInfo(addr): ConnectInfo<SocketAddr>,
+Query(params): Query<CreateChatParams>) -> Json<Result<String, String>>{
+    let chat_name = &params.name;
+    let users = &params.user;
+    for user in users{
+        println!("{}", user);
+    }
+    let created_by = match users.first() {
+        Some(first) => query!(r#"SELECT id AS "id!" FROM users WHERE username = ?"#, first)
+            .fetch_optional(&pool).await.unwrap().map(|row| row.id),
+        None => None,
+    };
+    // A chat of exactly two people is a direct chat, which a block in either direction prevents
+    if let (Some(creator_id), [_, other]) = (created_by, users.as_slice()) {
+        let Some(other_id) = query!(r#"SELECT id AS "id!" FROM users WHERE username = ?"#, other)
+            .fetch_optional(&pool).await.unwrap().map(|row| row.id) else {
+            return Json(Err(String::from("User not found")));
+        };
+        if let Err(e) = blocks::check_direct_chat(creator_id, other_id, other, &pool).await {
+            return Json(Err(e));
+        }
+    }
+    let visibility = params.visibility.as_str();
+    // A deleted chat may still carry the name, so the new chat is looked up by its row id
+    let chat_id = query!(
+        r#"INSERT INTO chats (name, created_at, created_by, visibility)
+        VALUES (?, datetime('now'), ?, ?)"#, chat_name, created_by, visibility
+        ).execute(&pool).await.unwrap().last_insert_rowid();
+    let chat_history: Vec<ChatHistoryMessage> = Vec::new();
+    let json_history = serde_json::to_string(&chat_history).unwrap();
+    query!(
+        r#"INSERT INTO chat_history_cache (chat_id, message_history, updated_at)
+        VALUES (?, ?, datetime('now'))"#, chat_id, json_history
+        ).execute(&pool).await.unwrap();
+    audit::record(AuditEvent::ChatCreated, created_by, None, Some(chat_id), Some(addr.ip()), Some(visibility), &pool).await;
+    for (i, user) in users.iter().enumerate(){
+        let user_id = query!("SELECT id FROM users WHERE username = ?", user)
+            .fetch_one(&pool)
+            .await.unwrap().id;        
+        let role = if i == 0 { "owner" } else { "member" };
+        query!(
+            r#"INSERT INTO chat_users (chat_id, user_id, is_active, joined_at, role)
+            VALUES (?, ?, 1, datetime('now'), ?)"#, chat_id, user_id, role
+            ).execute(&pool).await.unwrap();
+        if i > 0 {
+            audit::record(AuditEvent::MemberAdded, created_by, user_id, Some(chat_id), Some(addr.ip()), None, &pool).await;
+        }
+    }
+    Json(Ok(String::from("1")))
+}
+/// Checks for existing user, ignoring letter case:
+async fn check_user_exist(username: String, pool : SqlitePool)->Result<i64, sqlx::Error> {
+    let exists = sqlx::query_scalar!(
+        "SELECT EXISTS(SELECT 1 FROM users WHERE username = ? COLLATE NOCASE) AS _exists",
+        username
+    )
+    .fetch_one(&pool)
+    .await?;
+    Ok(exists)
+}
+/// Checks whether a user belongs to a chat
+async fn is_chat_member(chat_id: i64, user_id: i64, pool: &SqlitePool) -> bool {
+    let exists = sqlx::query_scalar!(
+        "SELECT EXISTS(SELECT 1 FROM chat_users WHERE user_id = ? AND chat_id = ?) AS _exists",
+        user_id, chat_id
+    ).fetch_one(pool).await.unwrap();
+    exists == 1
+}
+/// Returns the user's role in a chat (owner, admin or member), None if they are not a member
+async fn chat_role(chat_id: i64, user_id: i64, pool: &SqlitePool) -> Option<String> {
+    query!(
+        "SELECT role FROM chat_users WHERE chat_id = ? AND user_id = ?",
+        chat_id, user_id
+    ).fetch_optional(pool).await.unwrap()
+    .map(|row| row.role.unwrap_or_else(|| String::from("member")))
+}
+/// True if the user is an owner or admin of the chat
+async fn manages_chat(chat_id: i64, user_id: i64, pool: &SqlitePool) -> bool {
+    matches!(chat_role(chat_id, user_id, pool).await.as_deref(), Some("owner" | "admin"))
+}
+/// Looks up chat and user ids by name, failing unless the user is a member of the chat. Deleted chats are not found
+async fn lookup_member(chatname: &str, username: &str, pool: &SqlitePool) -> Result<(i64, i64), String> {
+    let chat_id = match query!("SELECT id FROM chats WHERE name = ? AND deleted_at IS NULL", chatname)
+        .fetch_optional(pool)
+        .await
+        .unwrap() {
+        Some(row) => row.id,
+        None => return Err(String::from("Chat not found")),
+    };
+    let user_id = match query!(r#"SELECT id AS "id!" FROM users WHERE username = ?"#, username)
+        .fetch_optional(pool)
+        .await
+        .unwrap() {
+        Some(row) => row.id,
+        None => return Err(String::from("User not found")),
+    };
+    if !is_chat_member(chat_id, user_id, pool).await {
+        return Err(String::from("User is not a member of this chat"));
+    }
+    Ok((chat_id, user_id))
+}
+/// Routing function for checking for existing user
+async fn check_user_route(State(pool): State<SqlitePool>, Path(username):Path<String>)->Json<Result<String, String>>{
+    Json(Ok(check_user_exist(username.clone(), pool.clone()).await.unwrap().to_string()))
+}
+/// Authenticates user login; repeated wrong passwords lock the account for a while (see ratelimit::LoginLockout).
+/// Users with two-factor authentication also send a code from their authenticator app or a recovery code.
+/// Banned accounts get an error once the password checks out. Every outcome but "2" goes to the audit log
+/// # Query format:
+/// curl "http://98.93.98.244:80/Authenticate/username/NameString/password/PasswordString?code=123456"
+/// # Return format:
+/// "1" when logged in, "0" for a wrong password or code, "2" when the password is right but a code is needed
+async fn login(State(pool): State<SqlitePool>, State(limits): State<RateLimits>, ConnectInfo(addr): ConnectInfo<SocketAddr>, Path((username, password)): Path<(String,String)>, Query(params): Query<LoginParams>) -> Json<Result<String, String>>{
+    let ip = Some(addr.ip());
+    let row = sqlx::query!(
+        r#"SELECT id AS "id!", password FROM users WHERE username = ?"#,
+        username
+    ).fetch_optional(&pool) // returns Option
+    .await.unwrap(); 
+    println!("Login user {}", username);
+    if let Some(row) = row.as_ref() {
+        match PasswordHash::new(&row.password) {
+            Ok(parsed_hash) => {
+                if Argon2::default()
+                    .verify_password(password.as_bytes(), &parsed_hash)
+                    .is_ok()
+                {
+                    if let Err(e) = moderation::check_account(row.id, &pool).await {
+                        audit::record(AuditEvent::LoginFailed, None, Some(row.id), None, ip, Some("Account is banned"), &pool).await;
+                        return Json(Err(e));
+                    }
+                    match totp::second_factor(row.id, params.code.as_deref(), &pool).await {
+                        SecondFactor::NotEnabled | SecondFactor::Passed => {
+                            limits.lockout.record_success(&username);
+                            audit::record(AuditEvent::LoginSucceeded, Some(row.id), Some(row.id), None, ip, None, &pool).await;
+                            Json(Ok(String::from("1")))
+                        }
+                        SecondFactor::Required => Json(Ok(String::from("2"))),
+                        SecondFactor::Failed => {
+                            if limits.lockout.record_failure(&username) {
+                                println!("Too many failed logins, locking {}", username);
+                            }
+                            audit::record(AuditEvent::LoginFailed, None, Some(row.id), None, ip, Some("Wrong code"), &pool).await;
+                            Json(Ok(String::from("0")))
+                        }
+                    }
+                } else {
+                    if limits.lockout.record_failure(&username) {
+                        println!("Too many failed logins, locking {}", username);
+                    }
+                    audit::record(AuditEvent::LoginFailed, None, Some(row.id), None, ip, Some("Wrong password"), &pool).await;
+                    Json(Ok(String::from("0")))
+                }
+            }
+            Err(_) => {
+                // The hash in the database is invalid
+                println!("Stored password hash is invalid!");
+                audit::record(AuditEvent::LoginFailed, None, Some(row.id), None, ip, Some("Account cannot log in"), &pool).await;
+                Json(Ok(String::from("0")))
+            }
+        }
+    } else {
+        println!("Username not found");
+        // The typed name is left out, since it is sometimes the password typed into the wrong box
+        audit::record(AuditEvent::LoginFailed, None, None, None, ip, Some("Unknown username"), &pool).await;
+        Json(Ok("0".to_string()))
+    }
+}
+///http://44.192.82.24/listchats?user=${currentUser}
+/// Archived chats are left out unless "?archived=true" is given; deleted chats never show up.
+/// Favorites come first, then chats the user gave a sort order, then the rest in the order they were joined
+async fn list_chats(State(pool): State<SqlitePool>, Path(username):Path<String>, Query(params): Query<ListChatsParams>) ->Json<Result<Vec<ChatInfo>, String>>{
+    let user_id = query!("SELECT id FROM users WHERE username = ?", username)
+            .fetch_one(&pool)
+            .await.unwrap().id;     
+
+    let chat_rows = sqlx::query!(
+        r#"SELECT chat_users.chat_id FROM chat_users JOIN chats ON chats.id = chat_users.chat_id
+        WHERE chat_users.user_id = ? AND chats.deleted_at IS NULL AND (? OR chats.archived_at IS NULL)
+        ORDER BY COALESCE(chat_users.favorite, 0) DESC, chat_users.sort_order IS NULL, chat_users.sort_order, chat_users.id"#,
+        user_id, params.archived
+    )
+    .fetch_all(&pool)
+    .await
+    .unwrap();
+    
+    let chat_ids: Vec<i64> = chat_rows.into_iter().map(|row| row.chat_id).collect();
+    let mut chats_infos: Vec<ChatInfo> = Vec::new();
+    for id in chat_ids{
+        let chat_name = query!("SELECT name FROM chats WHERE id = ?", id)
+            .fetch_one(&pool)
+            .await.unwrap().name;   
+        let attached_users = sqlx::query!(
+            "SELECT user_id FROM chat_users WHERE chat_id = ?",
+            id
+        ).fetch_all(&pool)   // fetch all matching rows
+        .await
+        .unwrap();
+        let user_ids_to_iter: Vec<i64> = attached_users.into_iter().map(|row| row.user_id).collect();
+        let mut users: Vec<String> = Vec::new();
+        for user_i in user_ids_to_iter{
+            let t = query!("SELECT username FROM users WHERE id = ?", user_i)
+            .fetch_one(&pool)
+            .await.unwrap().username;   
+            users.push(t)
+        }
+        let (unread, preferences) = match user_id {
+            Some(user_id) => (
+                receipts::unread_count(id, user_id, &pool).await,
+                preferences::chat_preferences(id, user_id, &pool).await,
+            ),
+            None => (0, ChatPreferences::default()),
+        };
+        let retention = retention::chat_policy(id, &pool).await;
+        let metadata = chat_metadata::chat_metadata(id, &pool).await;
+        let pins = pins::pinned_ids(id, &pool).await;
+        let chat_info_struct = ChatInfo{id: chat_name.unwrap(), users, unread, retention, metadata, pins, preferences};
+        chats_infos.push(chat_info_struct);
+        
+    }
+    Json(Ok(chats_infos))
+
+}
@@ -0,0 +1,186 @@
+use axum::{extract::{Path, State}, response::Json};
+use serde::{Deserialize, Serialize};
+use sqlx::{query, SqlitePool};
+
+use crate::chat_role;
+use crate::events::Presence;
+
+/// Chat roles allowed to notify every member with @here or @all
+const BROADCAST_ROLES: [&str; 2] = ["owner", "admin"];
+
+/// An unread mention returned by `list_mentions`
+#[derive(Deserialize, Serialize)]
+pub(crate) struct MentionInfo {
+    message_id: i64,
+    chatname: String,
+    author: String,
+    content: String,
+    kind: String,
+    created_at: String,
+}
+
+/// Characters allowed in a mentioned username
+fn is_mention_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '-' || c == '.'
+}
+
+/// Extracts the distinct names written as `@name` in a message, in order of first appearance and
+/// ignoring letter case, as usernames do.
+/// An `@` preceded by a word character (as in an email address) is not a mention, and trailing
+/// punctuation such as the full stop in "thanks @bob." is not part of the name
+pub(crate) fn parse_mentions(content: &str) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    let mut previous: Option<char> = None;
+    let mut chars = content.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let at_word_start = previous.is_none_or(|p| !(p.is_alphanumeric() || p == '_'));
+        previous = Some(c);
+        if c != '@' || !at_word_start {
+            continue;
+        }
+        let start = i + 1;
+        let mut end = start;
+        while let Some(&(j, next)) = chars.peek() {
+            if !is_mention_char(next) {
+                break;
+            }
+            end = j + next.len_utf8();
+            previous = Some(next);
+            chars.next();
+        }
+        let name = content[start..end].trim_end_matches(['.', '-']);
+        if !name.is_empty() && !names.iter().any(|n| n.eq_ignore_ascii_case(name)) {
+            names.push(name.to_string());
+        }
+    }
+    names
+}
+
+/// Records mentions for a processed message and returns how many users were notified.
+/// Named users must be members of the chat; @here reaches members with an open event stream and
+/// @all reaches every member, both only when the author is a chat owner/admin or a global admin.
+/// Members who muted the chat, turned its notifications off or blocked the author are skipped
+pub(crate) async fn record_mentions(
+    message_id: i64,
+    chat_id: i64,
+    author_id: i64,
+    content: &str,
+    presence: &Presence,
+    pool: &SqlitePool,
+) -> usize {
+    let names = parse_mentions(content);
+    if names.is_empty() {
+        return 0;
+    }
+    let members = query!(
+        r#"SELECT users.id AS "id!", users.username FROM chat_users
+        JOIN users ON users.id = chat_users.user_id WHERE chat_users.chat_id = ?
+        AND COALESCE(chat_users.notification_level, 'all') != 'none'
+        AND (chat_users.notifications_muted_until IS NULL OR chat_users.notifications_muted_until <= datetime('now'))
+        AND NOT EXISTS (SELECT 1 FROM blocks WHERE blocks.blocker_id = users.id AND blocks.blocked_id = ?)"#,
+        chat_id, author_id
+    ).fetch_all(pool).await.unwrap();
+    let named = |name: &str| names.iter().any(|n| n.eq_ignore_ascii_case(name));
+    let wants_broadcast = named("here") || named("all");
+    let can_broadcast = wants_broadcast && can_mention_everyone(chat_id, author_id, pool).await;
+    let online = presence.online_in(chat_id);
+    let mut mentioned: Vec<(i64, &str)> = Vec::new();
+    for member in members.iter().filter(|m| m.id != author_id) {
+        let kind = if named(&member.username) {
+            "direct"
+        } else if can_broadcast && named("all") {
+            "all"
+        } else if can_broadcast && named("here") && online.contains(&member.id) {
+            "here"
+        } else {
+            continue;
+        };
+        mentioned.push((member.id, kind));
+    }
+    for (user_id, kind) in &mentioned {
+        query!(
+            r#"INSERT OR IGNORE INTO mentions (message_id, chat_id, user_id, kind, is_read, created_at)
+            VALUES (?, ?, ?, ?, 0, datetime('now'))"#,
+            message_id, chat_id, user_id, kind
+        ).execute(pool).await.unwrap();
+    }
+    mentioned.len()
+}
+
+/// Whether the user may use @here and @all in the chat
+async fn can_mention_everyone(chat_id: i64, user_id: i64, pool: &SqlitePool) -> bool {
+    let global_role = query!("SELECT role FROM users WHERE id = ?", user_id)
+        .fetch_one(pool)
+        .await
+        .unwrap()
+        .role;
+    if global_role.as_deref() == Some("admin") {
+        return true;
+    }
+    match chat_role(chat_id, user_id, pool).await {
+        Some(role) => BROADCAST_ROLES.contains(&role.as_str()),
+        None => false,
+    }
+}
+
+/// Marks the user's mentions in a chat as read, up to and including the given message
+pub(crate) async fn mark_mentions_read(chat_id: i64, user_id: i64, up_to: i64, pool: &SqlitePool) {
+    query!(
+        "UPDATE mentions SET is_read = 1 WHERE chat_id = ? AND user_id = ? AND message_id <= ?",
+        chat_id, user_id, up_to
+    ).execute(pool).await.unwrap();
+}
+
+/// Lists a user's unread mentions across all chats, newest first; marking a chat read clears its mentions
+/// # Query format:
+/// curl "http://98.93.98.244:80/mentions/username/UsernameString"
+pub(crate) async fn list_mentions(
+    State(pool): State<SqlitePool>,
+    Path(username): Path<String>,
+) -> Json<Result<Vec<MentionInfo>, String>> {
+    let rows = query!(
+        r#"SELECT mentions.message_id, chats.name AS "chatname?", authors.username AS author,
+            messages.content, mentions.kind, messages.created_at AS "created_at!: String"
+        FROM mentions
+        JOIN users ON users.id = mentions.user_id
+        JOIN messages ON messages.id = mentions.message_id
+        JOIN users AS authors ON authors.id = messages.user_id
+        JOIN chats ON chats.id = mentions.chat_id
+        WHERE users.username = ? AND mentions.is_read = 0
+        ORDER BY mentions.message_id DESC"#,
+        username
+    ).fetch_all(&pool).await.unwrap();
+    let mentions = rows
+        .into_iter()
+        .map(|row| MentionInfo {
+            message_id: row.message_id,
+            chatname: row.chatname.unwrap_or_default(),
+            author: row.author,
+            content: row.content,
+            kind: row.kind,
+            created_at: row.created_at,
+        })
+        .collect();
+    Json(Ok(mentions))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_mentions() {
+        assert_eq!(parse_mentions("hey @alice and @bob_2"), vec!["alice", "bob_2"]);
+        assert_eq!(parse_mentions("@alice @alice"), vec!["alice"]);
+        assert_eq!(parse_mentions("@Alice @alice"), vec!["Alice"]);
+        assert_eq!(parse_mentions("thanks @carol."), vec!["carol"]);
+        assert_eq!(parse_mentions("@here deploy is done"), vec!["here"]);
+    }
+
+    #[test]
+    fn test_parse_mentions_ignores_non_mentions() {
+        assert!(parse_mentions("mail me at alice@example.com").is_empty());
+        assert!(parse_mentions("just an @ sign").is_empty());
+        assert!(parse_mentions("no mentions here").is_empty());
+    }
+}
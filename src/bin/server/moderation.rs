@@ -0,0 +1,490 @@
+use axum::{extract::{ConnectInfo, Path, State}, response::Json};
+use serde::{Deserialize, Serialize};
+use sqlx::{query, SqlitePool};
+use std::net::{IpAddr, SocketAddr};
+
+use crate::accounts::verify_credentials;
+use crate::audit::{self, AuditEvent};
+use crate::chat_role;
+use crate::commands::parse_duration;
+use crate::ratelimit::RateLimits;
+
+const MAX_REASON_CHARS: usize = 500;
+/// Global roles allowed to moderate every chat and ban accounts
+const GLOBAL_MODERATOR_ROLES: [&str; 2] = ["admin", "moderator"];
+
+/// What a moderator did
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ActionKind {
+    Kick,      // Removed from the chat, may be invited back
+    Mute,      // Cannot post in the chat until the mute expires
+    ChatBan,   // Removed from the chat and cannot rejoin until the ban expires
+    GlobalBan, // Cannot log in or post anywhere until the ban expires
+}
+
+impl ActionKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            ActionKind::Kick => "kick",
+            ActionKind::Mute => "mute",
+            ActionKind::ChatBan => "chat_ban",
+            ActionKind::GlobalBan => "global_ban",
+        }
+    }
+}
+
+/// A chat moderation request: "kick", "mute", "unmute", "ban" or "unban"
+#[derive(Deserialize)]
+pub(crate) struct ModerateRequest {
+    password: String,
+    action: String,
+    target: String,
+    #[serde(default)]
+    duration: Option<String>, // "30s", "10m", "2h" or "1d"; required for mutes, bans without one are permanent
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct GlobalBanRequest {
+    password: String,
+    target: String,
+    #[serde(default)]
+    duration: Option<String>, // Permanent when left out
+    #[serde(default)]
+    reason: Option<String>,
+    #[serde(default)]
+    lift: bool, // Lifts the target's ban instead
+}
+
+#[derive(Deserialize)]
+pub(crate) struct LogRequest {
+    password: String,
+}
+
+/// A recorded moderation action as shown in the moderation log
+#[derive(Serialize)]
+pub(crate) struct ModerationAction {
+    id: i64,
+    action: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chatname: Option<String>, // None for global bans
+    target: String,
+    actor: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+    created_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<String>, // None for kicks and permanent bans
+    #[serde(skip_serializing_if = "Option::is_none")]
+    revoked_at: Option<String>, // Set when lifted early
+    #[serde(skip_serializing_if = "Option::is_none")]
+    revoked_by: Option<String>,
+}
+
+/// How far a chat role reaches; a moderator must outrank whoever they act on
+pub(crate) fn rank(role: &str) -> u8 {
+    match role {
+        "owner" => 2,
+        "admin" => 1,
+        _ => 0,
+    }
+}
+
+/// Parses an optional duration into an SQLite time modifier such as "+600 seconds"
+pub(crate) fn expiry_modifier(duration: Option<&str>) -> Result<Option<String>, String> {
+    match duration.map(str::trim).filter(|d| !d.is_empty()) {
+        None => Ok(None),
+        Some(duration) => parse_duration(duration)
+            .map(|secs| Some(format!("+{} seconds", secs)))
+            .ok_or_else(|| format!("Invalid duration {}, use e.g. 30s, 10m, 2h or 1d (at most 30d)", duration)),
+    }
+}
+
+/// Trims a reason, treating blank text as none
+pub(crate) fn check_reason(reason: Option<&str>) -> Result<Option<String>, String> {
+    match reason.map(str::trim).filter(|r| !r.is_empty()) {
+        None => Ok(None),
+        Some(reason) if reason.chars().count() > MAX_REASON_CHARS => {
+            Err(format!("Reason can be at most {} characters", MAX_REASON_CHARS))
+        }
+        Some(reason) => Ok(Some(reason.to_string())),
+    }
+}
+
+/// Describes when an action ends for messages shown to the user it applies to
+fn until_text(expires_at: &Option<String>, reason: &Option<String>) -> String {
+    let mut text = match expires_at {
+        Some(expires_at) => format!(" until {} UTC", expires_at),
+        None => String::new(),
+    };
+    if let Some(reason) = reason {
+        text.push_str(&format!(" ({})", reason));
+    }
+    text
+}
+
+/// True if the user holds a global role that moderates every chat
+pub(crate) async fn is_global_moderator(user_id: i64, pool: &SqlitePool) -> bool {
+    let role = query!("SELECT role FROM users WHERE id = ?", user_id)
+        .fetch_optional(pool)
+        .await
+        .unwrap()
+        .and_then(|row| row.role);
+    role.is_some_and(|role| GLOBAL_MODERATOR_ROLES.contains(&role.as_str()))
+}
+
+/// Checks that the actor may moderate the target in the chat: global moderators may act on anyone but
+/// each other, chat owners and admins only on members they outrank
+pub(crate) async fn check_authority(chat_id: i64, actor_id: i64, target_id: i64, pool: &SqlitePool) -> Result<(), String> {
+    if actor_id == target_id {
+        return Err(String::from("You cannot use this on yourself"));
+    }
+    let target_is_moderator = is_global_moderator(target_id, pool).await;
+    if is_global_moderator(actor_id, pool).await && !target_is_moderator {
+        return Ok(());
+    }
+    let actor_role = chat_role(chat_id, actor_id, pool).await.unwrap_or_default();
+    if rank(&actor_role) == 0 {
+        return Err(String::from("Only chat owners, admins and moderators can moderate"));
+    }
+    let target_role = chat_role(chat_id, target_id, pool).await.unwrap_or_default();
+    if target_is_moderator || rank(&target_role) >= rank(&actor_role) {
+        return Err(String::from("You cannot moderate this user"));
+    }
+    Ok(())
+}
+
+/// Records an action, returning its id; the expiry modifier is applied to the current time, no modifier means no expiry
+async fn record(
+    kind: ActionKind, chat_id: Option<i64>, target_id: i64, actor_id: i64,
+    reason: Option<&str>, expiry: Option<&str>, pool: &SqlitePool,
+) -> i64 {
+    let kind = kind.as_str();
+    query!(
+        r#"INSERT INTO moderation_actions (action, chat_id, target_id, actor_id, reason, created_at, expires_at)
+        VALUES (?, ?, ?, ?, ?, datetime('now'), datetime('now', ?))"#,
+        kind, chat_id, target_id, actor_id, reason, expiry
+    ).execute(pool).await.unwrap().last_insert_rowid()
+}
+
+/// Ends the target's active actions of a kind early, returning whether there were any
+async fn lift(kind: ActionKind, chat_id: Option<i64>, target_id: i64, actor_id: i64, pool: &SqlitePool) -> bool {
+    let kind = kind.as_str();
+    let result = query!(
+        r#"UPDATE moderation_actions SET revoked_at = datetime('now'), revoked_by = ?
+        WHERE action = ? AND chat_id IS ? AND target_id = ? AND revoked_at IS NULL
+        AND (expires_at IS NULL OR expires_at > datetime('now'))"#,
+        actor_id, kind, chat_id, target_id
+    ).execute(pool).await.unwrap();
+    result.rows_affected() > 0
+}
+
+/// Expiry (None when permanent) and reason of the target's newest active action of a kind, None if there is none
+async fn active(kind: ActionKind, chat_id: Option<i64>, target_id: i64, pool: &SqlitePool) -> Option<(Option<String>, Option<String>)> {
+    let kind = kind.as_str();
+    query!(
+        r#"SELECT expires_at AS "expires_at: String", reason FROM moderation_actions
+        WHERE action = ? AND chat_id IS ? AND target_id = ? AND revoked_at IS NULL
+        AND (expires_at IS NULL OR expires_at > datetime('now'))
+        ORDER BY expires_at IS NULL DESC, expires_at DESC LIMIT 1"#,
+        kind, chat_id, target_id
+    ).fetch_optional(pool).await.unwrap()
+    .map(|row| (row.expires_at, row.reason))
+}
+
+/// Fails if the user is banned from the chat
+pub(crate) async fn check_not_banned(chat_id: i64, user_id: i64, pool: &SqlitePool) -> Result<(), String> {
+    match active(ActionKind::ChatBan, Some(chat_id), user_id, pool).await {
+        Some((expires_at, reason)) => Err(format!("Banned from this chat{}", until_text(&expires_at, &reason))),
+        None => Ok(()),
+    }
+}
+
+/// Fails if the account is banned from the whole server
+pub(crate) async fn check_account(user_id: i64, pool: &SqlitePool) -> Result<(), String> {
+    match active(ActionKind::GlobalBan, None, user_id, pool).await {
+        Some((expires_at, reason)) => Err(format!("This account is banned{}", until_text(&expires_at, &reason))),
+        None => Ok(()),
+    }
+}
+
+/// Fails if the user may not post in the chat because of a global ban, chat ban or mute
+pub(crate) async fn check_can_post(chat_id: i64, user_id: i64, pool: &SqlitePool) -> Result<(), String> {
+    check_account(user_id, pool).await?;
+    check_not_banned(chat_id, user_id, pool).await?;
+    match active(ActionKind::Mute, Some(chat_id), user_id, pool).await {
+        Some((expires_at, reason)) => Err(format!("You are muted in this chat{}", until_text(&expires_at, &reason))),
+        None => Ok(()),
+    }
+}
+
+/// Removes a member from a chat; the IP is that of the request, for the audit log
+pub(crate) async fn kick(
+    chat_id: i64, target_id: i64, actor_id: i64, reason: Option<&str>, ip: Option<IpAddr>, pool: &SqlitePool,
+) {
+    query!("DELETE FROM chat_users WHERE chat_id = ? AND user_id = ?", chat_id, target_id)
+        .execute(pool)
+        .await
+        .unwrap();
+    record(ActionKind::Kick, Some(chat_id), target_id, actor_id, reason, None, pool).await;
+    audit::record(AuditEvent::MemberRemoved, Some(actor_id), Some(target_id), Some(chat_id), ip, Some("Kicked"), pool).await;
+}
+
+/// Stops a member posting until the mute runs out, replacing any mute they already have; returns the action's id
+pub(crate) async fn mute(chat_id: i64, target_id: i64, actor_id: i64, secs: i64, reason: Option<&str>, pool: &SqlitePool) -> i64 {
+    lift(ActionKind::Mute, Some(chat_id), target_id, actor_id, pool).await;
+    let expiry = format!("+{} seconds", secs);
+    record(ActionKind::Mute, Some(chat_id), target_id, actor_id, reason, Some(&expiry), pool).await
+}
+
+/// Lifts a member's mute, returning whether they were muted
+pub(crate) async fn unmute(chat_id: i64, target_id: i64, actor_id: i64, pool: &SqlitePool) -> bool {
+    lift(ActionKind::Mute, Some(chat_id), target_id, actor_id, pool).await
+}
+
+/// Removes the user from the chat and keeps them out until the ban expires, or for good without an expiry;
+/// returns the action's id
+pub(crate) async fn ban(
+    chat_id: i64, target_id: i64, actor_id: i64, expiry: Option<&str>, reason: Option<&str>, ip: Option<IpAddr>,
+    pool: &SqlitePool,
+) -> i64 {
+    lift(ActionKind::ChatBan, Some(chat_id), target_id, actor_id, pool).await;
+    let removed = query!("DELETE FROM chat_users WHERE chat_id = ? AND user_id = ?", chat_id, target_id)
+        .execute(pool)
+        .await
+        .unwrap();
+    if removed.rows_affected() > 0 {
+        audit::record(AuditEvent::MemberRemoved, Some(actor_id), Some(target_id), Some(chat_id), ip, Some("Banned"), pool).await;
+    }
+    record(ActionKind::ChatBan, Some(chat_id), target_id, actor_id, reason, expiry, pool).await
+}
+
+/// Lifts a chat ban, returning whether the user was banned
+pub(crate) async fn unban(chat_id: i64, target_id: i64, actor_id: i64, pool: &SqlitePool) -> bool {
+    lift(ActionKind::ChatBan, Some(chat_id), target_id, actor_id, pool).await
+}
+
+/// Kicks, mutes or bans a user in a chat, or lifts a mute or ban. Chat owners and admins may act on members
+/// they outrank; global moderators may act in any chat
+/// # Query format:
+/// curl -X POST -H "Content-Type: application/json" -d '{"password": "PasswordString", "action": "mute", "target": "OtherUser", "duration": "2h", "reason": "Spamming"}' "http://98.93.98.244:80/moderate/chatname/ChatName/username/UsernameString"
+pub(crate) async fn moderate(
+    State(pool): State<SqlitePool>,
+    State(limits): State<RateLimits>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path((chatname, username)): Path<(String, String)>,
+    Json(request): Json<ModerateRequest>,
+) -> Json<Result<String, String>> {
+    let Some(chat) = query!(r#"SELECT id AS "id!" FROM chats WHERE name = ? AND deleted_at IS NULL"#, chatname)
+        .fetch_optional(&pool)
+        .await
+        .unwrap() else {
+        return Json(Err(String::from("Chat not found")));
+    };
+    let Some((actor_id, _)) = verify_credentials(&username, &request.password, &pool).await else {
+        limits.lockout.record_failure(&username);
+        return Json(Err(String::from("Incorrect username or password")));
+    };
+    let Some(target_id) = user_id(&request.target, &pool).await else {
+        return Json(Err(String::from("User not found")));
+    };
+    if let Err(e) = check_authority(chat.id, actor_id, target_id, &pool).await {
+        return Json(Err(e));
+    }
+    let (expiry, reason) = match (expiry_modifier(request.duration.as_deref()), check_reason(request.reason.as_deref())) {
+        (Ok(expiry), Ok(reason)) => (expiry, reason),
+        (Err(e), _) | (_, Err(e)) => return Json(Err(e)),
+    };
+    let is_member = chat_role(chat.id, target_id, &pool).await.is_some();
+    let changed = match request.action.as_str() {
+        "kick" | "mute" if !is_member => return Json(Err(format!("{} is not a member of this chat", request.target))),
+        "kick" => {
+            kick(chat.id, target_id, actor_id, reason.as_deref(), Some(addr.ip()), &pool).await;
+            true
+        }
+        "mute" => {
+            let Some(secs) = request.duration.as_deref().and_then(|d| parse_duration(d.trim())) else {
+                return Json(Err(String::from("A mute needs a duration")));
+            };
+            mute(chat.id, target_id, actor_id, secs, reason.as_deref(), &pool).await;
+            true
+        }
+        "unmute" => unmute(chat.id, target_id, actor_id, &pool).await,
+        "ban" => {
+            ban(chat.id, target_id, actor_id, expiry.as_deref(), reason.as_deref(), Some(addr.ip()), &pool).await;
+            true
+        }
+        "unban" => unban(chat.id, target_id, actor_id, &pool).await,
+        other => return Json(Err(format!("Unknown action {}, use kick, mute, unmute, ban or unban", other))),
+    };
+    if !changed {
+        return Json(Ok(String::from("0")));
+    }
+    println!("{} used {} on {} in {}", username, request.action, request.target, chatname);
+    Json(Ok(String::from("1")))
+}
+
+/// Bans an account from the whole server, or lifts its ban with "lift": true; only global moderators may do
+/// this. Banned users cannot log in or post
+/// # Query format:
+/// curl -X POST -H "Content-Type: application/json" -d '{"password": "PasswordString", "target": "OtherUser", "duration": "7d", "reason": "Spam bot"}' "http://98.93.98.244:80/globalban/username/NameString"
+pub(crate) async fn global_ban(
+    State(pool): State<SqlitePool>,
+    State(limits): State<RateLimits>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(username): Path<String>,
+    Json(request): Json<GlobalBanRequest>,
+) -> Json<Result<String, String>> {
+    let Some((actor_id, _)) = verify_credentials(&username, &request.password, &pool).await else {
+        limits.lockout.record_failure(&username);
+        return Json(Err(String::from("Incorrect username or password")));
+    };
+    if !is_global_moderator(actor_id, &pool).await {
+        return Json(Err(String::from("Only moderators can ban accounts")));
+    }
+    let Some(target_id) = user_id(&request.target, &pool).await else {
+        return Json(Err(String::from("User not found")));
+    };
+    if target_id == actor_id || is_global_moderator(target_id, &pool).await {
+        return Json(Err(String::from("You cannot ban this account")));
+    }
+    if request.lift {
+        let lifted = lift(ActionKind::GlobalBan, None, target_id, actor_id, &pool).await;
+        if lifted {
+            audit::record(AuditEvent::AccountUnbanned, Some(actor_id), Some(target_id), None, Some(addr.ip()), None, &pool).await;
+        }
+        return Json(Ok(String::from(if lifted { "1" } else { "0" })));
+    }
+    let (expiry, reason) = match (expiry_modifier(request.duration.as_deref()), check_reason(request.reason.as_deref())) {
+        (Ok(expiry), Ok(reason)) => (expiry, reason),
+        (Err(e), _) | (_, Err(e)) => return Json(Err(e)),
+    };
+    lift(ActionKind::GlobalBan, None, target_id, actor_id, &pool).await;
+    record(ActionKind::GlobalBan, None, target_id, actor_id, reason.as_deref(), expiry.as_deref(), &pool).await;
+    audit::record(AuditEvent::AccountBanned, Some(actor_id), Some(target_id), None, Some(addr.ip()), reason.as_deref(), &pool).await;
+    println!("{} banned {}", username, request.target);
+    Json(Ok(String::from("1")))
+}
+
+/// Lists a chat's moderation actions, newest first; only chat owners, admins and global moderators may see it
+/// # Query format:
+/// curl -X POST -H "Content-Type: application/json" -d '{"password": "PasswordString"}' "http://98.93.98.244:80/moderationlog/chatname/ChatName/username/UsernameString"
+/// # Return format:
+/// Array of "id", "action" (kick, mute, chat_ban), "target", "actor", "reason", "created_at", "expires_at",
+/// "revoked_at" and "revoked_by"
+pub(crate) async fn chat_log(
+    State(pool): State<SqlitePool>,
+    State(limits): State<RateLimits>,
+    Path((chatname, username)): Path<(String, String)>,
+    Json(request): Json<LogRequest>,
+) -> Json<Result<Vec<ModerationAction>, String>> {
+    let Some(chat) = query!(r#"SELECT id AS "id!" FROM chats WHERE name = ? AND deleted_at IS NULL"#, chatname)
+        .fetch_optional(&pool)
+        .await
+        .unwrap() else {
+        return Json(Err(String::from("Chat not found")));
+    };
+    let Some((viewer_id, _)) = verify_credentials(&username, &request.password, &pool).await else {
+        limits.lockout.record_failure(&username);
+        return Json(Err(String::from("Incorrect username or password")));
+    };
+    let manages = rank(&chat_role(chat.id, viewer_id, &pool).await.unwrap_or_default()) > 0;
+    if !manages && !is_global_moderator(viewer_id, &pool).await {
+        return Json(Err(String::from("Only chat owners, admins and moderators can see the moderation log")));
+    }
+    Json(Ok(actions(Some(chat.id), &pool).await))
+}
+
+/// Lists every moderation action on the server, global bans included, newest first; only global moderators
+/// may see it
+/// # Query format:
+/// curl -X POST -H "Content-Type: application/json" -d '{"password": "PasswordString"}' "http://98.93.98.244:80/moderationlog/username/UsernameString"
+pub(crate) async fn global_log(
+    State(pool): State<SqlitePool>,
+    State(limits): State<RateLimits>,
+    Path(username): Path<String>,
+    Json(request): Json<LogRequest>,
+) -> Json<Result<Vec<ModerationAction>, String>> {
+    let Some((viewer_id, _)) = verify_credentials(&username, &request.password, &pool).await else {
+        limits.lockout.record_failure(&username);
+        return Json(Err(String::from("Incorrect username or password")));
+    };
+    if !is_global_moderator(viewer_id, &pool).await {
+        return Json(Err(String::from("Only moderators can see the moderation log")));
+    }
+    Json(Ok(actions(None, &pool).await))
+}
+
+/// Actions taken in one chat, or everywhere when no chat is given
+async fn actions(chat_id: Option<i64>, pool: &SqlitePool) -> Vec<ModerationAction> {
+    query!(
+        r#"SELECT moderation_actions.id AS "id!", moderation_actions.action, chats.name AS "chatname?",
+        targets.username AS target, actors.username AS actor, moderation_actions.reason,
+        moderation_actions.created_at AS "created_at!: String", moderation_actions.expires_at AS "expires_at: String",
+        moderation_actions.revoked_at AS "revoked_at: String", revokers.username AS "revoked_by?"
+        FROM moderation_actions
+        JOIN users AS targets ON targets.id = moderation_actions.target_id
+        JOIN users AS actors ON actors.id = moderation_actions.actor_id
+        LEFT JOIN users AS revokers ON revokers.id = moderation_actions.revoked_by
+        LEFT JOIN chats ON chats.id = moderation_actions.chat_id
+        WHERE ?1 IS NULL OR moderation_actions.chat_id = ?1
+        ORDER BY moderation_actions.id DESC"#,
+        chat_id
+    ).fetch_all(pool).await.unwrap()
+    .into_iter()
+    .map(|row| ModerationAction {
+        id: row.id,
+        action: row.action,
+        chatname: row.chatname,
+        target: row.target,
+        actor: row.actor,
+        reason: row.reason,
+        created_at: row.created_at,
+        expires_at: row.expires_at,
+        revoked_at: row.revoked_at,
+        revoked_by: row.revoked_by,
+    })
+    .collect()
+}
+
+async fn user_id(username: &str, pool: &SqlitePool) -> Option<i64> {
+    query!(r#"SELECT id AS "id!" FROM users WHERE username = ?"#, username)
+        .fetch_optional(pool)
+        .await
+        .unwrap()
+        .map(|row| row.id)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ranks() {
+        assert!(rank("owner") > rank("admin") && rank("admin") > rank("member"));
+        assert_eq!(rank(""), 0);
+    }
+
+    #[test]
+    fn test_expiry_modifier() {
+        assert_eq!(expiry_modifier(Some("10m")), Ok(Some(String::from("+600 seconds"))));
+        assert_eq!(expiry_modifier(None), Ok(None));
+        assert_eq!(expiry_modifier(Some("  ")), Ok(None));
+        assert!(expiry_modifier(Some("forever")).is_err());
+    }
+
+    #[test]
+    fn test_reason_and_until_text() {
+        assert_eq!(check_reason(Some("  spam ")), Ok(Some(String::from("spam"))));
+        assert_eq!(check_reason(Some("")), Ok(None));
+        assert!(check_reason(Some(&"r".repeat(MAX_REASON_CHARS + 1))).is_err());
+        assert_eq!(until_text(&None, &None), "");
+        assert_eq!(
+            until_text(&Some(String::from("2030-01-01 00:00:00")), &Some(String::from("spam"))),
+            " until 2030-01-01 00:00:00 UTC (spam)"
+        );
+        assert_eq!(serde_json::to_string(&ActionKind::ChatBan).unwrap(), r#""chat_ban""#);
+    }
+}
@@ -0,0 +1,137 @@
+use axum::{extract::{Path, State}, response::Json};
+use serde::{Deserialize, Serialize};
+use sqlx::{query, SqlitePool};
+
+use crate::events::{publish, ChatEvent, EventSender};
+use crate::lookup_member;
+
+/// Most messages a chat can have pinned at once
+const MAX_PINS_PER_CHAT: i64 = 50;
+
+/// A pinned message as shown by /pins and chat details
+#[derive(Deserialize, Serialize)]
+pub(crate) struct PinnedMessage {
+    message_id: i64,
+    username: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    display_name: Option<String>, // The name an incoming webhook posted under
+    content: String,
+    created_at: String,
+    pinned_by: String,
+    pinned_at: String,
+}
+
+/// Pins a posted message of the chat; any member may pin. Pinning a pinned message is a no-op
+/// # Query format:
+/// curl -X POST "http://98.93.98.244:80/pin/chatname/ChatName/username/UsernameString/messageid/42"
+pub(crate) async fn pin_message(
+    State(pool): State<SqlitePool>,
+    State(events): State<EventSender>,
+    Path((chatname, username, message_id)): Path<(String, String, i64)>,
+) -> Json<Result<String, String>> {
+    let (chat_id, user_id) = match lookup_member(&chatname, &username, &pool).await {
+        Ok(ids) => ids,
+        Err(e) => return Json(Err(e)),
+    };
+    let posted = sqlx::query_scalar!(
+        "SELECT EXISTS(SELECT 1 FROM messages WHERE id = ? AND chat_id = ? AND status = 'Sent!') AS _exists",
+        message_id, chat_id
+    ).fetch_one(&pool).await.unwrap();
+    if posted == 0 {
+        return Json(Err(String::from("Message not found")));
+    }
+    let pinned = sqlx::query_scalar!("SELECT COUNT(*) FROM pins WHERE chat_id = ?", chat_id)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    if pinned >= MAX_PINS_PER_CHAT {
+        return Json(Err(format!("A chat can have at most {} pinned messages", MAX_PINS_PER_CHAT)));
+    }
+    let result = query!(
+        r#"INSERT OR IGNORE INTO pins (chat_id, message_id, pinned_by, pinned_at)
+        VALUES (?, ?, ?, datetime('now'))"#,
+        chat_id, message_id, user_id
+    ).execute(&pool).await.unwrap();
+    if result.rows_affected() == 0 {
+        return Json(Ok(String::from("0")));
+    }
+    println!("{} pinned message {} in {}", username, message_id, chatname);
+    publish(&events, ChatEvent::Pin { chat_id, message_id, username, pinned: true });
+    Json(Ok(String::from("1")))
+}
+
+/// Unpins a message; any member may unpin
+/// # Query format:
+/// curl -X DELETE "http://98.93.98.244:80/pin/chatname/ChatName/username/UsernameString/messageid/42"
+pub(crate) async fn unpin_message(
+    State(pool): State<SqlitePool>,
+    State(events): State<EventSender>,
+    Path((chatname, username, message_id)): Path<(String, String, i64)>,
+) -> Json<Result<String, String>> {
+    let (chat_id, _) = match lookup_member(&chatname, &username, &pool).await {
+        Ok(ids) => ids,
+        Err(e) => return Json(Err(e)),
+    };
+    let result = query!("DELETE FROM pins WHERE chat_id = ? AND message_id = ?", chat_id, message_id)
+        .execute(&pool)
+        .await
+        .unwrap();
+    if result.rows_affected() == 0 {
+        return Json(Ok(String::from("0")));
+    }
+    publish(&events, ChatEvent::Pin { chat_id, message_id, username, pinned: false });
+    Json(Ok(String::from("1")))
+}
+
+/// Lists the chat's pinned messages, most recently pinned first
+/// # Query format:
+/// curl "http://98.93.98.244:80/pins/chatname/ChatName/username/UsernameString"
+/// # Return format:
+/// Array of "message_id", "username", "display_name" (for incoming webhooks), "content", "created_at", "pinned_by" and "pinned_at"
+pub(crate) async fn list_pins(
+    State(pool): State<SqlitePool>,
+    Path((chatname, username)): Path<(String, String)>,
+) -> Json<Result<Vec<PinnedMessage>, String>> {
+    match lookup_member(&chatname, &username, &pool).await {
+        Ok((chat_id, _)) => Json(Ok(pinned_messages(chat_id, &pool).await)),
+        Err(e) => Json(Err(e)),
+    }
+}
+
+/// The chat's pinned messages, most recently pinned first
+pub(crate) async fn pinned_messages(chat_id: i64, pool: &SqlitePool) -> Vec<PinnedMessage> {
+    query!(
+        r#"SELECT pins.message_id, authors.username, messages.display_name,
+        messages.content, messages.created_at AS "created_at!: String", pinners.username AS pinned_by,
+        pins.pinned_at AS "pinned_at!: String"
+        FROM pins
+        JOIN messages ON messages.id = pins.message_id
+        JOIN users AS authors ON authors.id = messages.user_id
+        JOIN users AS pinners ON pinners.id = pins.pinned_by
+        WHERE pins.chat_id = ?
+        ORDER BY pins.id DESC"#,
+        chat_id
+    ).fetch_all(pool).await.unwrap()
+    .into_iter()
+    .map(|row| PinnedMessage {
+        message_id: row.message_id,
+        username: row.username,
+        display_name: row.display_name,
+        content: row.content,
+        created_at: row.created_at,
+        pinned_by: row.pinned_by,
+        pinned_at: row.pinned_at,
+    })
+    .collect()
+}
+
+/// Ids of the chat's pinned messages, most recently pinned first
+pub(crate) async fn pinned_ids(chat_id: i64, pool: &SqlitePool) -> Vec<i64> {
+    query!("SELECT message_id FROM pins WHERE chat_id = ? ORDER BY id DESC", chat_id)
+        .fetch_all(pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|row| row.message_id)
+        .collect()
+}
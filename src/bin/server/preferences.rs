@@ -0,0 +1,185 @@
+use axum::{extract::{Path, State}, response::Json};
+use serde::{Deserialize, Serialize};
+use sqlx::{query, SqlitePool};
+
+use crate::chat_metadata::present;
+use crate::commands::parse_duration;
+use crate::lookup_member;
+
+/// How much a member wants to hear about new activity in a chat
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum NotificationLevel {
+    #[default]
+    All,      // Every new message
+    Mentions, // Only messages mentioning the member
+    None,     // Nothing, not even mentions
+}
+
+impl NotificationLevel {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            NotificationLevel::All => "all",
+            NotificationLevel::Mentions => "mentions",
+            NotificationLevel::None => "none",
+        }
+    }
+
+    /// Reads a stored level, treating unknown values as the default
+    fn parse(text: &str) -> NotificationLevel {
+        match text {
+            "mentions" => NotificationLevel::Mentions,
+            "none" => NotificationLevel::None,
+            _ => NotificationLevel::All,
+        }
+    }
+}
+
+/// A member's own settings for one chat, reported by list_chats
+#[derive(Deserialize, Serialize, Default, Debug, PartialEq)]
+pub(crate) struct ChatPreferences {
+    #[serde(default)]
+    notification_level: NotificationLevel,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    muted_until: Option<String>, // Notifications are silenced until then (UTC)
+    #[serde(default)]
+    notify: NotificationLevel, // The level in effect now, None while muted
+    #[serde(default)]
+    favorite: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sort_order: Option<i64>, // Position among the member's chats, lowest first
+}
+
+impl ChatPreferences {
+    fn new(notification_level: NotificationLevel, muted_until: Option<String>, favorite: bool, sort_order: Option<i64>) -> Self {
+        let notify = if muted_until.is_some() { NotificationLevel::None } else { notification_level };
+        ChatPreferences { notification_level, muted_until, notify, favorite, sort_order }
+    }
+}
+
+/// Preferences to change; missing fields are left alone
+#[derive(Deserialize)]
+pub(crate) struct UpdatePreferencesRequest {
+    #[serde(default)]
+    notification_level: Option<NotificationLevel>,
+    #[serde(default, deserialize_with = "present")]
+    mute_for: Option<Option<String>>, // e.g. "8h", at most 30 days; null unmutes
+    #[serde(default)]
+    favorite: Option<bool>,
+    #[serde(default, deserialize_with = "present")]
+    sort_order: Option<Option<i64>>, // null returns the chat to the default order
+}
+
+/// The member's preferences for a chat; an expired mute is not reported
+pub(crate) async fn chat_preferences(chat_id: i64, user_id: i64, pool: &SqlitePool) -> ChatPreferences {
+    query!(
+        r#"SELECT COALESCE(notification_level, 'all') AS "notification_level!: String",
+        CASE WHEN notifications_muted_until > datetime('now') THEN notifications_muted_until END AS "muted_until: String",
+        COALESCE(favorite, 0) AS "favorite!: bool", sort_order
+        FROM chat_users WHERE chat_id = ? AND user_id = ?"#,
+        chat_id, user_id
+    ).fetch_optional(pool).await.unwrap()
+    .map(|row| ChatPreferences::new(
+        NotificationLevel::parse(&row.notification_level),
+        row.muted_until,
+        row.favorite,
+        row.sort_order,
+    ))
+    .unwrap_or_default()
+}
+
+/// Shows the user's preferences for a chat
+/// # Query format:
+/// curl "http://98.93.98.244:80/chatpreferences/chatname/ChatName/username/UsernameString"
+/// # Return format:
+/// "notification_level" (all, mentions or none), "muted_until", "notify" (the level in effect now), "favorite" and "sort_order"
+pub(crate) async fn get_preferences(
+    State(pool): State<SqlitePool>,
+    Path((chatname, username)): Path<(String, String)>,
+) -> Json<Result<ChatPreferences, String>> {
+    match lookup_member(&chatname, &username, &pool).await {
+        Ok((chat_id, user_id)) => Json(Ok(chat_preferences(chat_id, user_id, &pool).await)),
+        Err(e) => Json(Err(e)),
+    }
+}
+
+/// Updates the user's own preferences for a chat. Members who mute a chat or pick "none" are left out of
+/// its mentions; favorites come first in list_chats, followed by chats with a sort order
+/// # Query format:
+/// curl -X POST -H "Content-Type: application/json" -d '{"notification_level": "mentions", "mute_for": "8h", "favorite": true, "sort_order": 2}' "http://98.93.98.244:80/chatpreferences/chatname/ChatName/username/UsernameString"
+pub(crate) async fn update_preferences(
+    State(pool): State<SqlitePool>,
+    Path((chatname, username)): Path<(String, String)>,
+    Json(request): Json<UpdatePreferencesRequest>,
+) -> Json<Result<String, String>> {
+    let (chat_id, user_id) = match lookup_member(&chatname, &username, &pool).await {
+        Ok(ids) => ids,
+        Err(e) => return Json(Err(e)),
+    };
+    if let Some(mute_for) = request.mute_for {
+        let modifier = match mute_for.as_deref().map(parse_duration) {
+            Some(Some(secs)) => Some(format!("+{} seconds", secs)),
+            Some(None) => return Json(Err(String::from("Invalid mute duration, use e.g. 30m, 8h or 7d (at most 30 days)"))),
+            None => None,
+        };
+        query!(
+            "UPDATE chat_users SET notifications_muted_until = datetime('now', ?) WHERE chat_id = ? AND user_id = ?",
+            modifier, chat_id, user_id
+        ).execute(&pool).await.unwrap();
+    }
+    if let Some(level) = request.notification_level {
+        let level = level.as_str();
+        query!(
+            "UPDATE chat_users SET notification_level = ? WHERE chat_id = ? AND user_id = ?",
+            level, chat_id, user_id
+        ).execute(&pool).await.unwrap();
+    }
+    if let Some(favorite) = request.favorite {
+        query!(
+            "UPDATE chat_users SET favorite = ? WHERE chat_id = ? AND user_id = ?",
+            favorite, chat_id, user_id
+        ).execute(&pool).await.unwrap();
+    }
+    if let Some(sort_order) = request.sort_order {
+        query!(
+            "UPDATE chat_users SET sort_order = ? WHERE chat_id = ? AND user_id = ?",
+            sort_order, chat_id, user_id
+        ).execute(&pool).await.unwrap();
+    }
+    Json(Ok(String::from("1")))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_level_names() {
+        assert_eq!(serde_json::to_string(&NotificationLevel::Mentions).unwrap(), r#""mentions""#);
+        assert_eq!(serde_json::from_str::<NotificationLevel>(r#""none""#).unwrap(), NotificationLevel::None);
+        assert_eq!(NotificationLevel::parse("bogus"), NotificationLevel::All);
+        assert_eq!(NotificationLevel::parse(NotificationLevel::None.as_str()), NotificationLevel::None);
+    }
+
+    #[test]
+    fn test_mute_silences_notifications() {
+        let muted = ChatPreferences::new(NotificationLevel::All, Some(String::from("2030-01-01 00:00:00")), false, None);
+        assert_eq!(muted.notify, NotificationLevel::None);
+        let unmuted = ChatPreferences::new(NotificationLevel::Mentions, None, true, Some(1));
+        assert_eq!(unmuted.notify, NotificationLevel::Mentions);
+    }
+
+    #[test]
+    fn test_update_fields() {
+        let request: UpdatePreferencesRequest = serde_json::from_str(r#"{"favorite": true}"#).unwrap();
+        assert_eq!(request.favorite, Some(true));
+        assert_eq!(request.mute_for, None);
+        assert_eq!(request.sort_order, None);
+        let request: UpdatePreferencesRequest =
+            serde_json::from_str(r#"{"mute_for": null, "sort_order": null}"#).unwrap();
+        assert_eq!(request.mute_for, Some(None));
+        assert_eq!(request.sort_order, Some(None));
+        let request: UpdatePreferencesRequest = serde_json::from_str(r#"{"mute_for": "8h"}"#).unwrap();
+        assert_eq!(request.mute_for, Some(Some(String::from("8h"))));
+    }
+}
@@ -0,0 +1,275 @@
+use axum::{
+    extract::{Multipart, Path, State}, http::StatusCode, response::{IntoResponse, Json, Response},
+};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use sqlx::{query, SqlitePool};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::attachments::{self, Attachments};
+use crate::chat_metadata::moderated;
+use crate::filters::FilterChain;
+
+const MAX_DISPLAY_NAME_CHARS: usize = 64;
+const MAX_STATUS_CHARS: usize = 140;
+
+/// A user's public profile
+#[derive(Serialize)]
+pub(crate) struct Profile {
+    username: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    display_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timezone: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    local_time: Option<String>, // Current time in the user's time zone
+    #[serde(skip_serializing_if = "Option::is_none")]
+    avatar: Option<String>, // SHA-256 of the profile picture, fetched through /avatar
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    bot: bool,
+    created_at: String,
+}
+
+/// Profile fields to change; missing fields are left alone and empty text clears a field
+#[derive(Deserialize)]
+pub(crate) struct UpdateProfileRequest {
+    #[serde(default)]
+    display_name: Option<String>,
+    #[serde(default)]
+    status: Option<String>,
+    #[serde(default)]
+    timezone: Option<String>,
+}
+
+/// Checks the length of a one-line profile field; control characters such as line breaks are not allowed
+fn check_line(text: &str, max_chars: usize, field: &str) -> Result<(), String> {
+    if text.trim().chars().count() > max_chars {
+        return Err(format!("{} can be at most {} characters", field, max_chars));
+    }
+    if text.chars().any(char::is_control) {
+        return Err(format!("{} must be a single line", field));
+    }
+    Ok(())
+}
+
+/// Checks an IANA time zone name such as "Europe/Berlin"; empty text clears the time zone
+fn parse_timezone(name: &str) -> Result<Option<String>, String> {
+    match name.trim() {
+        "" => Ok(None),
+        name => name
+            .parse::<Tz>()
+            .map(|tz| Some(tz.name().to_string()))
+            .map_err(|_| format!("Unknown time zone {}, use a name such as Europe/Berlin", name)),
+    }
+}
+
+/// Current time in the time zone, None if the name is not a known zone
+fn local_time(timezone: &str) -> Option<String> {
+    let tz: Tz = timezone.parse().ok()?;
+    Some(chrono::Utc::now().with_timezone(&tz).format("%Y-%m-%d %H:%M").to_string())
+}
+
+/// Display names of the given users, for those that have one
+pub(crate) async fn display_names(usernames: &[&str], pool: &SqlitePool) -> HashMap<String, String> {
+    let names = serde_json::to_string(usernames).unwrap();
+    query!(
+        r#"SELECT username, display_name AS "display_name!" FROM users
+        WHERE display_name IS NOT NULL AND username IN (SELECT value FROM json_each(?))"#,
+        names
+    ).fetch_all(pool).await.unwrap()
+    .into_iter()
+    .map(|row| (row.username, row.display_name))
+    .collect()
+}
+
+/// Shows a user's profile
+/// # Query format:
+/// curl "http://98.93.98.244:80/profile/username/NameString"
+/// # Return format:
+/// "username", "display_name", "status", "timezone", "local_time", "avatar", "bot" and "created_at"
+pub(crate) async fn get_profile(
+    State(pool): State<SqlitePool>,
+    Path(username): Path<String>,
+) -> Json<Result<Profile, String>> {
+    let Some(row) = query!(
+        r#"SELECT username, display_name, status_text, timezone, avatar_sha256,
+        COALESCE(is_bot, 0) AS "bot!: bool", created_at AS "created_at: String"
+        FROM users WHERE username = ? AND COALESCE(role, '') != 'deleted'"#,
+        username
+    ).fetch_optional(&pool).await.unwrap() else {
+        return Json(Err(String::from("User not found")));
+    };
+    Json(Ok(Profile {
+        username: row.username,
+        display_name: row.display_name,
+        status: row.status_text,
+        local_time: row.timezone.as_deref().and_then(local_time),
+        timezone: row.timezone,
+        avatar: row.avatar_sha256,
+        bot: row.bot,
+        created_at: row.created_at.unwrap_or_default(),
+    }))
+}
+
+/// Updates the user's display name, status message and time zone; names and statuses go through moderation
+/// # Query format:
+/// curl -X POST -H "Content-Type: application/json" -d '{"display_name": "Alice Liddell", "status": "In meetings", "timezone": "Europe/London"}' "http://98.93.98.244:80/profile/username/NameString"
+pub(crate) async fn update_profile(
+    State(pool): State<SqlitePool>,
+    State(filters): State<Arc<FilterChain>>,
+    Path(username): Path<String>,
+    Json(request): Json<UpdateProfileRequest>,
+) -> Json<Result<String, String>> {
+    let Some(user) = query!(r#"SELECT id AS "id!" FROM users WHERE username = ?"#, username)
+        .fetch_optional(&pool)
+        .await
+        .unwrap() else {
+        return Json(Err(String::from("User not found")));
+    };
+    if let Some(display_name) = &request.display_name {
+        let display_name = match check_line(display_name, MAX_DISPLAY_NAME_CHARS, "Display name")
+            .and_then(|_| moderated(display_name, &filters))
+        {
+            Ok(display_name) => display_name,
+            Err(e) => return Json(Err(e)),
+        };
+        query!("UPDATE users SET display_name = ? WHERE id = ?", display_name, user.id)
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+    if let Some(status) = &request.status {
+        let status = match check_line(status, MAX_STATUS_CHARS, "Status").and_then(|_| moderated(status, &filters)) {
+            Ok(status) => status,
+            Err(e) => return Json(Err(e)),
+        };
+        query!("UPDATE users SET status_text = ? WHERE id = ?", status, user.id)
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+    if let Some(timezone) = &request.timezone {
+        let timezone = match parse_timezone(timezone) {
+            Ok(timezone) => timezone,
+            Err(e) => return Json(Err(e)),
+        };
+        query!("UPDATE users SET timezone = ? WHERE id = ?", timezone, user.id)
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+    println!("{} updated their profile", username);
+    Json(Ok(String::from("1")))
+}
+
+/// Sets the user's profile picture from the first file in the upload, replacing any earlier one
+/// # Query format:
+/// curl -F "file=@me.png" "http://98.93.98.244:80/profile/avatar/username/NameString"
+pub(crate) async fn upload_avatar(
+    State(pool): State<SqlitePool>,
+    State(attachments): State<Attachments>,
+    Path(username): Path<String>,
+    mut multipart: Multipart,
+) -> Json<Result<String, String>> {
+    let Some(user) = query!(r#"SELECT id AS "id!", avatar_sha256 FROM users WHERE username = ?"#, username)
+        .fetch_optional(&pool)
+        .await
+        .unwrap() else {
+        return Json(Err(String::from("User not found")));
+    };
+    let field = loop {
+        match multipart.next_field().await {
+            Ok(Some(field)) if field.file_name().is_some() => break field,
+            Ok(Some(_)) => continue,
+            Ok(None) => return Json(Err(String::from("No file uploaded"))),
+            Err(e) => return Json(Err(e.body_text())),
+        }
+    };
+    let content_type = field.content_type().unwrap_or("application/octet-stream").to_string();
+    let bytes = match field.bytes().await {
+        Ok(bytes) => bytes.to_vec(),
+        Err(e) => return Json(Err(e.body_text())),
+    };
+    let sha256 = match attachments::store_image(&attachments, &content_type, bytes).await {
+        Ok(sha256) => sha256,
+        Err(e) => return Json(Err(e)),
+    };
+    query!(
+        "UPDATE users SET avatar_sha256 = ?, avatar_content_type = ? WHERE id = ?",
+        sha256, content_type, user.id
+    ).execute(&pool).await.unwrap();
+    if let Some(previous) = user.avatar_sha256 {
+        attachments::remove_unreferenced(&attachments, vec![previous], &pool).await;
+    }
+    println!("{} uploaded a profile picture", username);
+    Json(Ok(sha256))
+}
+
+/// Removes the user's profile picture
+/// # Query format:
+/// curl -X DELETE "http://98.93.98.244:80/profile/avatar/username/NameString"
+pub(crate) async fn remove_avatar(
+    State(pool): State<SqlitePool>,
+    State(attachments): State<Attachments>,
+    Path(username): Path<String>,
+) -> Json<Result<String, String>> {
+    let Some(user) = query!(r#"SELECT id AS "id!", avatar_sha256 FROM users WHERE username = ?"#, username)
+        .fetch_optional(&pool)
+        .await
+        .unwrap() else {
+        return Json(Err(String::from("User not found")));
+    };
+    let Some(previous) = user.avatar_sha256 else {
+        return Json(Ok(String::from("0")));
+    };
+    query!("UPDATE users SET avatar_sha256 = NULL, avatar_content_type = NULL WHERE id = ?", user.id)
+        .execute(&pool)
+        .await
+        .unwrap();
+    attachments::remove_unreferenced(&attachments, vec![previous], &pool).await;
+    Json(Ok(String::from("1")))
+}
+
+/// Downloads a user's profile picture
+/// # Query format:
+/// curl -O "http://98.93.98.244:80/avatar/username/NameString"
+pub(crate) async fn download_avatar(
+    State(pool): State<SqlitePool>,
+    State(attachments): State<Attachments>,
+    Path(username): Path<String>,
+) -> Response {
+    let avatar = query!(
+        r#"SELECT avatar_sha256 AS "sha256!", avatar_content_type AS "content_type!" FROM users
+        WHERE username = ? AND avatar_sha256 IS NOT NULL AND avatar_content_type IS NOT NULL"#,
+        username
+    ).fetch_optional(&pool).await.unwrap();
+    match avatar {
+        Some(avatar) => attachments::blob_response(&attachments, avatar.sha256, avatar.content_type, None).await,
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_line_rules() {
+        assert!(check_line("Alice Liddell", MAX_DISPLAY_NAME_CHARS, "Display name").is_ok());
+        assert!(check_line(&"a".repeat(MAX_DISPLAY_NAME_CHARS + 1), MAX_DISPLAY_NAME_CHARS, "Display name").is_err());
+        assert!(check_line("two\nlines", MAX_STATUS_CHARS, "Status").is_err());
+        assert!(check_line("tab\there", MAX_STATUS_CHARS, "Status").is_err());
+    }
+
+    #[test]
+    fn test_timezones() {
+        assert_eq!(parse_timezone(" Europe/Berlin "), Ok(Some(String::from("Europe/Berlin"))));
+        assert_eq!(parse_timezone(""), Ok(None));
+        assert!(parse_timezone("Mars/Olympus").is_err());
+        assert!(local_time("UTC").is_some());
+        assert!(local_time("Nowhere").is_none());
+    }
+}
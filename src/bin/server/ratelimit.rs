@@ -0,0 +1,271 @@
+use axum::{
+    extract::{ConnectInfo, Path, Request, State}, http::{header, StatusCode}, middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Buckets kept before idle (full) ones are dropped
+const MAX_TRACKED_KEYS: usize = 10_000;
+/// Consecutive failed logins before an account is locked
+const MAX_FAILED_LOGINS: u32 = 5;
+const LOCKOUT_DURATION: Duration = Duration::from_secs(15 * 60);
+
+/// Allows `capacity` requests in a burst, refilled evenly over `period`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Limit {
+    capacity: f64,
+    period: Duration,
+}
+
+impl Limit {
+    pub(crate) const fn new(capacity: u32, period: Duration) -> Self {
+        Limit { capacity: capacity as f64, period }
+    }
+
+    /// Parses "N/S", N requests per S seconds, as used by the RATE_LIMIT_* variables
+    fn parse(value: &str) -> Option<Self> {
+        let (count, seconds) = value.split_once('/')?;
+        let count: u32 = count.trim().parse().ok()?;
+        let seconds: u64 = seconds.trim().parse().ok()?;
+        if count == 0 || seconds == 0 {
+            return None;
+        }
+        Some(Limit::new(count, Duration::from_secs(seconds)))
+    }
+
+    fn from_env(name: &str, default: Limit) -> Self {
+        std::env::var(name).ok().and_then(|v| Limit::parse(&v)).unwrap_or(default)
+    }
+
+    fn refill_per_sec(&self) -> f64 {
+        self.capacity / self.period.as_secs_f64()
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token buckets for one kind of request, keyed by user or client IP
+pub(crate) struct RateLimiter {
+    limit: Limit,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(limit: Limit) -> Self {
+        RateLimiter { limit, buckets: Mutex::new(HashMap::new()) }
+    }
+
+    /// Takes one token from every key's bucket, or returns how long until all of them have one.
+    /// Nothing is taken unless every key can pay, so a rejected request costs nothing
+    fn check(&self, keys: &[String], now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() > MAX_TRACKED_KEYS {
+            let limit = self.limit;
+            buckets.retain(|_, b| Self::refilled(limit, b, now) < limit.capacity);
+        }
+        let mut wait = Duration::ZERO;
+        for key in keys {
+            let bucket = buckets.entry(key.clone()).or_insert(Bucket { tokens: self.limit.capacity, updated: now });
+            bucket.tokens = Self::refilled(self.limit, bucket, now);
+            bucket.updated = now;
+            if bucket.tokens < 1.0 {
+                let seconds = (1.0 - bucket.tokens) / self.limit.refill_per_sec();
+                wait = wait.max(Duration::from_secs_f64(seconds));
+            }
+        }
+        if wait > Duration::ZERO {
+            return Err(wait);
+        }
+        for key in keys {
+            buckets.get_mut(key).unwrap().tokens -= 1.0;
+        }
+        Ok(())
+    }
+
+    fn refilled(limit: Limit, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * limit.refill_per_sec()).min(limit.capacity)
+    }
+}
+
+/// Consecutive failed logins per username, locking the account once they reach the limit
+#[derive(Default)]
+pub(crate) struct LoginLockout {
+    failures: Mutex<HashMap<String, (u32, Option<Instant>)>>,
+}
+
+impl LoginLockout {
+    /// How long the account stays locked, None if it is not locked
+    fn locked_for(&self, username: &str, now: Instant) -> Option<Duration> {
+        let failures = self.failures.lock().unwrap();
+        let until = failures.get(username)?.1?;
+        (until > now).then(|| until - now)
+    }
+
+    /// Counts a failed login, returns true if this failure locked the account
+    pub(crate) fn record_failure(&self, username: &str) -> bool {
+        self.record_failure_at(username, Instant::now())
+    }
+
+    fn record_failure_at(&self, username: &str, now: Instant) -> bool {
+        let mut failures = self.failures.lock().unwrap();
+        let entry = failures.entry(username.to_string()).or_insert((0, None));
+        if entry.1.is_some_and(|until| until <= now) {
+            *entry = (0, None); // The previous lockout expired, start counting again
+        }
+        entry.0 += 1;
+        if entry.0 >= MAX_FAILED_LOGINS && entry.1.is_none() {
+            entry.1 = Some(now + LOCKOUT_DURATION);
+            return true;
+        }
+        false
+    }
+
+    /// Clears the failure count after a successful login
+    pub(crate) fn record_success(&self, username: &str) {
+        self.failures.lock().unwrap().remove(username);
+    }
+}
+
+/// Rate limits for each protected route, configured with RATE_LIMIT_LOGIN, RATE_LIMIT_CREATE_ACCOUNT
+/// and RATE_LIMIT_MESSAGE as "N/S" (N requests per S seconds)
+#[derive(Clone)]
+pub(crate) struct RateLimits {
+    login: Arc<RateLimiter>,
+    create_account: Arc<RateLimiter>,
+    message: Arc<RateLimiter>,
+    pub(crate) lockout: Arc<LoginLockout>,
+}
+
+impl RateLimits {
+    pub(crate) fn from_env() -> Self {
+        RateLimits {
+            login: Arc::new(RateLimiter::new(Limit::from_env("RATE_LIMIT_LOGIN", Limit::new(10, Duration::from_secs(60))))),
+            create_account: Arc::new(RateLimiter::new(Limit::from_env(
+                "RATE_LIMIT_CREATE_ACCOUNT",
+                Limit::new(3, Duration::from_secs(60 * 60)),
+            ))),
+            message: Arc::new(RateLimiter::new(Limit::from_env("RATE_LIMIT_MESSAGE", Limit::new(20, Duration::from_secs(10))))),
+            lockout: Arc::new(LoginLockout::default()),
+        }
+    }
+}
+
+/// 429 response telling the client when to retry
+fn too_many_requests(wait: Duration) -> Response {
+    let seconds = wait.as_secs_f64().ceil().max(1.0) as u64;
+    (StatusCode::TOO_MANY_REQUESTS, [(header::RETRY_AFTER, seconds.to_string())], "Too many requests").into_response()
+}
+
+fn limit_keys(addr: &SocketAddr, username: Option<&String>) -> Vec<String> {
+    let mut keys = vec![format!("ip:{}", addr.ip())];
+    if let Some(username) = username {
+        keys.push(format!("user:{}", username));
+    }
+    keys
+}
+
+/// Middleware for /Authenticate and the other routes that check a password: rejects locked accounts,
+/// then limits attempts per user and per IP
+pub(crate) async fn limit_login(
+    State(limits): State<RateLimits>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(params): Path<HashMap<String, String>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let username = params.get("name");
+    if let Some(wait) = username.and_then(|u| limits.lockout.locked_for(u, Instant::now())) {
+        return too_many_requests(wait);
+    }
+    match limits.login.check(&limit_keys(&addr, username), Instant::now()) {
+        Ok(()) => next.run(request).await,
+        Err(wait) => too_many_requests(wait),
+    }
+}
+
+/// Middleware for /createaccount: limits new accounts per IP
+pub(crate) async fn limit_create_account(
+    State(limits): State<RateLimits>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    match limits.create_account.check(&limit_keys(&addr, None), Instant::now()) {
+        Ok(()) => next.run(request).await,
+        Err(wait) => too_many_requests(wait),
+    }
+}
+
+/// Middleware for /newmessage: limits posting per user and per IP
+pub(crate) async fn limit_message(
+    State(limits): State<RateLimits>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(params): Path<HashMap<String, String>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    match limits.message.check(&limit_keys(&addr, params.get("user")), Instant::now()) {
+        Ok(()) => next.run(request).await,
+        Err(wait) => too_many_requests(wait),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn keys(list: &[&str]) -> Vec<String> {
+        list.iter().map(|k| k.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_limit() {
+        assert_eq!(Limit::parse("5/60"), Some(Limit::new(5, Duration::from_secs(60))));
+        assert_eq!(Limit::parse("0/60"), None);
+        assert_eq!(Limit::parse("five"), None);
+    }
+
+    #[test]
+    fn test_bucket_refills() {
+        let limiter = RateLimiter::new(Limit::new(2, Duration::from_secs(10)));
+        let start = Instant::now();
+        let key = keys(&["ip:1.2.3.4"]);
+        assert!(limiter.check(&key, start).is_ok());
+        assert!(limiter.check(&key, start).is_ok());
+        let wait = limiter.check(&key, start).unwrap_err();
+        assert_eq!(wait, Duration::from_secs(5));
+        assert!(limiter.check(&key, start + Duration::from_secs(5)).is_ok());
+    }
+
+    #[test]
+    fn test_rejected_request_costs_nothing() {
+        let limiter = RateLimiter::new(Limit::new(1, Duration::from_secs(10)));
+        let now = Instant::now();
+        assert!(limiter.check(&keys(&["user:alice"]), now).is_ok());
+        // The IP still has its token because alice's empty bucket rejected the request
+        assert!(limiter.check(&keys(&["ip:1.2.3.4", "user:alice"]), now).is_err());
+        assert!(limiter.check(&keys(&["ip:1.2.3.4", "user:bob"]), now).is_ok());
+    }
+
+    #[test]
+    fn test_lockout() {
+        let lockout = LoginLockout::default();
+        let now = Instant::now();
+        for _ in 1..MAX_FAILED_LOGINS {
+            assert!(!lockout.record_failure_at("alice", now));
+        }
+        assert!(lockout.locked_for("alice", now).is_none());
+        assert!(lockout.record_failure_at("alice", now));
+        assert_eq!(lockout.locked_for("alice", now), Some(LOCKOUT_DURATION));
+        assert!(lockout.locked_for("alice", now + LOCKOUT_DURATION).is_none());
+        lockout.record_success("alice");
+        assert!(lockout.locked_for("alice", now).is_none());
+    }
+}
@@ -0,0 +1,141 @@
+use axum::{extract::{Path, State}, response::Json};
+use serde::{Deserialize, Serialize};
+use sqlx::{query, SqlitePool};
+use std::collections::HashMap;
+
+use crate::events::{publish, ChatEvent, EventSender};
+use crate::lookup_member;
+
+/// Longest emoji accepted, in characters; enough for skin tones and ZWJ sequences
+const MAX_EMOJI_CHARS: usize = 16;
+
+/// Aggregated reactions for one emoji on a message, attached to history responses
+#[derive(Deserialize, Serialize, Clone)]
+pub(crate) struct ReactionSummary {
+    pub(crate) emoji: String,
+    pub(crate) count: i64,
+    pub(crate) reacted_by_me: bool,
+}
+
+/// Rejects empty, oversized or whitespace-containing reactions
+fn valid_emoji(emoji: &str) -> bool {
+    let length = emoji.chars().count();
+    length > 0 && length <= MAX_EMOJI_CHARS && !emoji.chars().any(char::is_whitespace)
+}
+
+/// Looks up the chat and user ids for a reaction and checks that the message was posted in the chat and the user
+/// is a member. Scheduled and rejected messages are treated as missing, so reactions cannot reveal them
+async fn reaction_target(
+    chatname: &str,
+    username: &str,
+    message_id: i64,
+    pool: &SqlitePool,
+) -> Result<(i64, i64), String> {
+    let (chat_id, user_id) = lookup_member(chatname, username, pool).await?;
+    let in_chat = sqlx::query_scalar!(
+        "SELECT EXISTS(SELECT 1 FROM messages WHERE id = ? AND chat_id = ? AND status = 'Sent!') AS _exists",
+        message_id, chat_id
+    ).fetch_one(pool).await.unwrap();
+    if in_chat == 0 {
+        return Err(String::from("Message not found"));
+    }
+    Ok((chat_id, user_id))
+}
+
+/// Adds a reaction to a message; reacting twice with the same emoji is a no-op
+/// # Query format:
+/// curl -X POST "http://98.93.98.244:80/reaction/chatname/ChatName/username/UsernameString/messageid/42/emoji/%F0%9F%91%8D"
+pub(crate) async fn add_reaction(
+    State(pool): State<SqlitePool>,
+    State(events): State<EventSender>,
+    Path((chatname, username, message_id, emoji)): Path<(String, String, i64, String)>,
+) -> Json<Result<String, String>> {
+    if !valid_emoji(&emoji) {
+        return Json(Err(String::from("Invalid emoji")));
+    }
+    let (chat_id, user_id) = match reaction_target(&chatname, &username, message_id, &pool).await {
+        Ok(ids) => ids,
+        Err(e) => return Json(Err(e)),
+    };
+    let result = query!(
+        r#"INSERT OR IGNORE INTO reactions (message_id, user_id, emoji, created_at)
+        VALUES (?, ?, ?, datetime('now'))"#, message_id, user_id, emoji
+    ).execute(&pool).await.unwrap();
+    if result.rows_affected() == 0 {
+        return Json(Ok(String::from("0")));
+    }
+    println!("{} reacted {} to message {}", username, emoji, message_id);
+    publish(&events, ChatEvent::Reaction { chat_id, message_id, username, emoji, added: true });
+    Json(Ok(String::from("1")))
+}
+
+/// Removes a reaction the user previously added
+/// # Query format:
+/// curl -X DELETE "http://98.93.98.244:80/reaction/chatname/ChatName/username/UsernameString/messageid/42/emoji/%F0%9F%91%8D"
+pub(crate) async fn remove_reaction(
+    State(pool): State<SqlitePool>,
+    State(events): State<EventSender>,
+    Path((chatname, username, message_id, emoji)): Path<(String, String, i64, String)>,
+) -> Json<Result<String, String>> {
+    let (chat_id, user_id) = match reaction_target(&chatname, &username, message_id, &pool).await {
+        Ok(ids) => ids,
+        Err(e) => return Json(Err(e)),
+    };
+    let result = query!(
+        "DELETE FROM reactions WHERE message_id = ? AND user_id = ? AND emoji = ?",
+        message_id, user_id, emoji
+    ).execute(&pool).await.unwrap();
+    if result.rows_affected() == 0 {
+        return Json(Ok(String::from("0")));
+    }
+    publish(&events, ChatEvent::Reaction { chat_id, message_id, username, emoji, added: false });
+    Json(Ok(String::from("1")))
+}
+
+/// Aggregates reaction counts for every message in a chat, keyed by message id.
+/// `reacted_by_me` is set for emojis the given user has used; it is always false without a user
+pub(crate) async fn reaction_summaries(
+    chat_id: i64,
+    username: Option<&str>,
+    pool: &SqlitePool,
+) -> HashMap<i64, Vec<ReactionSummary>> {
+    let rows = query!(
+        r#"SELECT reactions.message_id AS "message_id!", reactions.emoji AS "emoji!", COUNT(*) AS "count!: i64",
+            COALESCE(MAX(users.username = ?), 0) AS "reacted_by_me!: bool"
+        FROM reactions
+        JOIN messages ON messages.id = reactions.message_id
+        JOIN users ON users.id = reactions.user_id
+        WHERE messages.chat_id = ?
+        GROUP BY reactions.message_id, reactions.emoji
+        ORDER BY MIN(reactions.id)"#,
+        username, chat_id
+    ).fetch_all(pool).await.unwrap();
+    let mut summaries: HashMap<i64, Vec<ReactionSummary>> = HashMap::new();
+    for row in rows {
+        summaries.entry(row.message_id).or_default().push(ReactionSummary {
+            emoji: row.emoji,
+            count: row.count,
+            reacted_by_me: row.reacted_by_me,
+        });
+    }
+    summaries
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_valid_emoji() {
+        assert!(valid_emoji("👍"));
+        assert!(valid_emoji("👍🏽"));
+        assert!(valid_emoji(":+1:"));
+    }
+
+    #[test]
+    fn test_invalid_emoji() {
+        assert!(!valid_emoji(""));
+        assert!(!valid_emoji("thumbs up"));
+        assert!(!valid_emoji("abcdefghijklmnopq")); // 17 characters
+    }
+}
@@ -0,0 +1,104 @@
+use axum::{extract::{Path, State}, response::Json};
+use sqlx::{query, SqlitePool};
+
+use crate::events::{publish, ChatEvent, EventSender};
+use crate::lookup_member;
+use crate::mentions::mark_mentions_read;
+
+/// Largest chat, in members, whose history responses include per-message "seen by" lists
+const SEEN_BY_MAX_MEMBERS: usize = 10;
+
+/// A member's read position, used to work out who has seen each message
+pub(crate) struct ReadPosition {
+    pub(crate) username: String,
+    pub(crate) last_read_message_id: i64,
+}
+
+/// Marks every delivered message in a chat as read by the user, along with any mentions of them in it
+/// # Query format:
+/// curl -X POST "http://98.93.98.244:80/markread/chatname/ChatName/username/UsernameString"
+/// # Return format:
+/// Id of the newest message now marked read, "0" if the chat has no messages
+pub(crate) async fn mark_read(
+    State(pool): State<SqlitePool>,
+    State(events): State<EventSender>,
+    Path((chatname, username)): Path<(String, String)>,
+) -> Json<Result<String, String>> {
+    let (chat_id, user_id) = match lookup_member(&chatname, &username, &pool).await {
+        Ok(ids) => ids,
+        Err(e) => return Json(Err(e)),
+    };
+    let latest = query!(
+        r#"SELECT COALESCE(MAX(id), 0) AS "latest!: i64" FROM messages WHERE chat_id = ? AND status = 'Sent!'"#,
+        chat_id
+    ).fetch_one(&pool).await.unwrap().latest;
+    // Never move the read position backwards if an older request arrives late
+    let result = query!(
+        r#"UPDATE chat_users SET last_read_message_id = ?
+        WHERE chat_id = ? AND user_id = ? AND COALESCE(last_read_message_id, 0) < ?"#,
+        latest, chat_id, user_id, latest
+    ).execute(&pool).await.unwrap();
+    mark_mentions_read(chat_id, user_id, latest, &pool).await;
+    if result.rows_affected() > 0 {
+        publish(&events, ChatEvent::Read { chat_id, username, message_id: latest });
+    }
+    Json(Ok(latest.to_string()))
+}
+
+/// Counts delivered messages from other members newer than the user's read position
+pub(crate) async fn unread_count(chat_id: i64, user_id: i64, pool: &SqlitePool) -> i64 {
+    query!(
+        r#"SELECT COUNT(*) AS "unread!: i64" FROM messages
+        WHERE chat_id = ? AND user_id != ? AND status = 'Sent!'
+        AND id > COALESCE((SELECT last_read_message_id FROM chat_users WHERE chat_id = ? AND user_id = ?), 0)"#,
+        chat_id, user_id, chat_id, user_id
+    ).fetch_one(pool).await.unwrap().unread
+}
+
+/// Read positions of every member, or None when the chat is too large for "seen by" lists
+pub(crate) async fn read_positions(chat_id: i64, pool: &SqlitePool) -> Option<Vec<ReadPosition>> {
+    let positions: Vec<ReadPosition> = query!(
+        r#"SELECT users.username, COALESCE(chat_users.last_read_message_id, 0) AS "last_read_message_id!: i64"
+        FROM chat_users JOIN users ON users.id = chat_users.user_id
+        WHERE chat_users.chat_id = ?"#,
+        chat_id
+    ).fetch_all(pool).await.unwrap()
+    .into_iter()
+    .map(|row| ReadPosition { username: row.username, last_read_message_id: row.last_read_message_id })
+    .collect();
+    if positions.len() > SEEN_BY_MAX_MEMBERS {
+        return None;
+    }
+    Some(positions)
+}
+
+/// Members other than the author whose read position has reached the message
+pub(crate) fn seen_by(message_id: i64, author: &str, positions: &[ReadPosition]) -> Vec<String> {
+    positions
+        .iter()
+        .filter(|p| p.username != author && p.last_read_message_id >= message_id)
+        .map(|p| p.username.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn position(username: &str, last_read_message_id: i64) -> ReadPosition {
+        ReadPosition { username: username.to_string(), last_read_message_id }
+    }
+
+    #[test]
+    fn test_seen_by() {
+        let positions = vec![position("alice", 5), position("bob", 3), position("carol", 0)];
+        assert_eq!(seen_by(3, "carol", &positions), vec!["alice", "bob"]);
+        assert_eq!(seen_by(4, "carol", &positions), vec!["alice"]);
+    }
+
+    #[test]
+    fn test_seen_by_excludes_author() {
+        let positions = vec![position("alice", 5), position("bob", 5)];
+        assert_eq!(seen_by(5, "alice", &positions), vec!["bob"]);
+    }
+}
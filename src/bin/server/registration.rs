@@ -0,0 +1,252 @@
+use axum::{extract::{ConnectInfo, Path, State}, http::StatusCode, response::Json};
+use axum_extra::extract::Query;
+use rand::{distributions::Alphanumeric, Rng};
+use serde::Deserialize;
+use sqlx::{query, SqlitePool};
+use std::net::SocketAddr;
+
+use crate::accounts::{hash_password, verify_credentials};
+use crate::audit::{self, AuditEvent};
+use crate::ratelimit::RateLimits;
+
+const MIN_USERNAME_LENGTH: usize = 3;
+const MAX_USERNAME_LENGTH: usize = 32;
+const DEFAULT_MIN_PASSWORD_LENGTH: usize = 8;
+/// Names that could be mistaken for the server, staff or a mention keyword
+const RESERVED_USERNAMES: [&str; 10] = [
+    "admin", "administrator", "root", "system", "server", "moderator", "support", "here", "all", "everyone",
+];
+/// Prefix of the placeholder names given to deleted accounts
+const DELETED_USER_PREFIX: &str = "deleted-user";
+const INVITE_CODE_LENGTH: usize = 12;
+/// Error message raised by the case-insensitive username triggers in chat_database.sql
+pub(crate) const USERNAME_TAKEN: &str = "Username is already taken";
+
+/// Whether a name is kept back for the server itself or for deleted accounts, ignoring letter case
+pub(crate) fn is_reserved(name: &str) -> bool {
+    let lowercase = name.to_ascii_lowercase();
+    RESERVED_USERNAMES.contains(&lowercase.as_str()) || lowercase.starts_with(DELETED_USER_PREFIX)
+}
+
+/// Whether anyone may register or only holders of an invite code
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum RegistrationMode {
+    Open,
+    InviteOnly,
+}
+
+/// Rules for new accounts and passwords, read from REGISTRATION_MODE ("open" or "invite")
+/// and PASSWORD_MIN_LENGTH at startup
+#[derive(Clone)]
+pub(crate) struct RegistrationPolicy {
+    mode: RegistrationMode,
+    min_password_length: usize,
+}
+
+impl RegistrationPolicy {
+    pub(crate) fn from_env() -> Self {
+        let mode = match std::env::var("REGISTRATION_MODE").as_deref() {
+            Ok("invite") => RegistrationMode::InviteOnly,
+            _ => RegistrationMode::Open,
+        };
+        let min_password_length = std::env::var("PASSWORD_MIN_LENGTH")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_MIN_PASSWORD_LENGTH);
+        RegistrationPolicy { mode, min_password_length }
+    }
+
+    /// Checks a username against the character, length and reserved-name rules
+    pub(crate) fn check_username(&self, username: &str) -> Result<(), String> {
+        let length = username.chars().count();
+        if !(MIN_USERNAME_LENGTH..=MAX_USERNAME_LENGTH).contains(&length) {
+            return Err(format!(
+                "Username must be {} to {} characters long", MIN_USERNAME_LENGTH, MAX_USERNAME_LENGTH
+            ));
+        }
+        if !username.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.')) {
+            return Err(String::from("Username may only contain letters, digits, '_', '-' and '.'"));
+        }
+        if !username.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+            return Err(String::from("Username must start with a letter or digit"));
+        }
+        if is_reserved(username) {
+            return Err(String::from("Username is reserved"));
+        }
+        Ok(())
+    }
+
+    /// Requires a minimum length, at least two kinds of character (lowercase, uppercase, digits, symbols)
+    /// and that the password is not just the username
+    pub(crate) fn check_password(&self, username: &str, password: &str) -> Result<(), String> {
+        if password.chars().count() < self.min_password_length {
+            return Err(format!("Password must be at least {} characters long", self.min_password_length));
+        }
+        let classes = [
+            password.chars().any(|c| c.is_lowercase()),
+            password.chars().any(|c| c.is_uppercase()),
+            password.chars().any(|c| c.is_ascii_digit()),
+            password.chars().any(|c| !c.is_alphanumeric()),
+        ];
+        if classes.iter().filter(|&&present| present).count() < 2 {
+            return Err(String::from("Password must mix at least two of lowercase, uppercase, digits and symbols"));
+        }
+        if password.to_lowercase().contains(&username.to_lowercase()) {
+            return Err(String::from("Password must not contain the username"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct RegisterParams {
+    invite: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct CreateInviteRequest {
+    admin_password: String,
+}
+
+/// Random code handed out by an admin to let one person register
+fn generate_invite_code() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(INVITE_CODE_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+/// True if the database refused the insert because the name is taken in some letter case
+pub(crate) fn is_username_conflict(error: &sqlx::Error) -> bool {
+    error
+        .as_database_error()
+        .is_some_and(|e| e.is_unique_violation() || e.message() == USERNAME_TAKEN)
+}
+
+/// Creates new user. Usernames are 3 to 32 letters, digits, '_', '-' or '.', unique regardless of case;
+/// when registration is invite-only an unused invite code is required
+/// # Query format:
+/// curl "http://98.93.98.244:80/createaccount/username/NameString/password/PasswordString?invite=InviteCode"
+/// # Return format:
+/// "1" on success; an error with status 400 for an invalid name or weak password, 403 for a missing
+/// or used invite and 409 if the name is taken
+pub(crate) async fn new_user(
+    State(pool): State<SqlitePool>,
+    State(policy): State<RegistrationPolicy>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path((username, password)): Path<(String, String)>,
+    Query(params): Query<RegisterParams>,
+) -> (StatusCode, Json<Result<String, String>>) {
+    if let Err(e) = policy
+        .check_username(&username)
+        .and_then(|_| policy.check_password(&username, &password))
+    {
+        return (StatusCode::BAD_REQUEST, Json(Err(e)));
+    }
+    let invite = match (policy.mode, params.invite) {
+        (RegistrationMode::InviteOnly, None) => {
+            return (StatusCode::FORBIDDEN, Json(Err(String::from("Registration requires an invite code"))));
+        }
+        (RegistrationMode::InviteOnly, Some(code)) => Some(code),
+        (RegistrationMode::Open, _) => None,
+    };
+    let password_hash = hash_password(&password);
+    let role: String = String::from("chatter");
+    // The insert and the invite claim commit together, so a failed claim leaves no account behind
+    let mut tx = pool.begin().await.unwrap();
+    let user_id = match query!(
+        r#"INSERT INTO users (username, password, role, created_at)
+        VALUES (?, ?, ?, datetime('now'))"#, username, password_hash, role
+    ).execute(&mut *tx).await {
+        Ok(result) => result.last_insert_rowid(),
+        Err(e) if is_username_conflict(&e) => {
+            return (StatusCode::CONFLICT, Json(Err(String::from(USERNAME_TAKEN))));
+        }
+        Err(e) => panic!("{}", e),
+    };
+    let invited = invite.is_some();
+    if let Some(code) = invite {
+        let claimed = query!(
+            "UPDATE invites SET used_by = ?, used_at = datetime('now') WHERE code = ? AND used_by IS NULL",
+            user_id, code
+        ).execute(&mut *tx).await.unwrap();
+        if claimed.rows_affected() == 0 {
+            return (StatusCode::FORBIDDEN, Json(Err(String::from("Invalid or used invite code"))));
+        }
+    }
+    tx.commit().await.unwrap();
+    let detail = invited.then_some("Invited");
+    audit::record(AuditEvent::AccountCreated, Some(user_id), Some(user_id), None, Some(addr.ip()), detail, &pool).await;
+    println!("Create new user {}", username);
+    (StatusCode::OK, Json(Ok(String::from("1"))))
+}
+
+/// Creates an invite code for invite-only registration; only admins may do this
+/// # Query format:
+/// curl -X POST -H "Content-Type: application/json" -d '{"admin_password": "AdminPass"}' "http://98.93.98.244:80/createinvite/admin/AdminName"
+/// # Return format:
+/// The invite code, to be passed as "?invite=" when creating the account
+pub(crate) async fn create_invite(
+    State(pool): State<SqlitePool>,
+    State(limits): State<RateLimits>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(admin): Path<String>,
+    Json(request): Json<CreateInviteRequest>,
+) -> Json<Result<String, String>> {
+    let admin_id = match verify_credentials(&admin, &request.admin_password, &pool).await {
+        Some((id, role)) if role == "admin" => id,
+        Some(_) => return Json(Err(String::from("Only admins can create invites"))),
+        None => {
+            limits.lockout.record_failure(&admin);
+            return Json(Err(String::from("Incorrect username or password")));
+        }
+    };
+    let code = generate_invite_code();
+    let invite_id = query!(
+        "INSERT INTO invites (code, created_by, created_at) VALUES (?, ?, datetime('now'))",
+        code, admin_id
+    ).execute(&pool).await.unwrap().last_insert_rowid();
+    let detail = format!("Invite {}", invite_id);
+    audit::record(AuditEvent::InviteCreated, Some(admin_id), None, None, Some(addr.ip()), Some(&detail), &pool).await;
+    println!("{} created an invite", admin);
+    Json(Ok(code))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn policy() -> RegistrationPolicy {
+        RegistrationPolicy { mode: RegistrationMode::Open, min_password_length: DEFAULT_MIN_PASSWORD_LENGTH }
+    }
+
+    #[test]
+    fn test_username_rules() {
+        let policy = policy();
+        assert!(policy.check_username("alice_01").is_ok());
+        assert!(policy.check_username("Bob.Smith-2").is_ok());
+        assert!(policy.check_username("al").is_err());
+        assert!(policy.check_username(&"a".repeat(MAX_USERNAME_LENGTH + 1)).is_err());
+        assert!(policy.check_username("alice smith").is_err());
+        assert!(policy.check_username("ålice").is_err());
+        assert!(policy.check_username("_alice").is_err());
+    }
+
+    #[test]
+    fn test_reserved_usernames() {
+        let policy = policy();
+        assert!(policy.check_username("Admin").is_err());
+        assert!(policy.check_username("here").is_err());
+        assert!(policy.check_username("deleted-user-7").is_err());
+    }
+
+    #[test]
+    fn test_password_strength() {
+        let policy = policy();
+        assert!(policy.check_password("alice", "correct-horse").is_ok());
+        assert!(policy.check_password("alice", "Short1").is_err());
+        assert!(policy.check_password("alice", "alllowercase").is_err());
+        assert!(policy.check_password("alice", "Alice2024!").is_err());
+    }
+}
@@ -0,0 +1,376 @@
+use axum::{extract::{ConnectInfo, Path, State}, response::Json};
+use serde::{Deserialize, Serialize};
+use sqlx::{query, SqlitePool};
+use std::net::SocketAddr;
+
+use crate::accounts::verify_credentials;
+use crate::attachments::Attachments;
+use crate::commands::parse_duration;
+use crate::events::EventSender;
+use crate::ratelimit::RateLimits;
+use crate::{chat_role, is_chat_member, moderation, retention};
+
+/// Where a report is in the moderation queue
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ReportStatus {
+    Open,     // Waiting for a moderator
+    Claimed,  // A moderator is looking at it
+    Resolved, // Handled, see the outcome
+}
+
+impl ReportStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            ReportStatus::Open => "open",
+            ReportStatus::Claimed => "claimed",
+            ReportStatus::Resolved => "resolved",
+        }
+    }
+}
+
+/// What a moderator did about a report
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Outcome {
+    Dismiss, // Nothing wrong with the message
+    Delete,  // The message is deleted
+    Mute,    // The author is muted in the chat
+    Ban,     // The author is banned from the chat
+}
+
+impl Outcome {
+    /// How the outcome is stored and reported
+    fn as_str(self) -> &'static str {
+        match self {
+            Outcome::Dismiss => "dismissed",
+            Outcome::Delete => "deleted",
+            Outcome::Mute => "muted",
+            Outcome::Ban => "banned",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ReportRequest {
+    reason: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ClaimRequest {
+    password: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ResolveRequest {
+    password: String,
+    outcome: Outcome,
+    #[serde(default)]
+    duration: Option<String>, // Required for mutes, bans without one are permanent
+    #[serde(default)]
+    note: Option<String>, // Why, for the other moderators; also recorded as the reason of a mute or ban
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ListReportsRequest {
+    password: String,
+    #[serde(default)]
+    status: Option<ReportStatus>,
+    #[serde(default)]
+    chatname: Option<String>,
+}
+
+/// A report as shown in the moderation queue
+#[derive(Serialize)]
+pub(crate) struct Report {
+    id: i64,
+    message_id: i64, // The reported message, which may since have been deleted
+    chatname: String,
+    author: String,
+    content: String, // The message's text when it was reported
+    reporter: String,
+    reason: String,
+    created_at: String,
+    status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    claimed_by: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    claimed_at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    resolved_by: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    resolved_at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    outcome: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    note: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    action_id: Option<i64>, // The mute or ban in the moderation log
+}
+
+async fn user_id(username: &str, pool: &SqlitePool) -> Option<i64> {
+    query!(r#"SELECT id AS "id!" FROM users WHERE username = ?"#, username)
+        .fetch_optional(pool)
+        .await
+        .unwrap()
+        .map(|row| row.id)
+}
+
+/// Global moderators handle every report, chat owners and admins those from their own chats
+async fn handles_chat(chat_id: i64, user_id: i64, pool: &SqlitePool) -> bool {
+    let role = chat_role(chat_id, user_id, pool).await.unwrap_or_default();
+    moderation::rank(&role) > 0 || moderation::is_global_moderator(user_id, pool).await
+}
+
+/// Reports a message to the chat's moderators; only members of the chat may report, and each member
+/// can report a message once
+/// # Query format:
+/// curl -X POST -H "Content-Type: application/json" -d '{"reason": "Spam"}' "http://98.93.98.244:80/report/messageid/42/username/UsernameString"
+/// # Return format:
+/// The report's id, or "0" if the user already reported the message
+pub(crate) async fn report_message(
+    State(pool): State<SqlitePool>,
+    Path((message_id, username)): Path<(i64, String)>,
+    Json(request): Json<ReportRequest>,
+) -> Json<Result<String, String>> {
+    let Some(reporter_id) = user_id(&username, &pool).await else {
+        return Json(Err(String::from("User not found")));
+    };
+    let message = query!(
+        r#"SELECT messages.chat_id AS "chat_id!", messages.user_id AS "user_id!", messages.content AS "content!"
+        FROM messages JOIN chats ON chats.id = messages.chat_id
+        WHERE messages.id = ? AND messages.status = 'Sent!' AND chats.deleted_at IS NULL"#,
+        message_id
+    ).fetch_optional(&pool).await.unwrap();
+    let Some(message) = message else {
+        return Json(Err(String::from("Message not found")));
+    };
+    // Messages in chats the user is not in are reported the same way as missing ones
+    if !is_chat_member(message.chat_id, reporter_id, &pool).await {
+        return Json(Err(String::from("Message not found")));
+    }
+    if message.user_id == reporter_id {
+        return Json(Err(String::from("You cannot report your own message")));
+    }
+    let reason = match moderation::check_reason(Some(&request.reason)) {
+        Ok(Some(reason)) => reason,
+        Ok(None) => return Json(Err(String::from("Say why you are reporting the message"))),
+        Err(e) => return Json(Err(e)),
+    };
+    let result = query!(
+        r#"INSERT OR IGNORE INTO reports (message_id, chat_id, author_id, content, reporter_id, reason, created_at, status)
+        VALUES (?, ?, ?, ?, ?, ?, datetime('now'), 'open')"#,
+        message_id, message.chat_id, message.user_id, message.content, reporter_id, reason
+    ).execute(&pool).await.unwrap();
+    if result.rows_affected() == 0 {
+        return Json(Ok(String::from("0")));
+    }
+    println!("{} reported message {}", username, message_id);
+    Json(Ok(result.last_insert_rowid().to_string()))
+}
+
+/// Lists the reports the user can handle, oldest first so the queue is worked in order. Without a status,
+/// open and claimed reports are listed; "chatname" narrows the list to one chat
+/// # Query format:
+/// curl -X POST -H "Content-Type: application/json" -d '{"password": "PasswordString", "status": "open"}' "http://98.93.98.244:80/reports/username/UsernameString"
+/// # Return format:
+/// Array of "id", "message_id", "chatname", "author", "content", "reporter", "reason", "created_at", "status",
+/// "claimed_by", "claimed_at", "resolved_by", "resolved_at", "outcome", "note" and "action_id"
+pub(crate) async fn list_reports(
+    State(pool): State<SqlitePool>,
+    State(limits): State<RateLimits>,
+    Path(username): Path<String>,
+    Json(params): Json<ListReportsRequest>,
+) -> Json<Result<Vec<Report>, String>> {
+    let Some((viewer_id, _)) = verify_credentials(&username, &params.password, &pool).await else {
+        limits.lockout.record_failure(&username);
+        return Json(Err(String::from("Incorrect username or password")));
+    };
+    let global = moderation::is_global_moderator(viewer_id, &pool).await;
+    let status = params.status.map(ReportStatus::as_str);
+    let reports = query!(
+        r#"SELECT reports.id AS "id!", reports.message_id, chats.name AS "chatname!", authors.username AS author,
+        reports.content, reporters.username AS reporter, reports.reason, reports.created_at AS "created_at!: String",
+        reports.status, claimers.username AS "claimed_by?", reports.claimed_at AS "claimed_at: String",
+        resolvers.username AS "resolved_by?", reports.resolved_at AS "resolved_at: String",
+        reports.outcome, reports.note, reports.action_id
+        FROM reports
+        JOIN chats ON chats.id = reports.chat_id
+        JOIN users AS authors ON authors.id = reports.author_id
+        JOIN users AS reporters ON reporters.id = reports.reporter_id
+        LEFT JOIN users AS claimers ON claimers.id = reports.claimed_by
+        LEFT JOIN users AS resolvers ON resolvers.id = reports.resolved_by
+        WHERE chats.deleted_at IS NULL
+        AND (?1 OR reports.chat_id IN (SELECT chat_id FROM chat_users WHERE user_id = ?2 AND role IN ('owner', 'admin')))
+        AND (?3 IS NULL AND reports.status != 'resolved' OR reports.status = ?3)
+        AND (?4 IS NULL OR chats.name = ?4)
+        ORDER BY reports.id"#,
+        global, viewer_id, status, params.chatname
+    ).fetch_all(&pool).await.unwrap()
+    .into_iter()
+    .map(|row| Report {
+        id: row.id,
+        message_id: row.message_id,
+        chatname: row.chatname,
+        author: row.author,
+        content: row.content,
+        reporter: row.reporter,
+        reason: row.reason,
+        created_at: row.created_at,
+        status: row.status,
+        claimed_by: row.claimed_by,
+        claimed_at: row.claimed_at,
+        resolved_by: row.resolved_by,
+        resolved_at: row.resolved_at,
+        outcome: row.outcome,
+        note: row.note,
+        action_id: row.action_id,
+    })
+    .collect();
+    Json(Ok(reports))
+}
+
+/// Claims an open report so other moderators know it is being looked at; claiming a report the user
+/// already claimed is a no-op
+/// # Query format:
+/// curl -X POST -H "Content-Type: application/json" -d '{"password": "PasswordString"}' "http://98.93.98.244:80/claimreport/reportid/7/username/UsernameString"
+pub(crate) async fn claim_report(
+    State(pool): State<SqlitePool>,
+    State(limits): State<RateLimits>,
+    Path((report_id, username)): Path<(i64, String)>,
+    Json(request): Json<ClaimRequest>,
+) -> Json<Result<String, String>> {
+    let Some((moderator_id, _)) = verify_credentials(&username, &request.password, &pool).await else {
+        limits.lockout.record_failure(&username);
+        return Json(Err(String::from("Incorrect username or password")));
+    };
+    let report = match handled_report(report_id, moderator_id, &pool).await {
+        Ok(report) => report,
+        Err(e) => return Json(Err(e)),
+    };
+    match report.claimed_by {
+        Some(claimed_by) if claimed_by == moderator_id => return Json(Ok(String::from("0"))),
+        Some(_) => return Json(Err(String::from("Another moderator has claimed this report"))),
+        None => {}
+    }
+    query!(
+        "UPDATE reports SET status = 'claimed', claimed_by = ?, claimed_at = datetime('now') WHERE id = ?",
+        moderator_id, report_id
+    ).execute(&pool).await.unwrap();
+    println!("{} claimed report {}", username, report_id);
+    Json(Ok(String::from("1")))
+}
+
+/// Resolves a report by dismissing it, deleting the message, or muting or banning its author. Other
+/// unresolved reports of the same message are resolved along with it. Reports claimed by another
+/// moderator cannot be resolved
+/// # Query format:
+/// curl -X POST -H "Content-Type: application/json" -d '{"password": "PasswordString", "outcome": "mute", "duration": "1d", "note": "Repeated spam"}' "http://98.93.98.244:80/resolvereport/reportid/7/username/UsernameString"
+/// "outcome" is one of dismiss, delete, mute or ban
+pub(crate) async fn resolve_report(
+    State(pool): State<SqlitePool>,
+    State(attachments): State<Attachments>,
+    State(events): State<EventSender>,
+    State(limits): State<RateLimits>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path((report_id, username)): Path<(i64, String)>,
+    Json(request): Json<ResolveRequest>,
+) -> Json<Result<String, String>> {
+    let Some((moderator_id, _)) = verify_credentials(&username, &request.password, &pool).await else {
+        limits.lockout.record_failure(&username);
+        return Json(Err(String::from("Incorrect username or password")));
+    };
+    let report = match handled_report(report_id, moderator_id, &pool).await {
+        Ok(report) => report,
+        Err(e) => return Json(Err(e)),
+    };
+    if report.claimed_by.is_some_and(|claimed_by| claimed_by != moderator_id) {
+        return Json(Err(String::from("Another moderator has claimed this report")));
+    }
+    let (expiry, note) = match (moderation::expiry_modifier(request.duration.as_deref()), moderation::check_reason(request.note.as_deref())) {
+        (Ok(expiry), Ok(note)) => (expiry, note),
+        (Err(e), _) | (_, Err(e)) => return Json(Err(e)),
+    };
+    // The moderation log gets the moderator's note, or failing that the reporter's reason
+    let reason = note.as_deref().unwrap_or(&report.reason);
+    let action_id = match request.outcome {
+        Outcome::Dismiss => None,
+        Outcome::Delete => {
+            retention::delete_messages(report.chat_id, &[report.message_id], &pool, &attachments, &events).await;
+            None
+        }
+        Outcome::Mute | Outcome::Ban => {
+            if let Err(e) = moderation::check_authority(report.chat_id, moderator_id, report.author_id, &pool).await {
+                return Json(Err(e));
+            }
+            if request.outcome == Outcome::Mute {
+                let Some(secs) = request.duration.as_deref().and_then(|d| parse_duration(d.trim())) else {
+                    return Json(Err(String::from("A mute needs a duration")));
+                };
+                if !is_chat_member(report.chat_id, report.author_id, &pool).await {
+                    return Json(Err(String::from("The author is no longer a member of this chat")));
+                }
+                Some(moderation::mute(report.chat_id, report.author_id, moderator_id, secs, Some(reason), &pool).await)
+            } else {
+                Some(moderation::ban(report.chat_id, report.author_id, moderator_id, expiry.as_deref(), Some(reason), Some(addr.ip()), &pool).await)
+            }
+        }
+    };
+    let outcome = request.outcome.as_str();
+    query!(
+        r#"UPDATE reports SET status = 'resolved', resolved_by = ?, resolved_at = datetime('now'),
+        outcome = ?, note = ?, action_id = ?
+        WHERE message_id = ? AND status != 'resolved'"#,
+        moderator_id, outcome, note, action_id, report.message_id
+    ).execute(&pool).await.unwrap();
+    println!("{} resolved report {}: {}", username, report_id, outcome);
+    Json(Ok(String::from("1")))
+}
+
+struct PendingReport {
+    chat_id: i64,
+    message_id: i64,
+    author_id: i64,
+    reason: String,
+    claimed_by: Option<i64>,
+}
+
+/// An unresolved report the moderator may handle
+async fn handled_report(report_id: i64, moderator_id: i64, pool: &SqlitePool) -> Result<PendingReport, String> {
+    let Some(report) = query!(
+        r#"SELECT reports.chat_id, reports.message_id, reports.author_id, reports.reason, reports.claimed_by, reports.status
+        FROM reports JOIN chats ON chats.id = reports.chat_id
+        WHERE reports.id = ? AND chats.deleted_at IS NULL"#,
+        report_id
+    ).fetch_optional(pool).await.unwrap() else {
+        return Err(String::from("Report not found"));
+    };
+    if !handles_chat(report.chat_id, moderator_id, pool).await {
+        return Err(String::from("Only chat owners, admins and moderators can handle reports"));
+    }
+    if report.status == ReportStatus::Resolved.as_str() {
+        return Err(String::from("This report has already been resolved"));
+    }
+    Ok(PendingReport {
+        chat_id: report.chat_id,
+        message_id: report.message_id,
+        author_id: report.author_id,
+        reason: report.reason,
+        claimed_by: report.claimed_by,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_resolve_request() {
+        let request: ResolveRequest = serde_json::from_str(r#"{"password": "pw", "outcome": "ban", "note": "Spam bot"}"#).unwrap();
+        assert_eq!(request.outcome, Outcome::Ban);
+        assert_eq!(request.duration, None);
+        assert_eq!(request.outcome.as_str(), "banned");
+        assert!(serde_json::from_str::<ResolveRequest>(r#"{"password": "pw", "outcome": "shrug"}"#).is_err());
+        assert_eq!(serde_json::from_str::<ReportStatus>(r#""claimed""#).unwrap(), ReportStatus::Claimed);
+    }
+}
@@ -0,0 +1,253 @@
+use axum::{extract::{Path, State}, response::Json};
+use serde::{Deserialize, Serialize};
+use sqlx::{query, SqlitePool};
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::accounts::verify_credentials;
+use crate::archive;
+use crate::attachments::{self, Attachments};
+use crate::events::{self, ChatEvent, EventSender};
+use crate::ratelimit::RateLimits;
+use crate::webhooks::{self, WebhookEvent};
+use crate::{lookup_member, manages_chat, ChatHistoryMessage};
+
+/// How often the janitor looks for expired messages
+const JANITOR_INTERVAL: Duration = Duration::from_secs(30);
+/// Longest time-to-live a single message may be given
+const MAX_TTL_SECS: i64 = 30 * 24 * 60 * 60;
+const MAX_RETENTION_DAYS: i64 = 3650;
+
+/// How long a chat keeps its messages; either limit, or both, may be set
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub(crate) struct RetentionPolicy {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    days: Option<i64>, // Messages older than this are deleted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    keep_last: Option<i64>, // Only this many of the newest messages are kept
+}
+
+impl RetentionPolicy {
+    fn check(&self) -> Result<(), String> {
+        if self.days.is_some_and(|d| !(1..=MAX_RETENTION_DAYS).contains(&d)) {
+            return Err(format!("Retention must be 1 to {} days", MAX_RETENTION_DAYS));
+        }
+        if self.keep_last.is_some_and(|n| n < 1) {
+            return Err(String::from("At least one message must be kept"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct SetRetentionRequest {
+    password: String,
+    #[serde(flatten)]
+    policy: RetentionPolicy,
+}
+
+/// Checks a per-message time-to-live in seconds
+pub(crate) fn check_ttl(secs: i64) -> Result<(), String> {
+    if !(1..=MAX_TTL_SECS).contains(&secs) {
+        return Err(format!("Message lifetime must be 1 to {} seconds", MAX_TTL_SECS));
+    }
+    Ok(())
+}
+
+/// A chat's retention policy, None if it keeps messages forever
+pub(crate) async fn chat_policy(chat_id: i64, pool: &SqlitePool) -> Option<RetentionPolicy> {
+    let row = query!("SELECT retention_days, retention_keep_last FROM chats WHERE id = ?", chat_id)
+        .fetch_optional(pool)
+        .await
+        .unwrap()?;
+    let policy = RetentionPolicy { days: row.retention_days, keep_last: row.retention_keep_last };
+    (policy != RetentionPolicy::default()).then_some(policy)
+}
+
+/// Sets how long a chat keeps its messages; only the chat's owner and admins may do this.
+/// Leaving both fields out keeps messages forever
+/// # Query format:
+/// curl -X POST -H "Content-Type: application/json" -d '{"password": "PasswordString", "days": 30, "keep_last": 1000}' "http://98.93.98.244:80/retention/chatname/ChatName/username/NameString"
+pub(crate) async fn set_retention(
+    State(pool): State<SqlitePool>,
+    State(limits): State<RateLimits>,
+    Path((chatname, username)): Path<(String, String)>,
+    Json(request): Json<SetRetentionRequest>,
+) -> Json<Result<String, String>> {
+    if verify_credentials(&username, &request.password, &pool).await.is_none() {
+        limits.lockout.record_failure(&username);
+        return Json(Err(String::from("Incorrect username or password")));
+    }
+    let (chat_id, user_id) = match lookup_member(&chatname, &username, &pool).await {
+        Ok(ids) => ids,
+        Err(e) => return Json(Err(e)),
+    };
+    if !manages_chat(chat_id, user_id, &pool).await {
+        return Json(Err(String::from("Only chat owners and admins can change retention")));
+    }
+    if let Err(e) = request.policy.check() {
+        return Json(Err(e));
+    }
+    query!(
+        "UPDATE chats SET retention_days = ?, retention_keep_last = ? WHERE id = ?",
+        request.policy.days, request.policy.keep_last, chat_id
+    ).execute(&pool).await.unwrap();
+    println!("{} set retention of {} to {:?}", username, chatname, request.policy);
+    Json(Ok(String::from("1")))
+}
+
+/// Background task that deletes messages past their time-to-live or their chat's retention policy,
+/// uploads never attached to a message, and deleted chats whose grace period has run out
+pub(crate) async fn janitor_thread(pool: SqlitePool, attachments: Attachments, events: EventSender) {
+    loop {
+        let purged = sweep(&pool, &attachments, &events).await;
+        if purged > 0 {
+            println!("Janitor purged {} messages", purged);
+        }
+        let uploads = attachments::sweep_pending(&attachments, &pool).await;
+        if uploads > 0 {
+            println!("Janitor removed {} unused uploads", uploads);
+        }
+        let chats = archive::purge_expired_chats(&pool, &attachments).await;
+        if chats > 0 {
+            println!("Janitor purged {} deleted chats", chats);
+        }
+        tokio::time::sleep(JANITOR_INTERVAL).await;
+    }
+}
+
+/// Finds and purges expired messages, returning how many were deleted. Messages still waiting
+/// in the queue (e.g. scheduled ones) are left for later
+async fn sweep(pool: &SqlitePool, attachments: &Attachments, events: &EventSender) -> usize {
+    let mut expired: HashMap<i64, Vec<i64>> = HashMap::new();
+    let rows = query!(
+        r#"SELECT messages.id AS "id!", messages.chat_id FROM messages JOIN chats ON chats.id = messages.chat_id
+        WHERE NOT EXISTS (SELECT 1 FROM message_queue WHERE message_queue.message_id = messages.id
+            AND message_queue.direction = 'inbound' AND message_queue.status = 'Queued')
+        AND (messages.expires_at <= datetime('now')
+            OR messages.created_at <= datetime('now', '-' || chats.retention_days || ' days'))"#
+    ).fetch_all(pool).await.unwrap();
+    for row in rows {
+        expired.entry(row.chat_id).or_default().push(row.id);
+    }
+    let capped = query!(
+        r#"SELECT id AS "id!", retention_keep_last AS "keep_last!" FROM chats WHERE retention_keep_last IS NOT NULL"#
+    ).fetch_all(pool).await.unwrap();
+    for chat in capped {
+        // Everything older than the oldest message still kept goes
+        let offset = chat.keep_last - 1;
+        let Some(cutoff) = query!(
+            "SELECT id FROM messages WHERE chat_id = ? AND status = 'Sent!' ORDER BY id DESC LIMIT 1 OFFSET ?",
+            chat.id, offset
+        ).fetch_optional(pool).await.unwrap() else {
+            continue;
+        };
+        let older = query!(
+            r#"SELECT id AS "id!" FROM messages WHERE chat_id = ? AND id < ?
+            AND NOT EXISTS (SELECT 1 FROM message_queue WHERE message_queue.message_id = messages.id
+                AND message_queue.direction = 'inbound' AND message_queue.status = 'Queued')"#,
+            chat.id, cutoff.id
+        ).fetch_all(pool).await.unwrap();
+        let ids = expired.entry(chat.id).or_default();
+        for row in older {
+            if !ids.contains(&row.id) {
+                ids.push(row.id);
+            }
+        }
+    }
+    let mut purged = 0;
+    for (chat_id, ids) in expired {
+        purged += ids.len();
+        delete_messages(chat_id, &ids, pool, attachments, events).await;
+    }
+    purged
+}
+
+/// Purges messages of one chat and tells the chat's webhooks and connected clients about those that
+/// had been posted
+pub(crate) async fn delete_messages(
+    chat_id: i64, ids: &[i64], pool: &SqlitePool, attachments: &Attachments, events: &EventSender,
+) {
+    let ids_json = serde_json::to_string(ids).unwrap();
+    let posted: Vec<i64> = query!(
+        r#"SELECT id AS "id!" FROM messages WHERE status = 'Sent!' AND id IN (SELECT value FROM json_each(?))"#,
+        ids_json
+    ).fetch_all(pool).await.unwrap()
+    .into_iter()
+    .map(|row| row.id)
+    .collect();
+    purge(chat_id, ids, pool, attachments).await;
+    // Queued after the purge, which drops every queue entry of the messages
+    for id in posted {
+        webhooks::queue_event(chat_id, id, WebhookEvent::MessageDeleted, pool).await;
+        events::publish(events, ChatEvent::Deleted { chat_id, message_id: id });
+    }
+}
+
+/// Deletes messages of one chat along with their queue entries, reactions, mentions, pins and
+/// attachments, and drops them from the chat's cached history
+pub(crate) async fn purge(chat_id: i64, ids: &[i64], pool: &SqlitePool, attachments: &Attachments) {
+    let mut blobs = Vec::new();
+    let mut tx = pool.begin().await.unwrap();
+    for id in ids {
+        let keys = query!("SELECT sha256 FROM attachments WHERE message_id = ?", id)
+            .fetch_all(&mut *tx)
+            .await
+            .unwrap();
+        blobs.extend(keys.into_iter().map(|row| row.sha256));
+        query!("DELETE FROM reactions WHERE message_id = ?", id).execute(&mut *tx).await.unwrap();
+        query!("DELETE FROM mentions WHERE message_id = ?", id).execute(&mut *tx).await.unwrap();
+        query!("DELETE FROM pins WHERE message_id = ?", id).execute(&mut *tx).await.unwrap();
+        query!(
+            "UPDATE chats SET avatar_attachment_id = NULL WHERE avatar_attachment_id IN (SELECT id FROM attachments WHERE message_id = ?)",
+            id
+        ).execute(&mut *tx).await.unwrap();
+        query!("DELETE FROM attachments WHERE message_id = ?", id).execute(&mut *tx).await.unwrap();
+        query!("DELETE FROM message_queue WHERE message_id = ?", id).execute(&mut *tx).await.unwrap();
+        query!("DELETE FROM messages WHERE id = ?", id).execute(&mut *tx).await.unwrap();
+    }
+    let cached = query!("SELECT message_history FROM chat_history_cache WHERE chat_id = ?", chat_id)
+        .fetch_optional(&mut *tx)
+        .await
+        .unwrap()
+        .and_then(|row| row.message_history);
+    if let Some(json_string) = cached {
+        let mut messages: Vec<ChatHistoryMessage> = serde_json::from_str(&json_string).unwrap();
+        messages.retain(|m| !ids.contains(&m.id));
+        let json_history = serde_json::to_string(&messages).unwrap();
+        query!(
+            "UPDATE chat_history_cache SET message_history = ?, updated_at = datetime('now') WHERE chat_id = ?",
+            json_history, chat_id
+        ).execute(&mut *tx).await.unwrap();
+    }
+    tx.commit().await.unwrap();
+    attachments::remove_unreferenced(attachments, blobs, pool).await;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_policy_limits() {
+        assert!(RetentionPolicy { days: Some(30), keep_last: Some(1000) }.check().is_ok());
+        assert!(RetentionPolicy::default().check().is_ok());
+        assert!(RetentionPolicy { days: Some(0), keep_last: None }.check().is_err());
+        assert!(RetentionPolicy { days: Some(MAX_RETENTION_DAYS + 1), keep_last: None }.check().is_err());
+        assert!(RetentionPolicy { days: None, keep_last: Some(0) }.check().is_err());
+    }
+
+    #[test]
+    fn test_request_fields() {
+        let request: SetRetentionRequest = serde_json::from_str(r#"{"password": "pw", "keep_last": 50}"#).unwrap();
+        assert_eq!(request.policy, RetentionPolicy { days: None, keep_last: Some(50) });
+        assert_eq!(serde_json::to_string(&request.policy).unwrap(), r#"{"keep_last":50}"#);
+    }
+
+    #[test]
+    fn test_ttl_limits() {
+        assert!(check_ttl(60).is_ok());
+        assert!(check_ttl(0).is_err());
+        assert!(check_ttl(MAX_TTL_SECS + 1).is_err());
+    }
+}
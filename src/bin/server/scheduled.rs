@@ -0,0 +1,222 @@
+use axum::{extract::{Path, State}, response::Json};
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{query, SqlitePool};
+
+/// How far ahead a message may be scheduled
+const MAX_SCHEDULE_DAYS: i64 = 365;
+/// Format SQLite's datetime('now') produces, so scheduled times compare directly against it
+const SQLITE_DATETIME: &str = "%Y-%m-%d %H:%M:%S";
+
+#[derive(Serialize)]
+pub(crate) struct ScheduledMessage {
+    message_id: i64,
+    chatname: String,
+    content: String,
+    deliver_at: String, // UTC, "YYYY-MM-DD HH:MM:SS"
+}
+
+#[derive(Deserialize)]
+pub(crate) struct EditScheduledRequest {
+    #[serde(default)]
+    content: Option<String>,
+    #[serde(default)]
+    deliver_at: Option<String>,
+}
+
+/// Parses a delivery time given as RFC 3339 ("2025-06-01T09:00:00+02:00") or as UTC
+/// "YYYY-MM-DD HH:MM[:SS]", returning it in SQLite's format. The time must be in the future
+/// and at most a year away
+pub(crate) fn parse_deliver_at(text: &str, now: DateTime<Utc>) -> Result<String, String> {
+    let text = text.trim();
+    let time = DateTime::parse_from_rfc3339(text)
+        .map(|t| t.with_timezone(&Utc))
+        .or_else(|_| NaiveDateTime::parse_from_str(text, SQLITE_DATETIME).map(|t| t.and_utc()))
+        .or_else(|_| NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M").map(|t| t.and_utc()))
+        .map_err(|_| format!("Invalid delivery time {}, use e.g. 2025-06-01 09:00 (UTC)", text))?;
+    if time <= now {
+        return Err(String::from("Delivery time must be in the future"));
+    }
+    if time > now + Duration::days(MAX_SCHEDULE_DAYS) {
+        return Err(format!("Messages can be scheduled at most {} days ahead", MAX_SCHEDULE_DAYS));
+    }
+    Ok(time.format(SQLITE_DATETIME).to_string())
+}
+
+/// Lists a user's scheduled messages that have not been delivered yet, soonest first
+/// # Query format:
+/// curl "http://98.93.98.244:80/scheduled/username/NameString"
+/// # Return format:
+/// Array of "message_id", "chatname", "content" and "deliver_at" (UTC)
+pub(crate) async fn list_scheduled(
+    State(pool): State<SqlitePool>,
+    Path(username): Path<String>,
+) -> Json<Result<Vec<ScheduledMessage>, String>> {
+    let messages = query!(
+        r#"SELECT messages.id AS "message_id!", chats.name AS "chatname!", messages.content,
+        message_queue.deliver_at AS "deliver_at!: String"
+        FROM message_queue
+        JOIN messages ON messages.id = message_queue.message_id
+        JOIN chats ON chats.id = messages.chat_id
+        JOIN users ON users.id = messages.user_id
+        WHERE users.username = ? AND message_queue.direction = 'inbound' AND message_queue.status = 'Queued'
+        AND message_queue.deliver_at > datetime('now')
+        ORDER BY message_queue.deliver_at"#,
+        username
+    ).fetch_all(&pool).await.unwrap()
+    .into_iter()
+    .map(|row| ScheduledMessage {
+        message_id: row.message_id,
+        chatname: row.chatname,
+        content: row.content,
+        deliver_at: row.deliver_at,
+    })
+    .collect();
+    Json(Ok(messages))
+}
+
+/// True if the message belongs to the user and is still waiting for its delivery time
+async fn is_pending(message_id: i64, username: &str, pool: &SqlitePool) -> bool {
+    let pending = sqlx::query_scalar!(
+        r#"SELECT EXISTS(SELECT 1 FROM message_queue
+        JOIN messages ON messages.id = message_queue.message_id
+        JOIN users ON users.id = messages.user_id
+        WHERE message_queue.message_id = ? AND users.username = ? AND message_queue.direction = 'inbound'
+        AND message_queue.status = 'Queued' AND message_queue.deliver_at > datetime('now')) AS _exists"#,
+        message_id, username
+    ).fetch_one(pool).await.unwrap();
+    pending == 1
+}
+
+/// Changes the text and/or delivery time of a scheduled message before it is sent
+/// # Query format:
+/// curl -X POST -H "Content-Type: application/json" -d '{"content": "New text", "deliver_at": "2025-06-01 09:30"}' "http://98.93.98.244:80/editscheduled/messageid/42/username/NameString"
+pub(crate) async fn edit_scheduled(
+    State(pool): State<SqlitePool>,
+    Path((message_id, username)): Path<(i64, String)>,
+    Json(request): Json<EditScheduledRequest>,
+) -> Json<Result<String, String>> {
+    let deliver_at = match request.deliver_at.as_deref().map(|t| parse_deliver_at(t, Utc::now())) {
+        Some(Ok(time)) => Some(time),
+        Some(Err(e)) => return Json(Err(e)),
+        None => None,
+    };
+    if request.content.as_deref().is_some_and(|c| c.trim().is_empty()) {
+        return Json(Err(String::from("Message cannot be empty")));
+    }
+    if !is_pending(message_id, &username, &pool).await {
+        return Json(Err(String::from("No pending scheduled message with that id")));
+    }
+    if !reschedule(message_id, deliver_at.as_deref(), request.content.as_deref(), &pool).await {
+        return Json(Err(String::from("The message is already being delivered")));
+    }
+    println!("{} edited scheduled message {}", username, message_id);
+    Json(Ok(String::from("1")))
+}
+
+/// Applies an edit to a scheduled message, returning false if it came due in the meantime. A self-destructing
+/// message's expiry moves with its delivery time, since its time-to-live counts from when it is posted
+async fn reschedule(message_id: i64, deliver_at: Option<&str>, content: Option<&str>, pool: &SqlitePool) -> bool {
+    // Rows that came due in the meantime are left alone, the message thread may already hold them
+    let mut tx = pool.begin().await.unwrap();
+    let still_pending = query!(
+        r#"UPDATE message_queue SET deliver_at = COALESCE(?, deliver_at)
+        WHERE message_id = ? AND direction = 'inbound' AND status = 'Queued' AND deliver_at > datetime('now')"#,
+        deliver_at, message_id
+    ).execute(&mut *tx).await.unwrap().rows_affected() > 0;
+    if !still_pending {
+        return false;
+    }
+    if let Some(deliver_at) = deliver_at {
+        query!(
+            r#"UPDATE messages SET expires_at = datetime(?, '+' || ttl_seconds || ' seconds')
+            WHERE id = ? AND ttl_seconds IS NOT NULL"#,
+            deliver_at, message_id
+        ).execute(&mut *tx).await.unwrap();
+    }
+    if let Some(content) = content {
+        query!("UPDATE messages SET content = ? WHERE id = ?", content, message_id)
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+    }
+    tx.commit().await.unwrap();
+    true
+}
+
+/// Cancels a scheduled message so it is never sent
+/// # Query format:
+/// curl -X POST "http://98.93.98.244:80/cancelscheduled/messageid/42/username/NameString"
+pub(crate) async fn cancel_scheduled(
+    State(pool): State<SqlitePool>,
+    Path((message_id, username)): Path<(i64, String)>,
+) -> Json<Result<String, String>> {
+    if !is_pending(message_id, &username, &pool).await {
+        return Json(Err(String::from("No pending scheduled message with that id")));
+    }
+    let mut tx = pool.begin().await.unwrap();
+    let cancelled = query!(
+        r#"UPDATE message_queue SET status = 'Cancelled'
+        WHERE message_id = ? AND direction = 'inbound' AND status = 'Queued' AND deliver_at > datetime('now')"#,
+        message_id
+    ).execute(&mut *tx).await.unwrap().rows_affected() > 0;
+    if !cancelled {
+        return Json(Err(String::from("The message is already being delivered")));
+    }
+    query!("UPDATE messages SET status = 'Cancelled' WHERE id = ?", message_id)
+        .execute(&mut *tx)
+        .await
+        .unwrap();
+    tx.commit().await.unwrap();
+    println!("{} cancelled scheduled message {}", username, message_id);
+    Json(Ok(String::from("1")))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn now() -> DateTime<Utc> {
+        NaiveDateTime::parse_from_str("2025-06-01 12:00:00", SQLITE_DATETIME).unwrap().and_utc()
+    }
+
+    #[test]
+    fn test_parse_formats() {
+        assert_eq!(parse_deliver_at("2025-06-01 13:30", now()), Ok(String::from("2025-06-01 13:30:00")));
+        assert_eq!(parse_deliver_at("2025-06-01 13:30:15", now()), Ok(String::from("2025-06-01 13:30:15")));
+        assert_eq!(parse_deliver_at("2025-06-01T15:30:00+02:00", now()), Ok(String::from("2025-06-01 13:30:00")));
+        assert!(parse_deliver_at("tomorrow", now()).is_err());
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert!(parse_deliver_at("2025-06-01 12:00", now()).is_err());
+        assert!(parse_deliver_at("2025-05-31 09:00", now()).is_err());
+        assert!(parse_deliver_at("2026-05-31 09:00", now()).is_ok());
+        assert!(parse_deliver_at("2026-06-02 09:00", now()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reschedule_moves_expiry() {
+        let pool = sqlx::sqlite::SqlitePoolOptions::new().max_connections(1).connect("sqlite::memory:").await.unwrap();
+        sqlx::raw_sql(include_str!("../../../chat_database.sql")).execute(&pool).await.unwrap();
+        let time = |hours| (Utc::now() + Duration::hours(hours)).format(SQLITE_DATETIME).to_string();
+        sqlx::query("INSERT INTO users (id, username, password) VALUES (1, 'alice', '')").execute(&pool).await.unwrap();
+        sqlx::query("INSERT INTO chats (id, name) VALUES (1, 'room')").execute(&pool).await.unwrap();
+        sqlx::query(
+            r#"INSERT INTO messages (id, chat_id, user_id, content, status, expires_at, ttl_seconds)
+            VALUES (1, 1, 1, 'Secret', 'Scheduled', datetime(?, '+60 seconds'), 60)"#
+        ).bind(time(1)).execute(&pool).await.unwrap();
+        sqlx::query(
+            "INSERT INTO message_queue (message_id, direction, status, deliver_at) VALUES (1, 'inbound', 'Queued', ?)"
+        ).bind(time(1)).execute(&pool).await.unwrap();
+        let later = time(3);
+        assert!(reschedule(1, Some(&later), None, &pool).await);
+        let expires_at: String = sqlx::query_scalar("SELECT expires_at FROM messages WHERE id = 1")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        let expected = NaiveDateTime::parse_from_str(&later, SQLITE_DATETIME).unwrap() + Duration::seconds(60);
+        assert_eq!(expires_at, expected.format(SQLITE_DATETIME).to_string());
+    }
+}
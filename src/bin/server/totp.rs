@@ -0,0 +1,305 @@
+use axum::{extract::{ConnectInfo, Path, State}, response::Json};
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use rand::{distributions::Alphanumeric, Rng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sqlx::{query, SqlitePool};
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::accounts::{hash_password, password_matches, verify_credentials};
+use crate::audit::{self, AuditEvent};
+use crate::ratelimit::RateLimits;
+
+/// Issuer shown next to the account in authenticator apps
+const TOTP_ISSUER: &str = "ChatServer";
+const TOTP_DIGITS: u32 = 6;
+const TOTP_PERIOD_SECS: u64 = 30;
+/// Steps either side of the current one that are still accepted, to allow for clock drift
+const TOTP_SKEW_STEPS: u64 = 1;
+const SECRET_BYTES: usize = 20;
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_LENGTH: usize = 10;
+
+#[derive(Deserialize)]
+pub(crate) struct EnrollRequest {
+    password: String,
+}
+
+#[derive(Serialize)]
+pub(crate) struct Enrollment {
+    secret: String,
+    otpauth_uri: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct CodeRequest {
+    password: String,
+    code: String,
+}
+
+/// Outcome of the second step of a login
+pub(crate) enum SecondFactor {
+    /// The user has not enabled two-factor authentication
+    NotEnabled,
+    /// Two-factor is enabled but no code was sent
+    Required,
+    Passed,
+    Failed,
+}
+
+/// HOTP value (RFC 4226) of the key for one counter value
+fn hotp(key: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).unwrap();
+    mac.update(&counter.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes(digest[offset..offset + 4].try_into().unwrap()) & 0x7fff_ffff;
+    binary % 10u32.pow(TOTP_DIGITS)
+}
+
+/// Returns the time step the code is valid for, if it matches any step within the allowed skew
+fn matching_step(secret: &str, code: &str, now: u64) -> Option<u64> {
+    let key = BASE32_NOPAD.decode(secret.as_bytes()).ok()?;
+    if code.len() != TOTP_DIGITS as usize || !code.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+    let current = now / TOTP_PERIOD_SECS;
+    (current.saturating_sub(TOTP_SKEW_STEPS)..=current + TOTP_SKEW_STEPS).find(|&step| hotp(&key, step) == code)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// Random base32 secret shared with the authenticator app
+fn generate_secret() -> String {
+    let mut bytes = [0u8; SECRET_BYTES];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    BASE32_NOPAD.encode(&bytes)
+}
+
+/// Key URI understood by authenticator apps, usually shown as a QR code
+fn otpauth_uri(username: &str, secret: &str) -> String {
+    format!(
+        "otpauth://totp/{issuer}:{username}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={}&period={}",
+        TOTP_DIGITS, TOTP_PERIOD_SECS, issuer = TOTP_ISSUER,
+    )
+}
+
+/// Random recovery code, shown to the user as two groups of five characters
+fn generate_recovery_code() -> String {
+    let code: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(RECOVERY_CODE_LENGTH)
+        .map(|c| char::from(c).to_ascii_lowercase())
+        .collect();
+    format!("{}-{}", &code[..RECOVERY_CODE_LENGTH / 2], &code[RECOVERY_CODE_LENGTH / 2..])
+}
+
+/// Recovery codes are compared without the dash and ignoring case
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars().filter(|c| *c != '-' && !c.is_whitespace()).collect::<String>().to_ascii_lowercase()
+}
+
+/// Accepts a TOTP code not used before, then falls back to an unused recovery code, which is used up
+async fn check_code(user_id: i64, secret: &str, code: &str, pool: &SqlitePool) -> bool {
+    if let Some(step) = matching_step(secret, code, unix_now()) {
+        let step = step as i64;
+        // Only move forward so each code works once, even with concurrent logins
+        let accepted = query!(
+            "UPDATE users SET totp_last_step = ? WHERE id = ? AND COALESCE(totp_last_step, 0) < ?",
+            step, user_id, step
+        ).execute(pool).await.unwrap();
+        return accepted.rows_affected() == 1;
+    }
+    let code = normalize_recovery_code(code);
+    let unused = query!(
+        r#"SELECT id AS "id!", code_hash FROM recovery_codes WHERE user_id = ? AND used_at IS NULL"#,
+        user_id
+    ).fetch_all(pool).await.unwrap();
+    let Some(recovery) = unused.iter().find(|r| password_matches(&code, &r.code_hash)) else {
+        return false;
+    };
+    let claimed = query!(
+        "UPDATE recovery_codes SET used_at = datetime('now') WHERE id = ? AND used_at IS NULL",
+        recovery.id
+    ).execute(pool).await.unwrap();
+    claimed.rows_affected() == 1
+}
+
+/// Second login step, run after the password has been checked
+pub(crate) async fn second_factor(user_id: i64, code: Option<&str>, pool: &SqlitePool) -> SecondFactor {
+    let row = query!(
+        r#"SELECT totp_secret, COALESCE(totp_enabled, 0) AS "enabled!: bool" FROM users WHERE id = ?"#,
+        user_id
+    ).fetch_one(pool).await.unwrap();
+    let Some(secret) = row.totp_secret.filter(|_| row.enabled) else {
+        return SecondFactor::NotEnabled;
+    };
+    match code {
+        None => SecondFactor::Required,
+        Some(code) if check_code(user_id, &secret, code, pool).await => SecondFactor::Passed,
+        Some(_) => SecondFactor::Failed,
+    }
+}
+
+/// Starts two-factor enrollment with a new secret; it takes effect once confirmed with a code
+/// # Query format:
+/// curl -X POST -H "Content-Type: application/json" -d '{"password": "PasswordString"}' "http://98.93.98.244:80/totp/enroll/username/NameString"
+/// # Return format:
+/// "secret" to type into an authenticator app, or "otpauth_uri" to show as a QR code
+pub(crate) async fn enroll(
+    State(pool): State<SqlitePool>,
+    State(limits): State<RateLimits>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(username): Path<String>,
+    Json(request): Json<EnrollRequest>,
+) -> Json<Result<Enrollment, String>> {
+    let Some((user_id, _)) = verify_credentials(&username, &request.password, &pool).await else {
+        limits.lockout.record_failure(&username);
+        return Json(Err(String::from("Incorrect username or password")));
+    };
+    let secret = generate_secret();
+    // Enrollment can be restarted until it is confirmed, but never replaces an active secret
+    let started = query!(
+        "UPDATE users SET totp_secret = ?, totp_last_step = 0 WHERE id = ? AND COALESCE(totp_enabled, 0) = 0",
+        secret, user_id
+    ).execute(&pool).await.unwrap();
+    if started.rows_affected() == 0 {
+        return Json(Err(String::from("Two-factor authentication is already enabled")));
+    }
+    audit::record(AuditEvent::KeyRegistered, Some(user_id), Some(user_id), None, Some(addr.ip()), Some("Two-factor secret"), &pool).await;
+    println!("{} started two-factor enrollment", username);
+    let otpauth_uri = otpauth_uri(&username, &secret);
+    Json(Ok(Enrollment { secret, otpauth_uri }))
+}
+
+/// Confirms enrollment with a code from the authenticator app and turns two-factor on
+/// # Query format:
+/// curl -X POST -H "Content-Type: application/json" -d '{"password": "PasswordString", "code": "123456"}' "http://98.93.98.244:80/totp/confirm/username/NameString"
+/// # Return format:
+/// Single-use recovery codes for logging in without the app; they are only shown this once
+pub(crate) async fn confirm(
+    State(pool): State<SqlitePool>,
+    State(limits): State<RateLimits>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(username): Path<String>,
+    Json(request): Json<CodeRequest>,
+) -> Json<Result<Vec<String>, String>> {
+    let Some((user_id, _)) = verify_credentials(&username, &request.password, &pool).await else {
+        limits.lockout.record_failure(&username);
+        return Json(Err(String::from("Incorrect username or password")));
+    };
+    let row = query!(
+        r#"SELECT totp_secret, COALESCE(totp_enabled, 0) AS "enabled!: bool" FROM users WHERE id = ?"#,
+        user_id
+    ).fetch_one(&pool).await.unwrap();
+    if row.enabled {
+        return Json(Err(String::from("Two-factor authentication is already enabled")));
+    }
+    let Some(secret) = row.totp_secret else {
+        return Json(Err(String::from("Two-factor enrollment has not been started")));
+    };
+    let Some(step) = matching_step(&secret, &request.code, unix_now()) else {
+        return Json(Err(String::from("Incorrect code")));
+    };
+    let codes: Vec<String> = (0..RECOVERY_CODE_COUNT).map(|_| generate_recovery_code()).collect();
+    let step = step as i64;
+    let mut tx = pool.begin().await.unwrap();
+    query!("DELETE FROM recovery_codes WHERE user_id = ?", user_id).execute(&mut *tx).await.unwrap();
+    for code in &codes {
+        let code_hash = hash_password(&normalize_recovery_code(code));
+        query!(
+            "INSERT INTO recovery_codes (user_id, code_hash, created_at) VALUES (?, ?, datetime('now'))",
+            user_id, code_hash
+        ).execute(&mut *tx).await.unwrap();
+    }
+    query!("UPDATE users SET totp_enabled = 1, totp_last_step = ? WHERE id = ?", step, user_id)
+        .execute(&mut *tx)
+        .await
+        .unwrap();
+    tx.commit().await.unwrap();
+    audit::record(AuditEvent::TotpEnabled, Some(user_id), Some(user_id), None, Some(addr.ip()), None, &pool).await;
+    println!("{} enabled two-factor authentication", username);
+    Json(Ok(codes))
+}
+
+/// Turns two-factor off; needs the password and a current code or a recovery code
+/// # Query format:
+/// curl -X POST -H "Content-Type: application/json" -d '{"password": "PasswordString", "code": "123456"}' "http://98.93.98.244:80/totp/disable/username/NameString"
+pub(crate) async fn disable(
+    State(pool): State<SqlitePool>,
+    State(limits): State<RateLimits>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(username): Path<String>,
+    Json(request): Json<CodeRequest>,
+) -> Json<Result<String, String>> {
+    let Some((user_id, _)) = verify_credentials(&username, &request.password, &pool).await else {
+        limits.lockout.record_failure(&username);
+        return Json(Err(String::from("Incorrect username or password")));
+    };
+    match second_factor(user_id, Some(&request.code), &pool).await {
+        SecondFactor::Passed => {}
+        SecondFactor::NotEnabled => return Json(Err(String::from("Two-factor authentication is not enabled"))),
+        SecondFactor::Required | SecondFactor::Failed => {
+            limits.lockout.record_failure(&username);
+            return Json(Err(String::from("Incorrect code")));
+        }
+    }
+    let mut tx = pool.begin().await.unwrap();
+    query!(
+        "UPDATE users SET totp_secret = NULL, totp_enabled = 0, totp_last_step = 0 WHERE id = ?",
+        user_id
+    ).execute(&mut *tx).await.unwrap();
+    query!("DELETE FROM recovery_codes WHERE user_id = ?", user_id).execute(&mut *tx).await.unwrap();
+    tx.commit().await.unwrap();
+    audit::record(AuditEvent::TotpDisabled, Some(user_id), Some(user_id), None, Some(addr.ip()), None, &pool).await;
+    println!("{} disabled two-factor authentication", username);
+    Json(Ok(String::from("1")))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// The shared secret used by the test vectors in RFC 4226 and RFC 6238
+    const RFC_KEY: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn test_hotp_rfc4226_vectors() {
+        let expected = [755224, 287082, 359152, 969429, 338314];
+        for (counter, code) in expected.iter().enumerate() {
+            assert_eq!(hotp(RFC_KEY, counter as u64), *code);
+        }
+    }
+
+    #[test]
+    fn test_totp_rfc6238_vectors() {
+        let secret = BASE32_NOPAD.encode(RFC_KEY);
+        // RFC 6238 lists 8 digit codes; these are their last 6 digits
+        assert_eq!(matching_step(&secret, "287082", 59), Some(1));
+        assert_eq!(matching_step(&secret, "081804", 1111111109), Some(1111111109 / TOTP_PERIOD_SECS));
+        assert_eq!(matching_step(&secret, "081804", 1111111109 + 2 * TOTP_PERIOD_SECS), None);
+        assert_eq!(matching_step(&secret, "81804", 1111111109), None);
+    }
+
+    #[test]
+    fn test_otpauth_uri() {
+        let uri = otpauth_uri("alice", "JBSWY3DPEHPK3PXP");
+        assert_eq!(
+            uri,
+            "otpauth://totp/ChatServer:alice?secret=JBSWY3DPEHPK3PXP&issuer=ChatServer&algorithm=SHA1&digits=6&period=30"
+        );
+    }
+
+    #[test]
+    fn test_recovery_codes() {
+        let code = generate_recovery_code();
+        assert_eq!(code.len(), RECOVERY_CODE_LENGTH + 1);
+        assert_eq!(normalize_recovery_code(&code.to_uppercase()), code.replace('-', ""));
+        assert_eq!(BASE32_NOPAD.decode(generate_secret().as_bytes()).unwrap().len(), SECRET_BYTES);
+    }
+}
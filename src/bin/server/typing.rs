@@ -0,0 +1,128 @@
+use axum::{extract::{Path, State}, response::Json};
+use sqlx::SqlitePool;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::events::{publish, ChatEvent, EventSender};
+use crate::lookup_member;
+
+/// How long a "start" signal lasts before the server sends "stop" on the user's behalf
+const TYPING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Users currently typing, keyed by (chat id, username). The value is a fresh generation on every
+/// "start" so an expiry timer can tell whether the signal was refreshed since it was armed. Generations
+/// come from one counter and are never reused, even after a stop removes the entry.
+/// Kept in memory only; typing state is never written to SQLite
+#[derive(Clone, Default)]
+pub(crate) struct TypingTracker {
+    active: Arc<Mutex<HashMap<(i64, String), u64>>>,
+    next_generation: Arc<AtomicU64>,
+}
+
+impl TypingTracker {
+    /// Records a start signal, returning the generation the expiry timer should check against and
+    /// whether the user was not already typing
+    fn start(&self, chat_id: i64, username: &str) -> (u64, bool) {
+        let generation = self.next_generation.fetch_add(1, Ordering::Relaxed) + 1;
+        let previous = self.active.lock().unwrap().insert((chat_id, username.to_string()), generation);
+        (generation, previous.is_none())
+    }
+
+    /// Clears a typing signal, returns whether the user was typing
+    fn stop(&self, chat_id: i64, username: &str) -> bool {
+        self.active.lock().unwrap().remove(&(chat_id, username.to_string())).is_some()
+    }
+
+    /// Clears a typing signal only if no newer start arrived since `generation`
+    fn expire(&self, chat_id: i64, username: &str, generation: u64) -> bool {
+        let mut active = self.active.lock().unwrap();
+        let key = (chat_id, username.to_string());
+        if active.get(&key) == Some(&generation) {
+            active.remove(&key);
+            return true;
+        }
+        false
+    }
+}
+
+/// Relays a typing start/stop signal to the other members of a chat
+/// # Query format:
+/// curl -X POST "http://98.93.98.244:80/typing/chatname/ChatName/username/UsernameString/state/start"
+/// State is "start" or "stop"; a start expires on its own after 5 seconds unless it is sent again
+pub(crate) async fn typing(
+    State(pool): State<SqlitePool>,
+    State(events): State<EventSender>,
+    State(tracker): State<TypingTracker>,
+    Path((chatname, username, state)): Path<(String, String, String)>,
+) -> Json<Result<String, String>> {
+    let (chat_id, _) = match lookup_member(&chatname, &username, &pool).await {
+        Ok(ids) => ids,
+        Err(e) => return Json(Err(e)),
+    };
+    match state.as_str() {
+        "start" => {
+            let (generation, started) = tracker.start(chat_id, &username);
+            if started {
+                publish(&events, ChatEvent::Typing { chat_id, username: username.clone(), typing: true });
+            }
+            tokio::spawn(async move {
+                tokio::time::sleep(TYPING_TIMEOUT).await;
+                if tracker.expire(chat_id, &username, generation) {
+                    publish(&events, ChatEvent::Typing { chat_id, username, typing: false });
+                }
+            });
+        }
+        "stop" => {
+            if tracker.stop(chat_id, &username) {
+                publish(&events, ChatEvent::Typing { chat_id, username, typing: false });
+            }
+        }
+        _ => return Json(Err(String::from("State must be start or stop"))),
+    }
+    Json(Ok(String::from("1")))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_expire_after_start() {
+        let tracker = TypingTracker::default();
+        let (generation, started) = tracker.start(1, "alice");
+        assert!(started);
+        assert!(tracker.expire(1, "alice", generation));
+        assert!(!tracker.stop(1, "alice"));
+    }
+
+    #[test]
+    fn test_refreshed_start_outlives_old_timer() {
+        let tracker = TypingTracker::default();
+        let (first, _) = tracker.start(1, "alice");
+        let (second, started) = tracker.start(1, "alice");
+        assert!(!started);
+        assert!(!tracker.expire(1, "alice", first));
+        assert!(tracker.expire(1, "alice", second));
+    }
+
+    #[test]
+    fn test_timer_from_before_stop_ignores_new_start() {
+        let tracker = TypingTracker::default();
+        let (old, _) = tracker.start(1, "alice");
+        assert!(tracker.stop(1, "alice"));
+        let (new, started) = tracker.start(1, "alice");
+        assert!(started);
+        assert!(!tracker.expire(1, "alice", old));
+        assert!(tracker.expire(1, "alice", new));
+    }
+
+    #[test]
+    fn test_stop() {
+        let tracker = TypingTracker::default();
+        tracker.start(1, "alice");
+        assert!(!tracker.stop(2, "alice"));
+        assert!(tracker.stop(1, "alice"));
+    }
+}
@@ -0,0 +1,545 @@
+use axum::{extract::{ConnectInfo, Path, State}, response::Json};
+use hmac::{Hmac, Mac};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use sqlx::{query, SqlitePool};
+use reqwest::{redirect::Policy, Url};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use crate::accounts::verify_credentials;
+use crate::audit::{self, AuditEvent};
+use crate::ratelimit::RateLimits;
+use crate::{lookup_member, manages_chat};
+
+/// Wait before each retry of a failed delivery; the delivery is dropped after the last one
+const RETRY_DELAYS_SECS: [u64; 4] = [10, 60, 300, 1800];
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+const SECRET_LENGTH: usize = 32;
+/// Attempts shown by the delivery log endpoint
+const DELIVERY_LOG_LIMIT: i64 = 50;
+
+/// Chat events sent to webhooks. Posted messages cannot be edited (only scheduled ones can, before any
+/// webhook has seen them), so there is no message.edited event
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum WebhookEvent {
+    MessageSent,
+    MessageDeleted, // Expired, removed by the retention policy or deleted by a moderator
+}
+
+impl WebhookEvent {
+    fn as_str(self) -> &'static str {
+        match self {
+            WebhookEvent::MessageSent => "message.sent",
+            WebhookEvent::MessageDeleted => "message.deleted",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct CreateWebhookRequest {
+    password: String,
+    url: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct DeleteWebhookRequest {
+    password: String,
+}
+
+#[derive(Serialize)]
+pub(crate) struct NewWebhook {
+    id: i64,
+    secret: String,
+}
+
+#[derive(Serialize)]
+pub(crate) struct WebhookInfo {
+    id: i64,
+    url: String,
+    created_at: String,
+}
+
+/// One delivery attempt, as shown in the delivery log
+#[derive(Serialize)]
+pub(crate) struct DeliveryAttempt {
+    queue_id: i64,
+    message_id: i64,
+    event: String,
+    attempt: i64,
+    status_code: Option<i64>,
+    error: Option<String>,
+    duration_ms: Option<i64>,
+    attempted_at: String,
+}
+
+/// Body POSTed to the webhook URL
+#[derive(Serialize)]
+struct WebhookPayload {
+    event: String,
+    delivery_id: i64,
+    chat: String,
+    message: PayloadMessage,
+}
+
+/// The message an event is about; only the id is left of a deleted message
+#[derive(Serialize)]
+#[serde(untagged)]
+enum PayloadMessage {
+    Message(WebhookMessage),
+    Deleted { id: i64 },
+}
+
+#[derive(Serialize)]
+struct WebhookMessage {
+    id: i64,
+    username: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    display_name: Option<String>, // The name an incoming webhook posted under
+    content: String,
+    created_at: String,
+    bot: bool,
+}
+
+fn generate_secret() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(SECRET_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+/// Hex HMAC-SHA256 of the body, sent as "X-Chat-Signature: sha256=..." so receivers can check
+/// the request came from this server
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(body);
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Whether deliveries may go to the address. Loopback, private, link-local and unspecified addresses are
+/// refused so a webhook cannot reach the server itself or the network behind it
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            let shared = first == 100 && (64..128).contains(&second); // Carrier-grade NAT, 100.64.0.0/10
+            !(ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified()
+                || ip.is_broadcast() || ip.is_multicast() || shared)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(mapped) => is_public(IpAddr::V4(mapped)),
+            None => !(ip.is_loopback() || ip.is_unspecified() || ip.is_unique_local()
+                || ip.is_unicast_link_local() || ip.is_multicast()),
+        },
+    }
+}
+
+/// Resolves a webhook URL's host, refusing the URL unless it is http(s) and every address is public.
+/// Returns the host and the checked addresses to connect to
+async fn resolve_public(url: &str) -> Result<(String, Vec<SocketAddr>), String> {
+    let parsed = Url::parse(url).map_err(|_| String::from("Invalid webhook URL"))?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return Err(String::from("Webhook URL must start with http:// or https://"));
+    }
+    let host = parsed.host_str().ok_or_else(|| String::from("Webhook URL has no host"))?;
+    let port = parsed.port_or_known_default().unwrap_or(80);
+    // IPv6 literals come bracketed, as in http://[2001:db8::1]/hook
+    let lookup = host.trim_start_matches('[').trim_end_matches(']');
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((lookup, port))
+        .await
+        .map_err(|_| format!("Cannot resolve {}", host))?
+        .collect();
+    if addrs.is_empty() || !addrs.iter().all(|addr| is_public(addr.ip())) {
+        return Err(String::from("Webhook URL must not point at a loopback, private or link-local address"));
+    }
+    Ok((host.to_string(), addrs))
+}
+
+/// Client for one delivery. It connects only to the addresses that were checked, so a second DNS answer
+/// cannot point it elsewhere, and does not follow redirects to other hosts
+fn delivery_client(host: &str, addrs: &[SocketAddr]) -> reqwest::Client {
+    reqwest::Client::builder()
+        .timeout(DELIVERY_TIMEOUT)
+        .redirect(Policy::none())
+        .resolve_to_addrs(host, addrs)
+        .build()
+        .unwrap()
+}
+
+/// Seconds to wait after the given failed attempt (counting from 1), None once retries run out
+fn retry_delay(attempt: usize) -> Option<u64> {
+    RETRY_DELAYS_SECS.get(attempt - 1).copied()
+}
+
+/// POSTs a signed payload, returning the response status
+async fn send(
+    client: &reqwest::Client,
+    url: &str,
+    secret: &str,
+    event: &str,
+    delivery_id: i64,
+    body: String,
+) -> Result<u16, String> {
+    let signature = format!("sha256={}", sign(secret, body.as_bytes()));
+    let response = client
+        .post(url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header("X-Chat-Event", event)
+        .header("X-Chat-Delivery", delivery_id.to_string())
+        .header("X-Chat-Signature", signature)
+        .body(body)
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(response.status().as_u16())
+}
+
+/// Queues an outbound delivery of the event to every webhook of the chat
+pub(crate) async fn queue_event(chat_id: i64, message_id: i64, event: WebhookEvent, pool: &SqlitePool) {
+    let event = event.as_str();
+    query!(
+        r#"INSERT INTO message_queue (message_id, direction, queued_at, processed_at, status, webhook_id, event, attempts, next_attempt_at)
+        SELECT ?, 'outbound', datetime('now'), NULL, 'Queued', id, ?, 0, datetime('now') FROM webhooks WHERE chat_id = ?"#,
+        message_id, event, chat_id
+    ).execute(pool).await.unwrap();
+}
+
+/// Background task delivering queued outbound entries, retrying failures with backoff
+pub(crate) async fn delivery_thread(pool: SqlitePool) {
+    // Deliveries interrupted by a restart are tried again
+    query!("UPDATE message_queue SET status = 'Queued' WHERE direction = 'outbound' AND status = 'Sending'")
+        .execute(&pool)
+        .await
+        .unwrap();
+    loop {
+        let Some(due) = query!(
+            r#"SELECT message_queue.id AS "id!", message_queue.message_id, message_queue.event AS "event!",
+            COALESCE(message_queue.attempts, 0) AS "attempts!: i64",
+            webhooks.id AS "webhook_id!", webhooks.url, webhooks.secret, chats.name AS "chatname!"
+            FROM message_queue
+            JOIN webhooks ON webhooks.id = message_queue.webhook_id
+            JOIN chats ON chats.id = webhooks.chat_id
+            WHERE message_queue.direction = 'outbound' AND message_queue.status = 'Queued'
+            AND message_queue.next_attempt_at <= datetime('now')
+            ORDER BY message_queue.next_attempt_at ASC LIMIT 1"#
+        ).fetch_optional(&pool).await.unwrap() else {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            continue;
+        };
+        let claimed = query!("UPDATE message_queue SET status = 'Sending' WHERE id = ? AND status = 'Queued'", due.id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        if claimed.rows_affected() == 0 {
+            continue;
+        }
+        let message = if due.event == WebhookEvent::MessageDeleted.as_str() {
+            PayloadMessage::Deleted { id: due.message_id }
+        } else {
+            let Some(message) = query!(
+                r#"SELECT messages.id AS "id!", users.username, messages.display_name, messages.content,
+                messages.created_at AS "created_at!: String", COALESCE(users.is_bot, 0) AS "bot!: bool"
+                FROM messages JOIN users ON users.id = messages.user_id WHERE messages.id = ?"#,
+                due.message_id
+            ).fetch_optional(&pool).await.unwrap() else {
+                // The message expired and was purged before it could be delivered
+                query!("DELETE FROM message_queue WHERE id = ?", due.id).execute(&pool).await.unwrap();
+                continue;
+            };
+            PayloadMessage::Message(WebhookMessage {
+                id: message.id,
+                username: message.username,
+                display_name: message.display_name,
+                content: message.content,
+                created_at: message.created_at,
+                bot: message.bot,
+            })
+        };
+        let payload = WebhookPayload {
+            event: due.event.clone(),
+            delivery_id: due.id,
+            chat: due.chatname,
+            message,
+        };
+        let body = serde_json::to_string(&payload).unwrap();
+        let started = Instant::now();
+        // Checked again on every attempt, since the host may have been pointed somewhere else since
+        let result = match resolve_public(&due.url).await {
+            Ok((host, addrs)) => send(&delivery_client(&host, &addrs), &due.url, &due.secret, &due.event, due.id, body).await,
+            Err(e) => Err(e),
+        };
+        let duration_ms = started.elapsed().as_millis() as i64;
+        let attempt = due.attempts + 1;
+        let (status_code, error) = match &result {
+            Ok(code) if (200..300).contains(code) => (Some(*code as i64), None),
+            Ok(code) => (Some(*code as i64), Some(format!("Receiver responded with {}", code))),
+            Err(e) => (None, Some(e.clone())),
+        };
+        query!(
+            r#"INSERT INTO webhook_deliveries (queue_id, webhook_id, attempt, status_code, error, duration_ms, attempted_at)
+            VALUES (?, ?, ?, ?, ?, ?, datetime('now'))"#,
+            due.id, due.webhook_id, attempt, status_code, error, duration_ms
+        ).execute(&pool).await.unwrap();
+        if error.is_none() {
+            query!(
+                "UPDATE message_queue SET status = 'Delivered', attempts = ?, processed_at = datetime('now') WHERE id = ?",
+                attempt, due.id
+            ).execute(&pool).await.unwrap();
+            continue;
+        }
+        match retry_delay(attempt as usize) {
+            Some(delay) => {
+                let modifier = format!("+{} seconds", delay);
+                query!(
+                    "UPDATE message_queue SET status = 'Queued', attempts = ?, next_attempt_at = datetime('now', ?) WHERE id = ?",
+                    attempt, modifier, due.id
+                ).execute(&pool).await.unwrap();
+            }
+            None => {
+                println!("Giving up on webhook delivery {} after {} attempts", due.id, attempt);
+                query!(
+                    "UPDATE message_queue SET status = 'Failed', attempts = ?, processed_at = datetime('now') WHERE id = ?",
+                    attempt, due.id
+                ).execute(&pool).await.unwrap();
+            }
+        }
+    }
+}
+
+/// Adds a webhook to a chat; only the chat's owner and admins may do this. The URL must reach a public
+/// address, and redirects from it are not followed
+/// # Query format:
+/// curl -X POST -H "Content-Type: application/json" -d '{"password": "PasswordString", "url": "https://example.com/hook"}' "http://98.93.98.244:80/createwebhook/chatname/ChatName/username/NameString"
+/// # Return format:
+/// "id" and "secret"; receivers check "X-Chat-Signature: sha256=<hex HMAC-SHA256 of the body>" with the secret
+pub(crate) async fn create_webhook(
+    State(pool): State<SqlitePool>,
+    State(limits): State<RateLimits>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path((chatname, username)): Path<(String, String)>,
+    Json(request): Json<CreateWebhookRequest>,
+) -> Json<Result<NewWebhook, String>> {
+    if verify_credentials(&username, &request.password, &pool).await.is_none() {
+        limits.lockout.record_failure(&username);
+        return Json(Err(String::from("Incorrect username or password")));
+    }
+    let (chat_id, user_id) = match lookup_member(&chatname, &username, &pool).await {
+        Ok(ids) => ids,
+        Err(e) => return Json(Err(e)),
+    };
+    if !manages_chat(chat_id, user_id, &pool).await {
+        return Json(Err(String::from("Only chat owners and admins can add webhooks")));
+    }
+    if let Err(e) = resolve_public(&request.url).await {
+        return Json(Err(e));
+    }
+    let secret = generate_secret();
+    let id = query!(
+        "INSERT INTO webhooks (chat_id, url, secret, created_by, created_at) VALUES (?, ?, ?, ?, datetime('now'))",
+        chat_id, request.url, secret, user_id
+    ).execute(&pool).await.unwrap().last_insert_rowid();
+    // The URL is left out, since receivers sometimes take a secret in it
+    let detail = format!("Webhook {}", id);
+    audit::record(AuditEvent::WebhookCreated, Some(user_id), None, Some(chat_id), Some(addr.ip()), Some(&detail), &pool).await;
+    println!("{} added webhook {} to {}", username, id, chatname);
+    Json(Ok(NewWebhook { id, secret }))
+}
+
+/// Removes a webhook; pending deliveries to it are dropped
+/// # Query format:
+/// curl -X POST -H "Content-Type: application/json" -d '{"password": "PasswordString"}' "http://98.93.98.244:80/deletewebhook/webhookid/3/username/NameString"
+pub(crate) async fn delete_webhook(
+    State(pool): State<SqlitePool>,
+    State(limits): State<RateLimits>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path((webhook_id, username)): Path<(i64, String)>,
+    Json(request): Json<DeleteWebhookRequest>,
+) -> Json<Result<String, String>> {
+    let Some((user_id, _)) = verify_credentials(&username, &request.password, &pool).await else {
+        limits.lockout.record_failure(&username);
+        return Json(Err(String::from("Incorrect username or password")));
+    };
+    let Some(webhook) = query!("SELECT chat_id FROM webhooks WHERE id = ?", webhook_id)
+        .fetch_optional(&pool)
+        .await
+        .unwrap() else {
+        return Json(Err(String::from("Webhook not found")));
+    };
+    if !manages_chat(webhook.chat_id, user_id, &pool).await {
+        return Json(Err(String::from("Only chat owners and admins can remove webhooks")));
+    }
+    let mut tx = pool.begin().await.unwrap();
+    query!(
+        "DELETE FROM message_queue WHERE direction = 'outbound' AND webhook_id = ? AND status = 'Queued'",
+        webhook_id
+    ).execute(&mut *tx).await.unwrap();
+    query!("DELETE FROM webhooks WHERE id = ?", webhook_id).execute(&mut *tx).await.unwrap();
+    tx.commit().await.unwrap();
+    let detail = format!("Webhook {}", webhook_id);
+    audit::record(AuditEvent::WebhookDeleted, Some(user_id), None, Some(webhook.chat_id), Some(addr.ip()), Some(&detail), &pool).await;
+    println!("{} removed webhook {}", username, webhook_id);
+    Json(Ok(String::from("1")))
+}
+
+/// Lists a chat's webhooks for its owner and admins
+/// # Query format:
+/// curl "http://98.93.98.244:80/listwebhooks/chatname/ChatName/username/NameString"
+pub(crate) async fn list_webhooks(
+    State(pool): State<SqlitePool>,
+    Path((chatname, username)): Path<(String, String)>,
+) -> Json<Result<Vec<WebhookInfo>, String>> {
+    let (chat_id, user_id) = match lookup_member(&chatname, &username, &pool).await {
+        Ok(ids) => ids,
+        Err(e) => return Json(Err(e)),
+    };
+    if !manages_chat(chat_id, user_id, &pool).await {
+        return Json(Err(String::from("Only chat owners and admins can see webhooks")));
+    }
+    let webhooks = query!(
+        r#"SELECT id AS "id!", url, created_at AS "created_at!: String" FROM webhooks WHERE chat_id = ? ORDER BY id"#,
+        chat_id
+    ).fetch_all(&pool).await.unwrap()
+    .into_iter()
+    .map(|row| WebhookInfo { id: row.id, url: row.url, created_at: row.created_at })
+    .collect();
+    Json(Ok(webhooks))
+}
+
+/// Recent delivery attempts of a webhook, newest first
+/// # Query format:
+/// curl "http://98.93.98.244:80/webhookdeliveries/webhookid/3/username/NameString"
+/// # Return format:
+/// Array of "queue_id", "message_id", "event", "attempt", "status_code", "error", "duration_ms" and "attempted_at"
+pub(crate) async fn delivery_log(
+    State(pool): State<SqlitePool>,
+    Path((webhook_id, username)): Path<(i64, String)>,
+) -> Json<Result<Vec<DeliveryAttempt>, String>> {
+    let Some(webhook) = query!("SELECT chat_id FROM webhooks WHERE id = ?", webhook_id)
+        .fetch_optional(&pool)
+        .await
+        .unwrap() else {
+        return Json(Err(String::from("Webhook not found")));
+    };
+    let Some(user) = query!(r#"SELECT id AS "id!" FROM users WHERE username = ?"#, username)
+        .fetch_optional(&pool)
+        .await
+        .unwrap() else {
+        return Json(Err(String::from("User not found")));
+    };
+    if !manages_chat(webhook.chat_id, user.id, &pool).await {
+        return Json(Err(String::from("Only chat owners and admins can see webhook deliveries")));
+    }
+    let attempts = query!(
+        r#"SELECT webhook_deliveries.queue_id, message_queue.message_id, message_queue.event AS "event!",
+        webhook_deliveries.attempt, webhook_deliveries.status_code, webhook_deliveries.error,
+        webhook_deliveries.duration_ms, webhook_deliveries.attempted_at AS "attempted_at!: String"
+        FROM webhook_deliveries JOIN message_queue ON message_queue.id = webhook_deliveries.queue_id
+        WHERE webhook_deliveries.webhook_id = ?
+        ORDER BY webhook_deliveries.id DESC LIMIT ?"#,
+        webhook_id, DELIVERY_LOG_LIMIT
+    ).fetch_all(&pool).await.unwrap()
+    .into_iter()
+    .map(|row| DeliveryAttempt {
+        queue_id: row.queue_id,
+        message_id: row.message_id,
+        event: row.event,
+        attempt: row.attempt,
+        status_code: row.status_code,
+        error: row.error,
+        duration_ms: row.duration_ms,
+        attempted_at: row.attempted_at,
+    })
+    .collect();
+    Json(Ok(attempts))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::{http::{HeaderMap, StatusCode}, routing::post, Router};
+
+    #[test]
+    fn test_sign_rfc4231_vector() {
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_deleted_payload() {
+        let payload = WebhookPayload {
+            event: String::from(WebhookEvent::MessageDeleted.as_str()),
+            delivery_id: 3,
+            chat: String::from("builds"),
+            message: PayloadMessage::Deleted { id: 42 },
+        };
+        assert_eq!(
+            serde_json::to_string(&payload).unwrap(),
+            r#"{"event":"message.deleted","delivery_id":3,"chat":"builds","message":{"id":42}}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn test_internal_urls_rejected() {
+        for url in [
+            "http://127.0.0.1:9000/hook",
+            "http://localhost/hook",
+            "http://169.254.169.254/latest/meta-data",
+            "http://10.0.0.5/hook",
+            "https://192.168.1.1/hook",
+            "http://0.0.0.0/hook",
+            "http://[::1]/hook",
+            "http://[::ffff:127.0.0.1]/hook",
+            "http://[fd00::1]/hook",
+            "ftp://93.184.216.34/hook",
+        ] {
+            assert!(resolve_public(url).await.is_err(), "{} was accepted", url);
+        }
+        let (host, addrs) = resolve_public("https://93.184.216.34/hook").await.unwrap();
+        assert_eq!(host, "93.184.216.34");
+        assert_eq!(addrs, vec![SocketAddr::from(([93, 184, 216, 34], 443))]);
+        assert!(!is_public(IpAddr::from([100, 64, 0, 1])));
+        assert!(is_public(IpAddr::from([8, 8, 8, 8])));
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        assert_eq!(retry_delay(1), Some(RETRY_DELAYS_SECS[0]));
+        assert_eq!(retry_delay(RETRY_DELAYS_SECS.len()), RETRY_DELAYS_SECS.last().copied());
+        assert_eq!(retry_delay(RETRY_DELAYS_SECS.len() + 1), None);
+    }
+
+    #[tokio::test]
+    async fn test_send_to_stand_in_receiver() {
+        let (received, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let app = Router::new()
+            .route("/hook", post(move |headers: HeaderMap, body: String| async move {
+                received.send((headers, body)).unwrap();
+                StatusCode::OK
+            }))
+            .route("/broken", post(|| async { StatusCode::INTERNAL_SERVER_ERROR }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let client = reqwest::Client::new();
+
+        let url = format!("http://{}/hook", addr);
+        let status = send(&client, &url, "secret", "message.sent", 7, String::from(r#"{"a":1}"#)).await;
+        assert_eq!(status, Ok(200));
+        let (headers, body) = receiver.recv().await.unwrap();
+        assert_eq!(body, r#"{"a":1}"#);
+        assert_eq!(headers["x-chat-event"], "message.sent");
+        assert_eq!(headers["x-chat-delivery"], "7");
+        assert_eq!(headers["x-chat-signature"], format!("sha256={}", sign("secret", body.as_bytes())).as_str());
+
+        let url = format!("http://{}/broken", addr);
+        assert_eq!(send(&client, &url, "secret", "message.sent", 8, String::new()).await, Ok(500));
+    }
+}